@@ -0,0 +1,168 @@
+// Measures the memory taken by the stored transactions of a generated input,
+// as the compact records of `Transactions` against keeping every `Transaction` as it is.
+//
+//     cargo bench --bench compact_transactions
+//
+// BENCH_ROWS sets the rows of the generated file, 50 million by default. Keeping every transaction
+// as it is doesn't fit in memory for that many, so it is measured on the first BENCH_BASELINE_ROWS
+// rows, 5 million by default, and projected to the whole file.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::HashMap,
+    env,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use csv::StringRecord;
+use integrator::{
+    csv::get_transactions_iter,
+    models::{
+        stores::TransactionStore,
+        transaction::{Transaction, TransactionID},
+        transactions::Transactions,
+    },
+};
+
+// Keeps count of the bytes allocated at any time.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn rows_from_env(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(default)
+}
+
+// Deposits and withdrawals of many clients with up to four decimals, a few of them in another currency.
+fn generate(path: &Path, rows: usize) {
+    let mut writer = BufWriter::new(File::create(path).expect("Failed to create the input file"));
+    writeln!(writer, "type,client,tx,amount,currency").unwrap();
+    let mut seed: u64 = 42;
+    for txid in 1..=rows {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        let kind = if seed.is_multiple_of(4) {
+            "withdrawal"
+        } else {
+            "deposit"
+        };
+        let client = (seed >> 16) % 65535;
+        let amount = (seed >> 32) % 10_000_000;
+        let currency = if seed.is_multiple_of(10) {
+            "EUR"
+        } else {
+            "USD"
+        };
+        writeln!(
+            writer,
+            "{},{},{},{}.{:04},{}",
+            kind,
+            client,
+            txid,
+            amount / 10_000,
+            amount % 10_000,
+            currency
+        )
+        .unwrap();
+    }
+}
+
+fn input(rows: usize) -> PathBuf {
+    let path = env::temp_dir().join(format!("integrator-bench-{}.csv", rows));
+    if !path.exists() {
+        let started = Instant::now();
+        generate(&path, rows);
+        println!(
+            "generated {} rows into {} in {:.1?}",
+            rows,
+            path.display(),
+            started.elapsed()
+        );
+    }
+    path
+}
+
+fn transactions(path: &Path, limit: usize) -> impl Iterator<Item = Transaction> {
+    get_transactions_iter(path.to_str().unwrap().to_string())
+        .into_records()
+        .flatten()
+        .filter_map(|record: StringRecord| Transaction::from_record(record).ok())
+        .take(limit)
+}
+
+fn report(name: &str, rows: usize, bytes: usize, started: Instant) {
+    println!(
+        "{:<24} {:>12} rows {:>10.1} MiB {:>8.1} bytes/transaction {:>10.1?}",
+        name,
+        rows,
+        bytes as f64 / (1024.0 * 1024.0),
+        bytes as f64 / rows as f64,
+        started.elapsed()
+    );
+}
+
+fn main() {
+    let rows = rows_from_env("BENCH_ROWS", 50_000_000);
+    let baseline_rows = rows_from_env("BENCH_BASELINE_ROWS", 5_000_000).min(rows);
+    let path = input(rows);
+
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let started = Instant::now();
+    let mut full: HashMap<TransactionID, Transaction> = HashMap::new();
+    for transaction in transactions(&path, baseline_rows) {
+        full.insert(transaction.id, transaction);
+    }
+    let full_bytes = ALLOCATED.load(Ordering::Relaxed) - before;
+    report("full transactions", full.len(), full_bytes, started);
+    drop(full);
+
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let started = Instant::now();
+    let mut compact = Transactions::new();
+    for transaction in transactions(&path, rows) {
+        compact
+            .set(transaction)
+            .expect("The in-memory store doesn't fail");
+    }
+    let compact_bytes = ALLOCATED.load(Ordering::Relaxed) - before;
+    let compact_rows = compact.size().expect("The in-memory store doesn't fail");
+    report("compact transactions", compact_rows, compact_bytes, started);
+
+    let full_per_row = full_bytes as f64 / baseline_rows as f64;
+    let compact_per_row = compact_bytes as f64 / compact_rows as f64;
+    println!(
+        "full transactions projected to {} rows: {:.1} MiB, {:.1}x the compact ones",
+        rows,
+        full_per_row * rows as f64 / (1024.0 * 1024.0),
+        full_per_row / compact_per_row
+    );
+}
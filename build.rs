@@ -0,0 +1,12 @@
+// Generates the gRPC service out of its protobuf schema, with a protoc that comes as a dependency.
+// Builds without the `grpc` feature have nothing to generate.
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        let protoc =
+            protoc_bin_vendored::protoc_bin_path().expect("Failed to find the vendored protoc");
+        std::env::set_var("PROTOC", protoc);
+        tonic_prost_build::compile_protos("proto/integrator.proto")
+            .expect("Failed to compile the protobuf schema");
+    }
+}
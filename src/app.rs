@@ -1,151 +1,907 @@
-use std::collections::HashMap;
+use std::{borrow::Cow, io, mem, sync::Arc};
 
 use csv::StringRecord;
 
+use crate::clock::{Clock, SystemClock};
 use crate::models::{
-    account::{Account, RejectedTransaction, Result},
-    output::Output,
-    transaction::{Amount, ClientID, Transaction, TransactionType},
+    account::{Account, RejectedTransaction, Result, Snapshot},
+    authorizations::{AuthorizationExpiry, Authorizations},
+    bookkeeping::Bookkeeping,
+    clients::CreditLimit,
+    disputes::{DisputeAging, DisputeWindow, Disputes},
+    events::{Event, Observer, Observers},
+    fees::{Fee, FeeRevenue, FeeSchedule},
+    handlers::{ReservedName, TransactionHandler, TransactionHandlers},
+    limits::LimitRules,
+    output::{OutputSink, StdoutSink},
+    rates::RateTable,
+    stores::{AccountStore, Accounts, TransactionStore},
+    transaction::{Amount, ClientID, Timestamp, Transaction, TransactionID, TransactionType},
     transactions::Transactions,
 };
 
-type Accounts = HashMap<ClientID, Account>;
-
 #[derive(Debug, Clone)]
-pub struct App {
-    pub accounts: Accounts,
-    transactions: Transactions,
-    output: Output,
+pub struct App<
+    C: Clock = SystemClock,
+    A: AccountStore = Accounts,
+    T: TransactionStore = Transactions,
+    O: OutputSink = StdoutSink,
+> {
+    pub accounts: A,
+    transactions: T,
+    disputes: Disputes,
+    rates: RateTable,
+    fee_schedule: FeeSchedule,
+    fees: Vec<Fee>,
+    revenue: FeeRevenue,
+    limits: LimitRules,
+    authorizations: Authorizations,
+    handlers: TransactionHandlers,
+    observers: Observers,
+    // Whether the events go to the outbox of the transaction store.
+    outbox: bool,
+    // What expiring stale authorizations did while processing the current transaction,
+    // told before its own events. Only kept when someone wants events.
+    expiry_events: Vec<Event>,
+    // How many fee entries the transaction store was told about.
+    saved_fees: usize,
+    // Whether the bookkeeping was read from the transaction store since it was created or
+    // a transaction rolled back, persistent stores keep it as of the last commit.
+    restored: bool,
+    // How many transactions were given to process.
+    sequence: u64,
+    output: O,
+    // The source of time for the time-based rules.
+    clock: C,
 }
 
 impl App {
     pub fn new() -> Self {
+        Self::with_clock(SystemClock)
+    }
+}
+
+impl<C: Clock> App<C> {
+    pub fn with_clock(clock: C) -> Self {
+        Self::with_stores(clock, Accounts::new(), Transactions::new())
+    }
+}
+
+impl<C: Clock, A: AccountStore, O: OutputSink> App<C, A, Transactions, O> {
+    // Bounds the memory used by the stored transactions, spilling the oldest ones to disk.
+    pub fn set_memory_budget(
+        &mut self,
+        bytes: usize,
+        spill_filename: Option<String>,
+    ) -> io::Result<()> {
+        self.transactions.set_memory_budget(bytes, spill_filename)
+    }
+}
+
+impl<C: Clock, T: TransactionStore, O: OutputSink> App<C, Accounts, T, O> {
+    // Accounts kept in memory can't fail to be read, these look at them creating them as needed.
+    fn account(&mut self, client_id: ClientID) -> &mut Account {
+        self.accounts.account(client_id)
+    }
+
+    pub fn get_available_balance(&mut self, client_id: ClientID) -> Amount {
+        self.account(client_id).available_balance()
+    }
+
+    pub fn get_held_balance(&mut self, client_id: ClientID) -> Amount {
+        self.account(client_id).held_balance()
+    }
+
+    pub fn get_total_balance(&mut self, client_id: ClientID) -> Amount {
+        self.account(client_id).total_balance()
+    }
+
+    pub fn is_locked(&mut self, client_id: ClientID) -> bool {
+        self.account(client_id).is_locked()
+    }
+}
+
+impl<C: Clock, A: AccountStore, T: TransactionStore> App<C, A, T> {
+    pub fn with_stores(clock: C, accounts: A, transactions: T) -> Self {
         Self {
-            accounts: Default::default(),
-            transactions: Transactions::new(),
-            output: Output::new(),
+            accounts,
+            transactions,
+            disputes: Disputes::new(),
+            rates: RateTable::new(),
+            fee_schedule: FeeSchedule::new(),
+            fees: Default::default(),
+            revenue: FeeRevenue::new(),
+            limits: LimitRules::new(),
+            authorizations: Authorizations::new(),
+            handlers: TransactionHandlers::new(),
+            observers: Observers::new(),
+            outbox: false,
+            expiry_events: vec![],
+            saved_fees: 0,
+            restored: false,
+            sequence: 0,
+            output: StdoutSink::new(),
+            clock,
+        }
+    }
+}
+
+impl<C: Clock, A: AccountStore, T: TransactionStore, O: OutputSink> App<C, A, T, O> {
+    // Sends the rows somewhere else than stdout.
+    pub fn with_output<P: OutputSink>(self, output: P) -> App<C, A, T, P> {
+        App {
+            accounts: self.accounts,
+            transactions: self.transactions,
+            disputes: self.disputes,
+            rates: self.rates,
+            fee_schedule: self.fee_schedule,
+            fees: self.fees,
+            revenue: self.revenue,
+            limits: self.limits,
+            authorizations: self.authorizations,
+            handlers: self.handlers,
+            observers: self.observers,
+            outbox: self.outbox,
+            expiry_events: self.expiry_events,
+            saved_fees: self.saved_fees,
+            restored: self.restored,
+            sequence: self.sequence,
+            output,
+            clock: self.clock,
         }
     }
 
     pub fn process(&mut self, transaction: Transaction) -> Result<Transaction> {
-        if (transaction.kind == TransactionType::Deposit)
-            || (transaction.kind == TransactionType::Withdrawal)
+        // Rejected transactions are told as they were given
+        let given = self.wants_events().then(|| transaction.clone());
+        let was_locked = self.is_account_locked(transaction.client_id);
+        self.transactions
+            .reserve_memory(self.disputes.footprint() + self.authorizations.footprint());
+        let result = match self.begin() {
+            Ok(()) => self.process_transaction(transaction),
+            Err(error) => Err(error.into()),
+        };
+        self.persist(given, result, was_locked)
+    }
+
+    // Everything processing a transaction changes gets persisted together.
+    // After a rollback, the bookkeeping is read back as the stores kept it first.
+    fn begin(&mut self) -> io::Result<()> {
+        if !self.restored {
+            self.restore()?;
+        }
+        self.accounts.begin()?;
+        self.transactions.begin()
+    }
+
+    // Picks up the bookkeeping a persistent transaction store kept: open authorizations,
+    // disputes and when transactions arrived, the fees, the recent activity the limit rules
+    // look at and how many transactions went by.
+    // Stores not keeping it leave the app as it is.
+    pub fn restore(&mut self) -> io::Result<()> {
+        if let Some(bookkeeping) = self.transactions.load_bookkeeping()? {
+            self.sequence = bookkeeping.sequence;
+            self.disputes.restore(&bookkeeping);
+            self.authorizations.restore(&bookkeeping);
+            self.limits.restore(&bookkeeping);
+            self.revenue = FeeRevenue::new();
+            for fee in bookkeeping.fees.iter() {
+                self.revenue.collect(fee);
+            }
+            self.fees = bookkeeping.fees;
+            self.saved_fees = self.fees.len();
+        }
+        self.restored = true;
+        Ok(())
+    }
+
+    // Journals the accepted transaction, writes the events to the outbox and has the stores
+    // persist what processing it changed, bookkeeping included.
+    fn commit(&mut self, accepted: Option<&Transaction>, events: &[Event]) -> io::Result<()> {
+        if let Some(transaction) = accepted {
+            self.transactions.journal(transaction)?;
+        }
+        if self.outbox {
+            self.transactions.outbox(events)?;
+        }
+        let mut changes = Bookkeeping {
+            sequence: self.sequence,
+            fees: self.fees[self.saved_fees..].to_vec(),
+            ..Default::default()
+        };
+        self.saved_fees = self.fees.len();
+        self.disputes.take_changes(&mut changes);
+        self.authorizations.take_changes(&mut changes);
+        self.limits.take_changes(&mut changes);
+        self.transactions.save_bookkeeping(&changes)?;
+        self.transactions.commit()?;
+        self.accounts.commit()
+    }
+
+    // Has the stores persist what processing the transaction changed along with its events,
+    // and tells the observers. When a store failed meanwhile, the stores roll back what changed
+    // instead, the bookkeeping is read back before the next one and the transaction is told as rejected.
+    fn persist(
+        &mut self,
+        given: Option<Transaction>,
+        result: Result<Transaction>,
+        was_locked: bool,
+    ) -> Result<Transaction> {
+        let mut events = mem::take(&mut self.expiry_events);
+        events.extend(self.outcome_events(given.clone(), &result, was_locked));
+        let stored = match &result {
+            Err(RejectedTransaction::StorageFailed) => Err(RejectedTransaction::StorageFailed),
+            _ => self
+                .commit(result.as_ref().ok(), &events)
+                .map_err(RejectedTransaction::from),
+        };
+        if let Err(reason) = stored {
+            self.transactions.rollback();
+            self.accounts.rollback();
+            // Fees posted meanwhile go away along with what the accounts were charged
+            self.fees.truncate(self.saved_fees);
+            self.revenue = FeeRevenue::new();
+            for fee in self.fees.iter() {
+                self.revenue.collect(fee);
+            }
+            self.restored = false;
+            let result = Err(reason);
+            self.observers
+                .notify(&self.outcome_events(given, &result, was_locked));
+            return result;
+        }
+        self.observers.notify(&events);
+        result
+    }
+
+    fn wants_events(&self) -> bool {
+        self.outbox || !self.observers.is_empty()
+    }
+
+    // Nothing when no one wants events, else what the transaction given did or why it was rejected.
+    fn outcome_events(
+        &self,
+        given: Option<Transaction>,
+        result: &Result<Transaction>,
+        was_locked: bool,
+    ) -> Vec<Event> {
+        match (given, result) {
+            (None, _) => vec![],
+            (Some(_), Ok(processed)) => self.accepted_events(processed, was_locked),
+            (Some(transaction), Err(reason)) => vec![Event::TransactionRejected {
+                transaction,
+                reason: reason.clone(),
+            }],
+        }
+    }
+
+    // An accepted transaction, what it did to a dispute and whether it locked the account.
+    fn accepted_events(&self, processed: &Transaction, was_locked: bool) -> Vec<Event> {
+        let mut events = vec![Event::TransactionAccepted(processed.clone())];
+        match processed.kind {
+            TransactionType::Dispute => events.push(Event::DisputeOpened(processed.clone())),
+            TransactionType::Resolve => events.push(Event::DisputeResolved(processed.clone())),
+            TransactionType::Chargeback => events.push(Event::ChargebackApplied(processed.clone())),
+            _ => {}
+        }
+        if !was_locked && self.is_account_locked(processed.client_id) {
+            events.push(Event::AccountLocked {
+                client_id: processed.client_id,
+            });
+        }
+        events
+    }
+
+    // An account that can't be read is told as it would be without one.
+    fn is_account_locked(&self, client_id: ClientID) -> bool {
+        matches!(self.accounts.get_account(client_id), Ok(Some(account)) if account.is_locked())
+    }
+
+    // Every observer gets every event, in the order they subscribed.
+    pub fn subscribe(&mut self, observer: Arc<dyn Observer>) {
+        self.observers.subscribe(observer);
+    }
+
+    pub fn set_observers(&mut self, observers: Observers) {
+        self.observers = observers;
+    }
+
+    // Has the transaction store write the events of each transaction along with its journal entry.
+    pub fn set_outbox(&mut self, outbox: bool) {
+        self.outbox = outbox;
+    }
+
+    fn process_transaction(&mut self, transaction: Transaction) -> Result<Transaction> {
+        if let Some(timestamp) = transaction.timestamp {
+            self.clock.observe(timestamp);
+        }
+        let now = self.clock.now();
+        self.sequence += 1;
+        // The stores are told before the bookkeeping changes, a failing one leaves it as it was.
+        // What the accounts changed meanwhile is rolled back by their store.
+        for txid in self.disputes.take_expired(self.sequence, now) {
+            self.transactions.remove(txid)?;
+        }
+        self.expire_authorizations(now)?;
+        // Limit rules are evaluated before any balance changes
+        self.limits.check(&transaction, now)?;
+        let processed = self.apply(transaction, now)?;
+        if (processed.kind == TransactionType::Deposit)
+            || (processed.kind == TransactionType::Withdrawal)
         {
-            // We only need to store deposits and withdrawals
-            self.transactions.set(transaction.clone());
+            // We only need to store accepted deposits and withdrawals,
+            // rejected ones can't be disputed nor refunded
+            self.transactions.set(processed.clone())?;
+            self.disputes.arrived(processed.id, self.sequence, now);
         }
+        self.limits.record(&processed, now);
+        if let Some(timestamp) = processed.timestamp {
+            self.record_history(&processed, timestamp)?;
+        }
+        Ok(processed)
+    }
+
+    // Keeps the balance history of the account changed by a timestamped transaction.
+    fn record_history(&mut self, transaction: &Transaction, timestamp: Timestamp) -> Result<()> {
+        Self::get_or_create_account(&mut self.accounts, transaction.client_id)?
+            .record_snapshot(timestamp);
+        Ok(())
+    }
+
+    // Answers the balances the client had at the given time, as of the timestamped transactions processed.
+    pub fn balance_at(
+        &self,
+        client_id: ClientID,
+        timestamp: Timestamp,
+    ) -> io::Result<Option<Snapshot>> {
+        self.accounts.snapshot_at(client_id, timestamp)
+    }
+
+    fn apply(&mut self, transaction: Transaction, now: Timestamp) -> Result<Transaction> {
         match transaction.kind {
             TransactionType::Deposit => Self::process_deposit(&mut self.accounts, &transaction),
             TransactionType::Withdrawal => {
-                Self::process_withdrawal(&mut self.accounts, &transaction)
+                let fee = transaction.amount.and_then(|amount| {
+                    self.fee_schedule
+                        .fee_for(&transaction.kind, &transaction.currency, amount)
+                });
+                let processed = Self::process_withdrawal(&mut self.accounts, &transaction, fee)?;
+                self.post_fee(processed, fee)
             }
             TransactionType::Dispute => {
-                Self::process_dispute(&mut self.accounts, &self.transactions, &transaction)
+                // Holds are shared with the authorizations, a transaction is held once at most
+                if self.disputes.is_open(transaction.id) {
+                    return Err(RejectedTransaction::InvalidInput);
+                }
+                self.disputes
+                    .check_window(transaction.id, self.sequence, now)?;
+                let processed =
+                    Self::process_dispute(&mut self.accounts, &self.transactions, &transaction)?;
+                self.disputes.open(transaction.id, self.sequence, now);
+                Ok(processed)
             }
             TransactionType::Resolve => {
-                Self::process_resolve(&mut self.accounts, &self.transactions, &transaction)
+                self.check_disputed(transaction.id)?;
+                let processed =
+                    Self::process_resolve(&mut self.accounts, &self.transactions, &transaction)?;
+                self.close_dispute(transaction.id, now)?;
+                Ok(processed)
             }
             TransactionType::Chargeback => {
-                Self::process_chargeback(&mut self.accounts, &self.transactions, &transaction)
+                self.check_disputed(transaction.id)?;
+                // Chargeback fees are a share of the charged back amount, in its currency
+                let charged_back = self
+                    .transactions
+                    .get(transaction.id)?
+                    .and_then(|tx| tx.amount);
+                let processed =
+                    Self::process_chargeback(&mut self.accounts, &self.transactions, &transaction)?;
+                let fee = charged_back.and_then(|amount| {
+                    self.fee_schedule
+                        .fee_for(&transaction.kind, &processed.currency, amount)
+                });
+                // The account is locked by now, the fee takes no more than its available funds
+                let available =
+                    Self::get_or_create_account(&mut self.accounts, processed.client_id)?
+                        .available_balance_in(&processed.currency);
+                let fee = fee
+                    .map(|fee| if fee > available { available } else { fee })
+                    .filter(|fee| *fee > Amount::from(0));
+                self.close_dispute(transaction.id, now)?;
+                self.post_fee(processed, fee)
+            }
+            TransactionType::Convert => {
+                Self::process_convert(&mut self.accounts, &self.rates, &transaction, now)
+            }
+            TransactionType::Authorize => {
+                if self.authorizations.contains(transaction.id) {
+                    return Err(RejectedTransaction::InvalidInput);
+                }
+                let processed =
+                    Self::get_or_create_account(&mut self.accounts, transaction.client_id)?
+                        .process_authorize(&transaction)?;
+                self.authorizations.open(&processed, self.sequence, now);
+                Ok(processed)
+            }
+            TransactionType::Refund => {
+                let processed =
+                    Self::get_or_create_account(&mut self.accounts, transaction.client_id)?
+                        .process_refund(&transaction, &self.transactions)?;
+                self.transactions
+                    .add_refund(processed.id, processed.amount.unwrap_or_default())?;
+                Ok(processed)
+            }
+            TransactionType::Capture => self.process_capture(&transaction),
+            TransactionType::Void => self.process_void(&transaction),
+            TransactionType::Custom(name) => self.process_custom(name, &transaction),
+        }
+    }
+
+    fn process_custom(&mut self, name: &str, transaction: &Transaction) -> Result<Transaction> {
+        let handler = match self.handlers.get(name) {
+            None => return Err(RejectedTransaction::InvalidType),
+            Some(value) => value,
+        };
+        let account = Self::get_or_create_account(&mut self.accounts, transaction.client_id)?;
+        if account.is_locked() {
+            return Err(RejectedTransaction::AccountLocked);
+        }
+        handler.validate(transaction, account)?;
+        handler.apply(transaction, account)
+    }
+
+    pub fn set_handlers(&mut self, handlers: TransactionHandlers) {
+        self.handlers = handlers;
+    }
+
+    // Lets the app process records of a transaction type defined outside the crate.
+    // Built-in transaction types can't be replaced.
+    pub fn register_handler(
+        &mut self,
+        handler: impl TransactionHandler + 'static,
+    ) -> std::result::Result<(), ReservedName> {
+        self.handlers.register(Arc::new(handler))
+    }
+
+    // Partial captures keep the authorization open until nothing remains of it.
+    fn process_capture(&mut self, transaction: &Transaction) -> Result<Transaction> {
+        let authorization = match self.authorizations.get(transaction.id) {
+            Some(value) if value.client_id == transaction.client_id => value,
+            _ => return Err(RejectedTransaction::IDNotFound),
+        };
+        let account = Self::get_or_create_account(&mut self.accounts, transaction.client_id)?;
+        let processed = account.process_capture(transaction, authorization)?;
+        let captured = processed.amount.unwrap_or_default();
+        let authorization = self
+            .authorizations
+            .get_mut(transaction.id)
+            .expect("The authorization was just found");
+        authorization.captured += captured;
+        if authorization.remaining() <= Amount::from(0) {
+            self.authorizations.close(transaction.id);
+        }
+        Ok(processed)
+    }
+
+    fn process_void(&mut self, transaction: &Transaction) -> Result<Transaction> {
+        let authorization = match self.authorizations.get(transaction.id) {
+            Some(value) if value.client_id == transaction.client_id => value,
+            _ => return Err(RejectedTransaction::IDNotFound),
+        };
+        let account = Self::get_or_create_account(&mut self.accounts, transaction.client_id)?;
+        if account.is_locked() {
+            return Err(RejectedTransaction::AccountLocked);
+        }
+        account.release_hold(authorization)?;
+        let currency = authorization.currency.clone();
+        self.authorizations.close(transaction.id);
+        Ok(transaction.in_currency(&currency))
+    }
+
+    // Closes the disputes open for too long with a synthetic resolve or chargeback,
+    // answering the transactions applied so they can be reported as any other.
+    pub fn age_disputes(&mut self) -> Vec<Transaction> {
+        let now = self.clock.now();
+        let mut processed = vec![];
+        let kind = self.disputes.aging_action().kind();
+        for txid in self.disputes.aged(self.sequence, now) {
+            // The disputed transaction is kept as long as its dispute is open
+            let synthetic = match self.transactions.get(txid) {
+                Ok(Some(disputed)) => {
+                    Transaction::new_synthetic(kind.clone(), disputed.client_id, txid)
+                }
+                Ok(None) => {
+                    self.disputes.stop_aging(txid);
+                    continue;
+                }
+                // Tried again after the next transaction
+                Err(_) => continue,
+            };
+            let given = self.wants_events().then(|| synthetic.clone());
+            let was_locked = self.is_account_locked(synthetic.client_id);
+            let result = match self.begin() {
+                Ok(()) => self.apply(synthetic, now),
+                Err(error) => Err(error.into()),
+            };
+            match self.persist(given, result, was_locked) {
+                Ok(value) => processed.push(value),
+                // Tried again after the next transaction
+                Err(RejectedTransaction::StorageFailed) => {}
+                // A dispute that can't be closed, like on a locked account, keeps its funds held
+                // until a resolve or chargeback comes, its rejection is told as any other
+                Err(_) => self.disputes.stop_aging(txid),
+            }
+        }
+        processed
+    }
+
+    // Only the funds of an open dispute can be released or charged back, the other held funds
+    // belong to authorizations.
+    fn check_disputed(&self, txid: TransactionID) -> Result<()> {
+        if self.disputes.is_open(txid) {
+            Ok(())
+        } else {
+            Err(RejectedTransaction::IDNotFound)
+        }
+    }
+
+    // A transaction out of its dispute window goes away once its dispute is closed.
+    // It goes away from the store first, a failing one leaves the dispute open.
+    fn close_dispute(&mut self, txid: TransactionID, now: Timestamp) -> Result<()> {
+        if self
+            .disputes
+            .check_window(txid, self.sequence, now)
+            .is_err()
+        {
+            self.transactions.remove(txid)?;
+        }
+        self.disputes.close(txid, self.sequence, now);
+        Ok(())
+    }
+
+    pub fn set_dispute_aging(&mut self, aging: DisputeAging) {
+        self.disputes.set_aging(aging);
+    }
+
+    // Stale authorizations give their remaining held funds back to available.
+    fn expire_authorizations(&mut self, now: Timestamp) -> Result<()> {
+        for authorization in self.authorizations.take_expired(self.sequence, now) {
+            let account = Self::get_or_create_account(&mut self.accounts, authorization.client_id)?;
+            let released = account.release_hold(&authorization);
+            if !self.wants_events() {
+                continue;
             }
+            let void = Transaction {
+                amount: Some(authorization.remaining()),
+                ..Transaction::new_synthetic(
+                    TransactionType::Void,
+                    authorization.client_id,
+                    authorization.id,
+                )
+                .in_currency(&authorization.currency)
+            };
+            // An inconsistent hold can't be released, it stays as it is and its void is told as rejected
+            self.expiry_events.push(match released {
+                Ok(()) => Event::HoldReleased(void),
+                Err(reason) => Event::TransactionRejected {
+                    transaction: void,
+                    reason,
+                },
+            });
         }
+        Ok(())
+    }
+
+    pub fn set_authorization_expiry(&mut self, expiry: AuthorizationExpiry) {
+        self.authorizations.set_expiry(expiry);
+    }
+
+    pub fn set_dispute_window(&mut self, window: DisputeWindow) {
+        self.disputes.set_window(window);
+    }
+
+    pub fn open_authorizations(&self) -> usize {
+        self.authorizations.size()
+    }
+
+    pub fn set_rates(&mut self, rates: RateTable) {
+        self.rates = rates;
+    }
+
+    // Persistent stores write it at once, in a database transaction of its own.
+    pub fn set_credit_limit(&mut self, credit_limit: &CreditLimit) -> io::Result<()> {
+        self.accounts.begin()?;
+        let set = self
+            .accounts
+            .get_or_create_account(credit_limit.client_id)
+            .map(|account| account.set_credit_limit(&credit_limit.currency, credit_limit.limit))
+            .and_then(|_| self.accounts.commit());
+        if set.is_err() {
+            self.accounts.rollback();
+        }
+        set
+    }
+
+    pub fn set_limits(&mut self, limits: LimitRules) {
+        self.limits = limits;
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    pub fn set_fee_schedule(&mut self, fee_schedule: FeeSchedule) {
+        self.fee_schedule = fee_schedule;
+    }
+
+    // Charges the fee to the client, credits it to the fee revenue and records the entry linked to the transaction.
+    fn post_fee(&mut self, transaction: Transaction, fee: Option<Amount>) -> Result<Transaction> {
+        let amount = match fee {
+            None => return Ok(transaction),
+            Some(value) => value,
+        };
+        let entry = Fee::new(&transaction, amount);
+        Self::get_or_create_account(&mut self.accounts, entry.client_id)?.charge_fee(&entry)?;
+        self.revenue.collect(&entry);
+        self.fees.push(entry);
+        Ok(Transaction {
+            fee: Some(amount),
+            ..transaction
+        })
+    }
+
+    // The fees collected from the clients.
+    pub fn fee_revenue(&self) -> &FeeRevenue {
+        &self.revenue
+    }
+
+    // The fee entries linked to the given transaction.
+    pub fn fees_for(&self, txid: TransactionID) -> Vec<&Fee> {
+        self.fees
+            .iter()
+            .filter(|fee| fee.linked_id == txid)
+            .collect()
     }
 
     pub fn process_record(&mut self, record: StringRecord) -> Result<Transaction> {
-        let transaction = Transaction::from_record(record);
+        // Custom types can't share a name with the built-in ones
+        let handler = record.get(0).and_then(|name| self.handlers.get(name));
+        let transaction = match handler {
+            Some(handler) => handler.parse(record),
+            None => Transaction::from_record(record),
+        };
         match transaction {
             Err(err) => Err(err),
             Ok(tx) => self.process(tx),
         }
     }
 
-    fn process_deposit(accounts: &mut Accounts, transaction: &Transaction) -> Result<Transaction> {
-        let account = Self::get_or_create_account(accounts, transaction.client_id);
+    fn process_deposit(accounts: &mut A, transaction: &Transaction) -> Result<Transaction> {
+        let account = Self::get_or_create_account(accounts, transaction.client_id)?;
         account.process_deposit(transaction)
     }
 
     fn process_withdrawal(
-        accounts: &mut Accounts,
+        accounts: &mut A,
         transaction: &Transaction,
+        fee: Option<Amount>,
     ) -> Result<Transaction> {
-        let account = Self::get_or_create_account(accounts, transaction.client_id);
-        account.process_withdrawal(transaction)
+        let account = Self::get_or_create_account(accounts, transaction.client_id)?;
+        account.process_withdrawal_with_fee(transaction, fee)
     }
 
     fn process_dispute(
-        accounts: &mut Accounts,
-        transactions: &Transactions,
+        accounts: &mut A,
+        transactions: &T,
         transaction: &Transaction,
     ) -> Result<Transaction> {
-        let account = Self::get_or_create_account(accounts, transaction.client_id);
-        account.process_dispute(transaction, &transactions)
+        let account = Self::get_or_create_account(accounts, transaction.client_id)?;
+        account.process_dispute(transaction, transactions)
     }
 
     fn process_resolve(
-        accounts: &mut Accounts,
-        transactions: &Transactions,
+        accounts: &mut A,
+        transactions: &T,
         transaction: &Transaction,
     ) -> Result<Transaction> {
-        let account = Self::get_or_create_account(accounts, transaction.client_id);
-        account.process_resolve(transaction, &transactions)
+        let account = Self::get_or_create_account(accounts, transaction.client_id)?;
+        account.process_resolve(transaction, transactions)
     }
 
     fn process_chargeback(
-        accounts: &mut Accounts,
-        transactions: &Transactions,
+        accounts: &mut A,
+        transactions: &T,
         transaction: &Transaction,
     ) -> Result<Transaction> {
-        let account = Self::get_or_create_account(accounts, transaction.client_id);
-        account.process_chargeback(transaction, &transactions)
+        let account = Self::get_or_create_account(accounts, transaction.client_id)?;
+        account.process_chargeback(transaction, transactions)
     }
 
-    pub fn get_available_balance(&mut self, client_id: ClientID) -> Amount {
-        let account = Self::get_or_create_account(&mut self.accounts, client_id);
-        account.available_balance()
+    fn process_convert(
+        accounts: &mut A,
+        rates: &RateTable,
+        transaction: &Transaction,
+        now: Timestamp,
+    ) -> Result<Transaction> {
+        let account = Self::get_or_create_account(accounts, transaction.client_id)?;
+        account.process_convert(transaction, rates, now)
     }
 
-    pub fn get_held_balance(&mut self, client_id: ClientID) -> Amount {
-        let account = Self::get_or_create_account(&mut self.accounts, client_id);
-        account.held_balance()
+    pub fn transactions_size(&self) -> io::Result<usize> {
+        self.transactions.size()
     }
 
-    pub fn get_total_balance(&mut self, client_id: ClientID) -> Amount {
-        let account = Self::get_or_create_account(&mut self.accounts, client_id);
-        account.total_balance()
+    pub fn output_write(&mut self, msg: String) -> io::Result<()> {
+        self.output.write_line(&msg)
     }
 
-    pub fn is_locked(&mut self, client_id: ClientID) -> bool {
-        let account = Self::get_or_create_account(&mut self.accounts, client_id);
-        account.is_locked()
+    // Writes the rows of the accounts changed by an accepted transaction.
+    pub fn output_transaction(&mut self, transaction: &Transaction) -> io::Result<()> {
+        // Accepted transactions always leave an account, unless it was rolled back meanwhile
+        let account = match self.accounts.get_account(transaction.client_id)? {
+            None => return Ok(()),
+            Some(value) => value,
+        };
+        self.output
+            .write_transaction(&account, &self.revenue, transaction)
     }
 
-    pub fn transactions_size(&self) -> usize {
-        self.transactions.size()
+    // Writes the rows of the balances the client had at the given time, none when unknown.
+    pub fn output_balance_at(
+        &mut self,
+        client_id: ClientID,
+        timestamp: Timestamp,
+    ) -> io::Result<()> {
+        match self.accounts.snapshot_at(client_id, timestamp)? {
+            Some(snapshot) => self.output.write_snapshot(client_id, &snapshot),
+            None => Ok(()),
+        }
+    }
+
+    pub fn flush_output(&mut self) -> io::Result<()> {
+        self.output.flush()
     }
 
-    pub fn output_write(&self, msg: String) {
-        self.output.write(msg);
+    pub fn output(&self) -> &O {
+        &self.output
     }
 
-    fn get_or_create_account(accounts: &mut Accounts, client_id: ClientID) -> &mut Account {
-        accounts
-            .entry(client_id)
-            .or_insert_with(|| Account::new(client_id))
+    fn get_or_create_account(accounts: &mut A, client_id: ClientID) -> Result<&mut Account> {
+        Ok(accounts.get_or_create_account(client_id)?)
     }
 
-    pub fn get_account(&self, client_id: ClientID) -> Result<&Account> {
+    // Stores not keeping the accounts in memory answer an owned copy.
+    pub fn get_account(&self, client_id: ClientID) -> Result<Cow<'_, Account>> {
         self.accounts
-            .get(&client_id)
-            .ok_or_else(|| RejectedTransaction::IDNotFound)
+            .get_account(client_id)?
+            .ok_or(RejectedTransaction::IDNotFound)
+    }
+}
+
+impl Default for App {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[cfg(test)]
+// The baseline tests are kept as they were first written
+#[allow(
+    clippy::assertions_on_constants,
+    clippy::bool_assert_comparison,
+    clippy::clone_on_copy
+)]
 mod tests {
+    use std::{borrow::Cow, io, sync::Arc};
+
     use csv::StringRecord;
     use fraction::Decimal;
 
-    use crate::{app::App, models::transaction::Transaction};
+    use crate::{
+        app::App,
+        clock::{Clock, SimulatedClock, SystemClock},
+        models::{
+            account::{Account, RejectedTransaction, Result},
+            authorizations::AuthorizationExpiry,
+            clients::CreditLimit,
+            disputes::{AgedDisputeAction, DisputeAging, DisputeWindow},
+            events::{Event, EventLog},
+            fees::{FeeRule, FeeSchedule},
+            handlers::{ReservedName, TransactionHandler},
+            limits::{LimitRule, LimitRules},
+            output::MemorySink,
+            rates::RateTable,
+            sqlite,
+            stores::{Accounts, TransactionStore},
+            transaction::{Amount, Transaction, TransactionID, TransactionType},
+            transactions::Transactions,
+        },
+    };
+
+    // A transaction store counting the lookups made by the processing logic.
+    #[derive(Debug, Default)]
+    struct CountingTransactions {
+        inner: Transactions,
+        lookups: std::cell::Cell<usize>,
+    }
+
+    impl TransactionStore for CountingTransactions {
+        fn set(&mut self, transaction: Transaction) -> io::Result<Option<TransactionID>> {
+            self.inner.set(transaction)
+        }
+
+        fn get(&self, txid: TransactionID) -> io::Result<Option<Cow<'_, Transaction>>> {
+            self.lookups.set(self.lookups.get() + 1);
+            self.inner.get(txid)
+        }
+
+        fn remove(&mut self, txid: TransactionID) -> io::Result<bool> {
+            self.inner.remove(txid)
+        }
+
+        fn size(&self) -> io::Result<usize> {
+            self.inner.size()
+        }
+
+        fn add_refund(&mut self, txid: TransactionID, amount: Amount) -> io::Result<()> {
+            self.inner.add_refund(txid, amount)
+        }
+
+        fn refunded(&self, txid: TransactionID) -> io::Result<Amount> {
+            self.inner.refunded(txid)
+        }
+    }
+
+    // A transaction store whose disk went away, once told so.
+    #[derive(Debug, Default)]
+    struct FailingTransactions {
+        inner: Transactions,
+        failing: bool,
+    }
+
+    impl FailingTransactions {
+        fn check(&self) -> io::Result<()> {
+            if self.failing {
+                return Err(io::Error::other("The disk went away"));
+            }
+            Ok(())
+        }
+    }
+
+    impl TransactionStore for FailingTransactions {
+        fn set(&mut self, transaction: Transaction) -> io::Result<Option<TransactionID>> {
+            self.check()?;
+            self.inner.set(transaction)
+        }
+
+        fn get(&self, txid: TransactionID) -> io::Result<Option<Cow<'_, Transaction>>> {
+            self.check()?;
+            self.inner.get(txid)
+        }
+
+        fn remove(&mut self, txid: TransactionID) -> io::Result<bool> {
+            self.check()?;
+            self.inner.remove(txid)
+        }
+
+        fn size(&self) -> io::Result<usize> {
+            self.inner.size()
+        }
+
+        fn add_refund(&mut self, txid: TransactionID, amount: Amount) -> io::Result<()> {
+            self.check()?;
+            self.inner.add_refund(txid, amount)
+        }
+
+        fn refunded(&self, txid: TransactionID) -> io::Result<Amount> {
+            self.check()?;
+            self.inner.refunded(txid)
+        }
+
+        fn journal(&mut self, _transaction: &Transaction) -> io::Result<()> {
+            self.check()
+        }
+    }
 
     #[test]
     fn deposit_can_increase_account_balance() {
@@ -196,7 +952,7 @@ mod tests {
         app.process(tx2.unwrap()).unwrap();
         let after2 = app.get_available_balance(client_id);
         assert_eq!(after2, Decimal::from(3.0 - 1.3));
-        let size = app.transactions_size();
+        let size = app.transactions_size().unwrap();
         assert_eq!(size, 2);
         assert_eq!(
             app.get_held_balance(client_id) + app.get_available_balance(client_id),
@@ -285,4 +1041,1324 @@ mod tests {
         );
         assert_eq!(app.is_locked(client_id), true);
     }
+
+    #[test]
+    fn deposits_in_different_currencies_keep_separate_balances() {
+        let mut app = App::new();
+        let tx1 = Transaction::from_record(StringRecord::from(vec!["deposit", "2", "4", "2.0"]));
+        let client_id = tx1.as_ref().unwrap().client_id;
+        app.process(tx1.unwrap()).unwrap();
+        let tx2 =
+            Transaction::from_record(StringRecord::from(vec!["deposit", "2", "5", "1.5", "EUR"]));
+        app.process(tx2.unwrap()).unwrap();
+        let tx3 = Transaction::from_record(StringRecord::from(vec![
+            "withdrawal",
+            "2",
+            "6",
+            "0.5",
+            "eur",
+        ]));
+        app.process(tx3.unwrap()).unwrap();
+        let account = app.get_account(client_id).unwrap();
+        assert_eq!(account.available_balance(), Decimal::from(2.0));
+        assert_eq!(account.available_balance_in("EUR"), Decimal::from(1.0));
+        assert_eq!(account.total_balance_in("EUR"), Decimal::from(1.0));
+        assert_eq!(
+            account.currencies(),
+            vec!["EUR".to_string(), "USD".to_string()]
+        );
+    }
+
+    #[test]
+    fn dispute_holds_funds_in_the_currency_of_the_disputed_transaction() {
+        let mut app = App::new();
+        let tx1 =
+            Transaction::from_record(StringRecord::from(vec!["deposit", "2", "4", "2.0", "EUR"]));
+        let client_id = tx1.as_ref().unwrap().client_id;
+        app.process(tx1.unwrap()).unwrap();
+        let tx2 = Transaction::from_record(StringRecord::from(vec!["deposit", "2", "5", "3.0"]));
+        app.process(tx2.unwrap()).unwrap();
+        let tx3 =
+            Transaction::from_record(StringRecord::from(vec!["deposit", "2", "6", "1.0", "EUR"]));
+        app.process(tx3.unwrap()).unwrap();
+        let tx4 = Transaction::from_record(StringRecord::from(vec!["dispute", "2", "4", ""]));
+        let disputed = app.process(tx4.unwrap()).unwrap();
+        assert_eq!(disputed.currency, "EUR");
+        let account = app.get_account(client_id).unwrap();
+        assert_eq!(account.held_balance_in("EUR"), Decimal::from(2.0));
+        assert_eq!(account.available_balance_in("EUR"), Decimal::from(1.0));
+        assert_eq!(account.held_balance(), Decimal::from(0));
+        assert_eq!(account.available_balance(), Decimal::from(3.0));
+    }
+
+    #[test]
+    fn convert_debits_one_currency_and_credits_the_other_at_the_latest_rate() {
+        let mut app = App::new();
+        let mut rates = RateTable::new();
+        rates.set_rate("EUR".into(), "USD".into(), Decimal::from(1.05), 1);
+        rates.set_rate("EUR".into(), "USD".into(), Decimal::from(1.1), 2);
+        app.set_rates(rates);
+        let tx1 =
+            Transaction::from_record(StringRecord::from(vec!["deposit", "2", "4", "20", "EUR"]));
+        let client_id = tx1.as_ref().unwrap().client_id;
+        app.process(tx1.unwrap()).unwrap();
+        let tx2 = Transaction::from_record(StringRecord::from(vec![
+            "convert", "2", "5", "10", "EUR/USD",
+        ]));
+        let converted = app.process(tx2.unwrap()).unwrap();
+        assert_eq!(converted.rate, Some(Decimal::from(1.1)));
+        let account = app.get_account(client_id).unwrap();
+        assert_eq!(account.available_balance_in("EUR"), Decimal::from(10));
+        assert_eq!(account.available_balance_in("USD"), Decimal::from(11));
+        assert_eq!(account.total_balance_in("USD"), Decimal::from(11));
+    }
+
+    #[test]
+    fn convert_without_a_rate_is_rejected() {
+        let mut app = App::new();
+        let tx1 =
+            Transaction::from_record(StringRecord::from(vec!["deposit", "2", "4", "20", "EUR"]));
+        let client_id = tx1.as_ref().unwrap().client_id;
+        app.process(tx1.unwrap()).unwrap();
+        let tx2 = Transaction::from_record(StringRecord::from(vec![
+            "convert", "2", "5", "10", "EUR/GBP",
+        ]));
+        let result = app.process(tx2.unwrap());
+        assert!(matches!(result, Err(RejectedTransaction::RateNotFound)));
+        let account = app.get_account(client_id).unwrap();
+        assert_eq!(account.available_balance_in("EUR"), Decimal::from(20));
+        assert_eq!(account.available_balance_in("GBP"), Decimal::from(0));
+    }
+
+    #[test]
+    fn withdrawal_fee_is_charged_to_the_client_and_credited_to_the_revenue_account() {
+        let mut app = App::new();
+        let mut fee_schedule = FeeSchedule::new();
+        let rule = FeeRule::new(Decimal::from(0.5), Decimal::from(1), None, None);
+        fee_schedule.set_rule(TransactionType::Withdrawal, rule);
+        app.set_fee_schedule(fee_schedule);
+        let tx1 = Transaction::from_record(StringRecord::from(vec!["deposit", "2", "4", "100"]));
+        let client_id = tx1.as_ref().unwrap().client_id;
+        app.process(tx1.unwrap()).unwrap();
+        let tx2 = Transaction::from_record(StringRecord::from(vec!["withdrawal", "2", "5", "50"]));
+        let withdrawn = app.process(tx2.unwrap()).unwrap();
+        assert_eq!(withdrawn.fee, Some(Decimal::from(1)));
+        assert_eq!(app.get_available_balance(client_id), Decimal::from(49));
+        assert_eq!(app.get_total_balance(client_id), Decimal::from(49));
+        assert_eq!(app.fee_revenue().balance("USD"), Decimal::from(1));
+        let tx3 =
+            Transaction::from_record(StringRecord::from(vec!["withdrawal", "2", "6", "48.5"]));
+        let result = app.process(tx3.unwrap());
+        assert!(matches!(
+            result,
+            Err(RejectedTransaction::InsufficientFunds)
+        ));
+        assert_eq!(app.get_available_balance(client_id), Decimal::from(49));
+    }
+
+    #[test]
+    fn chargeback_fee_is_posted_as_a_linked_entry() {
+        let mut app = App::new();
+        let mut fee_schedule = FeeSchedule::new();
+        let rule = FeeRule::new(Decimal::from(15), Decimal::from(0), None, None);
+        fee_schedule.set_rule(TransactionType::Chargeback, rule);
+        app.set_fee_schedule(fee_schedule);
+        let tx1 = Transaction::from_record(StringRecord::from(vec!["deposit", "2", "4", "30"]));
+        let client_id = tx1.as_ref().unwrap().client_id;
+        app.process(tx1.unwrap()).unwrap();
+        let tx2 = Transaction::from_record(StringRecord::from(vec!["deposit", "2", "5", "10"]));
+        app.process(tx2.unwrap()).unwrap();
+        let tx3 = Transaction::from_record(StringRecord::from(vec!["dispute", "2", "5", ""]));
+        app.process(tx3.unwrap()).unwrap();
+        let tx4 = Transaction::from_record(StringRecord::from(vec!["chargeback", "2", "5", ""]));
+        app.process(tx4.unwrap()).unwrap();
+        assert_eq!(app.get_total_balance(client_id), Decimal::from(15));
+        assert!(app.is_locked(client_id));
+        let fees = app.fees_for(5);
+        assert_eq!(fees.len(), 1);
+        assert_eq!(fees[0].kind, TransactionType::Chargeback);
+        assert_eq!(fees[0].amount, Decimal::from(15));
+        assert_eq!(app.fee_revenue().balance("USD"), Decimal::from(15));
+    }
+
+    #[test]
+    fn chargeback_fee_takes_no_more_than_the_available_funds() {
+        let mut app = App::new();
+        let mut fee_schedule = FeeSchedule::new();
+        let rule = FeeRule::new(Decimal::from(15), Decimal::from(0), None, None);
+        fee_schedule.set_rule(TransactionType::Chargeback, rule);
+        app.set_fee_schedule(fee_schedule);
+        let records = vec![
+            vec!["deposit", "2", "4", "5"],
+            vec!["deposit", "2", "5", "10"],
+            vec!["dispute", "2", "5", ""],
+            vec!["chargeback", "2", "5", ""],
+        ];
+        for record in records {
+            app.process_record(StringRecord::from(record)).unwrap();
+        }
+        assert_eq!(app.get_available_balance(2), Decimal::from(0));
+        assert_eq!(app.get_total_balance(2), Decimal::from(0));
+        assert_eq!(app.fees_for(5)[0].amount, Decimal::from(5));
+        assert_eq!(app.fee_revenue().balance("USD"), Decimal::from(5));
+    }
+
+    #[test]
+    fn fees_are_charged_as_per_the_rule_of_the_currency() {
+        let mut app = App::new();
+        let mut fee_schedule = FeeSchedule::new();
+        let rule = FeeRule::new(Decimal::from(1), Decimal::from(0), None, None);
+        fee_schedule.set_rule(TransactionType::Withdrawal, rule);
+        let rule = FeeRule::new(Decimal::from(100), Decimal::from(0), None, None);
+        fee_schedule.set_rule_in(TransactionType::Withdrawal, "JPY", rule);
+        app.set_fee_schedule(fee_schedule);
+        let records = vec![
+            vec!["deposit", "2", "1", "1000", "JPY"],
+            vec!["deposit", "2", "2", "10", "EUR"],
+            vec!["withdrawal", "2", "3", "500", "JPY"],
+            vec!["withdrawal", "2", "4", "5", "EUR"],
+        ];
+        for record in records {
+            app.process_record(StringRecord::from(record)).unwrap();
+        }
+        let account = app.get_account(2).unwrap();
+        assert_eq!(account.available_balance_in("JPY"), Decimal::from(400));
+        assert_eq!(account.available_balance_in("EUR"), Decimal::from(5));
+        assert_eq!(app.fee_revenue().balance("JPY"), Decimal::from(100));
+        assert_eq!(app.fee_revenue().balance("EUR"), Decimal::from(0));
+        assert_eq!(app.fee_revenue().balance("USD"), Decimal::from(0));
+    }
+
+    #[test]
+    fn the_largest_client_id_is_an_ordinary_client() {
+        let mut app = App::new();
+        let tx = Transaction::from_record(StringRecord::from(vec!["deposit", "65535", "1", "5"]));
+        app.process(tx.unwrap()).unwrap();
+        assert_eq!(app.get_available_balance(65535), Decimal::from(5));
+    }
+
+    #[test]
+    fn withdrawal_can_use_the_credit_line_down_to_the_limit() {
+        let mut app = App::new();
+        app.set_credit_limit(&CreditLimit {
+            client_id: 2,
+            limit: Decimal::from(100),
+            currency: "USD".to_string(),
+        })
+        .unwrap();
+        let tx1 = Transaction::from_record(StringRecord::from(vec!["deposit", "2", "4", "10"]));
+        app.process(tx1.unwrap()).unwrap();
+        let tx2 = Transaction::from_record(StringRecord::from(vec!["withdrawal", "2", "5", "60"]));
+        app.process(tx2.unwrap()).unwrap();
+        assert_eq!(app.get_available_balance(2), Decimal::from(-50));
+        assert_eq!(app.get_total_balance(2), Decimal::from(-50));
+        let tx3 = Transaction::from_record(StringRecord::from(vec!["withdrawal", "2", "6", "60"]));
+        let result = app.process(tx3.unwrap());
+        assert!(matches!(
+            result,
+            Err(RejectedTransaction::CreditLimitExceeded)
+        ));
+        let tx4 = Transaction::from_record(StringRecord::from(vec!["deposit", "3", "7", "10"]));
+        app.process(tx4.unwrap()).unwrap();
+        let tx5 = Transaction::from_record(StringRecord::from(vec!["withdrawal", "3", "8", "60"]));
+        let result = app.process(tx5.unwrap());
+        assert!(matches!(
+            result,
+            Err(RejectedTransaction::InsufficientFunds)
+        ));
+    }
+
+    #[test]
+    fn credit_limits_cant_be_granted_by_an_input_row() {
+        let mut app = App::new();
+        let tx1 = Transaction::from_record(StringRecord::from(vec!["deposit", "2", "4", "15"]));
+        app.process(tx1.unwrap()).unwrap();
+        let record = StringRecord::from(vec!["credit_limit", "2", "5", "1000000"]);
+        assert!(matches!(
+            app.process_record(record),
+            Err(RejectedTransaction::InvalidInput)
+        ));
+        let tx2 = Transaction::from_record(StringRecord::from(vec!["withdrawal", "2", "6", "500"]));
+        let result = app.process(tx2.unwrap());
+        assert!(matches!(
+            result,
+            Err(RejectedTransaction::InsufficientFunds)
+        ));
+        assert_eq!(
+            app.get_account(2).unwrap().credit_limit_in("USD"),
+            Decimal::from(0)
+        );
+        assert_eq!(app.get_available_balance(2), Decimal::from(15));
+    }
+
+    #[test]
+    fn withdrawals_exceeding_a_limit_rule_are_rejected_with_the_rule_name() {
+        let mut app = App::new();
+        let mut limits = LimitRules::new();
+        limits.add_rule(LimitRule::MaxWithdrawal {
+            amount: Decimal::from(100),
+        });
+        limits.add_rule(LimitRule::MaxWithdrawalsPerTransactions {
+            count: 1,
+            transactions: 2,
+        });
+        app.set_limits(limits);
+        let tx1 = Transaction::from_record(StringRecord::from(vec!["deposit", "2", "4", "500"]));
+        app.process(tx1.unwrap()).unwrap();
+        let tx2 = Transaction::from_record(StringRecord::from(vec!["withdrawal", "2", "5", "150"]));
+        match app.process(tx2.unwrap()) {
+            Err(RejectedTransaction::LimitExceeded { rule }) => assert_eq!(rule, "max_withdrawal"),
+            _ => unreachable!(),
+        }
+        assert_eq!(app.get_available_balance(2), Decimal::from(500));
+        let tx3 = Transaction::from_record(StringRecord::from(vec!["withdrawal", "2", "6", "50"]));
+        app.process(tx3.unwrap()).unwrap();
+        let tx4 = Transaction::from_record(StringRecord::from(vec!["withdrawal", "2", "7", "50"]));
+        match app.process(tx4.unwrap()) {
+            Err(RejectedTransaction::LimitExceeded { rule }) => {
+                assert_eq!(rule, "max_withdrawals_per_transactions")
+            }
+            _ => unreachable!(),
+        }
+        let tx5 = Transaction::from_record(StringRecord::from(vec!["deposit", "2", "8", "1"]));
+        app.process(tx5.unwrap()).unwrap();
+        let tx6 = Transaction::from_record(StringRecord::from(vec!["withdrawal", "2", "9", "50"]));
+        app.process(tx6.unwrap()).unwrap();
+        assert_eq!(app.get_available_balance(2), Decimal::from(401));
+    }
+
+    #[test]
+    fn authorize_holds_funds_until_partially_captured_and_voided() {
+        let mut app = App::new();
+        let tx1 = Transaction::from_record(StringRecord::from(vec!["deposit", "2", "1", "100"]));
+        app.process(tx1.unwrap()).unwrap();
+        let tx2 = Transaction::from_record(StringRecord::from(vec!["authorize", "2", "2", "40"]));
+        app.process(tx2.unwrap()).unwrap();
+        assert_eq!(app.get_available_balance(2), Decimal::from(60));
+        assert_eq!(app.get_held_balance(2), Decimal::from(40));
+        assert_eq!(app.get_total_balance(2), Decimal::from(100));
+        let tx3 = Transaction::from_record(StringRecord::from(vec!["capture", "2", "2", "15"]));
+        app.process(tx3.unwrap()).unwrap();
+        assert_eq!(app.get_held_balance(2), Decimal::from(25));
+        assert_eq!(app.get_total_balance(2), Decimal::from(85));
+        let tx4 = Transaction::from_record(StringRecord::from(vec!["capture", "2", "2", "30"]));
+        let result = app.process(tx4.unwrap());
+        assert!(matches!(
+            result,
+            Err(RejectedTransaction::InconsistentWithValueHeld)
+        ));
+        let tx5 = Transaction::from_record(StringRecord::from(vec!["void", "3", "2", ""]));
+        let result = app.process(tx5.unwrap());
+        assert!(matches!(result, Err(RejectedTransaction::IDNotFound)));
+        let tx6 = Transaction::from_record(StringRecord::from(vec!["void", "2", "2", ""]));
+        app.process(tx6.unwrap()).unwrap();
+        assert_eq!(app.get_available_balance(2), Decimal::from(85));
+        assert_eq!(app.get_held_balance(2), Decimal::from(0));
+        assert_eq!(app.get_total_balance(2), Decimal::from(85));
+        assert_eq!(app.open_authorizations(), 0);
+    }
+
+    #[test]
+    fn authorizations_of_no_funds_are_rejected() {
+        let mut app = App::new();
+        let tx1 = Transaction::from_record(StringRecord::from(vec!["deposit", "2", "1", "10"]));
+        app.process(tx1.unwrap()).unwrap();
+        for (txid, amount) in [("2", "-5"), ("3", "0")] {
+            let tx =
+                Transaction::from_record(StringRecord::from(vec!["authorize", "2", txid, amount]));
+            let result = app.process(tx.unwrap());
+            assert!(matches!(result, Err(RejectedTransaction::InvalidInput)));
+        }
+        assert_eq!(app.get_available_balance(2), Decimal::from(10));
+        assert_eq!(app.get_held_balance(2), Decimal::from(0));
+        assert_eq!(app.open_authorizations(), 0);
+    }
+
+    #[test]
+    fn resolves_and_chargebacks_without_an_open_dispute_leave_the_holds_alone() {
+        let mut app = App::new();
+        let records = vec![
+            vec!["deposit", "2", "1", "100"],
+            vec!["authorize", "2", "2", "40"],
+            vec!["deposit", "2", "3", "10"],
+        ];
+        for record in records {
+            app.process_record(StringRecord::from(record)).unwrap();
+        }
+        for kind in ["resolve", "chargeback"] {
+            let tx = Transaction::from_record(StringRecord::from(vec![kind, "2", "3", ""]));
+            let result = app.process(tx.unwrap());
+            assert!(matches!(result, Err(RejectedTransaction::IDNotFound)));
+        }
+        assert_eq!(app.get_available_balance(2), Decimal::from(70));
+        assert_eq!(app.get_held_balance(2), Decimal::from(40));
+        assert!(!app.is_locked(2));
+        let tx4 = Transaction::from_record(StringRecord::from(vec!["capture", "2", "2", ""]));
+        app.process(tx4.unwrap()).unwrap();
+        assert_eq!(app.get_total_balance(2), Decimal::from(70));
+    }
+
+    #[test]
+    fn a_transaction_under_dispute_cant_be_disputed_again() {
+        let mut app = App::new();
+        let records = vec![
+            vec!["deposit", "2", "1", "100"],
+            vec!["deposit", "2", "2", "10"],
+            vec!["dispute", "2", "2", ""],
+        ];
+        for record in records {
+            app.process_record(StringRecord::from(record)).unwrap();
+        }
+        let tx4 = Transaction::from_record(StringRecord::from(vec!["dispute", "2", "2", ""]));
+        let result = app.process(tx4.unwrap());
+        assert!(matches!(result, Err(RejectedTransaction::InvalidInput)));
+        assert_eq!(app.get_held_balance(2), Decimal::from(10));
+        let tx5 = Transaction::from_record(StringRecord::from(vec!["resolve", "2", "2", ""]));
+        app.process(tx5.unwrap()).unwrap();
+        let tx6 = Transaction::from_record(StringRecord::from(vec!["resolve", "2", "2", ""]));
+        let result = app.process(tx6.unwrap());
+        assert!(matches!(result, Err(RejectedTransaction::IDNotFound)));
+        assert_eq!(app.get_available_balance(2), Decimal::from(110));
+        assert_eq!(app.get_held_balance(2), Decimal::from(0));
+    }
+
+    #[test]
+    fn capture_without_amount_takes_what_remains_of_the_authorization() {
+        let mut app = App::new();
+        let tx1 = Transaction::from_record(StringRecord::from(vec!["deposit", "2", "1", "100"]));
+        app.process(tx1.unwrap()).unwrap();
+        let tx2 = Transaction::from_record(StringRecord::from(vec!["authorize", "2", "2", "40"]));
+        app.process(tx2.unwrap()).unwrap();
+        let tx3 = Transaction::from_record(StringRecord::from(vec!["capture", "2", "2", ""]));
+        let captured = app.process(tx3.unwrap()).unwrap();
+        assert_eq!(captured.amount, Some(Decimal::from(40)));
+        assert_eq!(app.get_total_balance(2), Decimal::from(60));
+        assert_eq!(app.open_authorizations(), 0);
+    }
+
+    #[test]
+    fn stale_authorizations_expire_after_a_number_of_transactions() {
+        let mut app = App::new();
+        app.set_authorization_expiry(AuthorizationExpiry {
+            transactions: Some(1),
+            seconds: None,
+        });
+        let tx1 = Transaction::from_record(StringRecord::from(vec!["deposit", "2", "1", "100"]));
+        app.process(tx1.unwrap()).unwrap();
+        let tx2 = Transaction::from_record(StringRecord::from(vec!["authorize", "2", "2", "40"]));
+        app.process(tx2.unwrap()).unwrap();
+        let tx3 = Transaction::from_record(StringRecord::from(vec!["deposit", "3", "3", "1"]));
+        app.process(tx3.unwrap()).unwrap();
+        assert_eq!(app.get_held_balance(2), Decimal::from(40));
+        let tx4 = Transaction::from_record(StringRecord::from(vec!["deposit", "3", "4", "1"]));
+        app.process(tx4.unwrap()).unwrap();
+        assert_eq!(app.get_held_balance(2), Decimal::from(0));
+        assert_eq!(app.get_available_balance(2), Decimal::from(100));
+        let tx5 = Transaction::from_record(StringRecord::from(vec!["capture", "2", "2", ""]));
+        let result = app.process(tx5.unwrap());
+        assert!(matches!(result, Err(RejectedTransaction::IDNotFound)));
+    }
+
+    #[test]
+    fn expired_holds_are_told_as_released_before_the_transaction_expiring_them() {
+        let log = Arc::new(EventLog::new());
+        let mut app = App::new();
+        app.subscribe(log.clone());
+        app.set_authorization_expiry(AuthorizationExpiry {
+            transactions: Some(1),
+            seconds: None,
+        });
+        let records = vec![
+            vec!["deposit", "2", "1", "100", "EUR"],
+            vec!["authorize", "2", "2", "40", "EUR"],
+            vec!["capture", "2", "2", "15"],
+        ];
+        for record in records {
+            app.process_record(StringRecord::from(record)).unwrap();
+        }
+        assert!(log
+            .events()
+            .iter()
+            .all(|event| !matches!(event, Event::HoldReleased(_))));
+        let tx4 = Transaction::from_record(StringRecord::from(vec!["deposit", "3", "4", "1"]));
+        app.process(tx4.unwrap()).unwrap();
+        let events = log.events();
+        let released = &events[events.len() - 2];
+        assert!(matches!(
+            released,
+            Event::HoldReleased(void) if void.id == 2
+                && void.kind == TransactionType::Void
+                && void.amount == Some(Decimal::from(25))
+                && void.currency == "EUR"
+        ));
+        assert_eq!(released.client_id(), 2);
+        assert!(matches!(events.last(), Some(Event::TransactionAccepted(tx)) if tx.id == 4));
+        let account = app.get_account(2).unwrap();
+        assert_eq!(account.available_balance_in("EUR"), Decimal::from(85));
+    }
+
+    #[test]
+    fn refunds_are_linked_to_the_original_withdrawal_and_capped_by_its_amount() {
+        let mut app = App::new();
+        let tx1 = Transaction::from_record(StringRecord::from(vec!["deposit", "2", "1", "100"]));
+        app.process(tx1.unwrap()).unwrap();
+        let tx2 = Transaction::from_record(StringRecord::from(vec![
+            "withdrawal",
+            "2",
+            "2",
+            "30",
+            "USD",
+        ]));
+        app.process(tx2.unwrap()).unwrap();
+        let tx3 = Transaction::from_record(StringRecord::from(vec!["refund", "2", "2", "10"]));
+        app.process(tx3.unwrap()).unwrap();
+        let tx4 = Transaction::from_record(StringRecord::from(vec!["refund", "2", "2", "15"]));
+        app.process(tx4.unwrap()).unwrap();
+        assert_eq!(app.get_available_balance(2), Decimal::from(95));
+        assert_eq!(app.get_total_balance(2), Decimal::from(95));
+        let tx5 = Transaction::from_record(StringRecord::from(vec!["refund", "2", "2", "10"]));
+        let result = app.process(tx5.unwrap());
+        assert!(matches!(
+            result,
+            Err(RejectedTransaction::RefundExceedsWithdrawal)
+        ));
+        let tx6 = Transaction::from_record(StringRecord::from(vec!["refund", "3", "2", "5"]));
+        let result = app.process(tx6.unwrap());
+        assert!(matches!(result, Err(RejectedTransaction::ClientMismatch)));
+        let tx7 = Transaction::from_record(StringRecord::from(vec!["refund", "2", "1", "5"]));
+        let result = app.process(tx7.unwrap());
+        assert!(matches!(result, Err(RejectedTransaction::IDNotFound)));
+        let tx8 = Transaction::from_record(StringRecord::from(vec!["refund", "2", "2", "5"]));
+        app.process(tx8.unwrap()).unwrap();
+        assert_eq!(app.get_available_balance(2), Decimal::from(100));
+    }
+
+    #[test]
+    fn rejected_withdrawals_cannot_be_refunded() {
+        let mut app = App::new();
+        let tx1 = Transaction::from_record(StringRecord::from(vec!["deposit", "2", "1", "10"]));
+        app.process(tx1.unwrap()).unwrap();
+        let tx2 = Transaction::from_record(StringRecord::from(vec!["withdrawal", "2", "2", "30"]));
+        assert!(app.process(tx2.unwrap()).is_err());
+        let tx3 = Transaction::from_record(StringRecord::from(vec!["refund", "2", "2", "30"]));
+        let result = app.process(tx3.unwrap());
+        assert!(matches!(result, Err(RejectedTransaction::IDNotFound)));
+        assert_eq!(app.get_available_balance(2), Decimal::from(10));
+    }
+
+    #[test]
+    fn balance_at_answers_the_balances_at_a_point_in_time() {
+        let mut app = App::new();
+        let records = vec![
+            vec!["deposit", "5", "1", "100", "", "2022-08-30T09:00:00Z"],
+            vec!["withdrawal", "5", "2", "30", "", "2022-08-30T16:59:59Z"],
+            vec!["dispute", "5", "2", "", "", "2022-08-30T17:00:01Z"],
+            vec!["deposit", "5", "3", "10", "EUR", "2022-08-30T18:00:00Z"],
+        ];
+        for record in records {
+            app.process_record(StringRecord::from(record)).unwrap();
+        }
+        assert!(app.balance_at(5, 1661849999).unwrap().is_none());
+        let at_five = app.balance_at(5, 1661878800).unwrap().unwrap();
+        assert_eq!(at_five.balance("USD").available(), Decimal::from(70));
+        assert_eq!(at_five.balance("USD").held(), Decimal::from(0));
+        assert_eq!(at_five.balance("EUR").total(), Decimal::from(0));
+        let at_six = app.balance_at(5, 1661882400).unwrap().unwrap();
+        assert_eq!(at_six.balance("USD").held(), Decimal::from(30));
+        assert_eq!(at_six.balance("EUR").total(), Decimal::from(10));
+        assert!(app.balance_at(6, 1661882400).unwrap().is_none());
+    }
+
+    #[test]
+    fn balance_at_takes_each_currency_from_its_latest_change() {
+        let mut app = App::new();
+        let records = vec![
+            vec!["deposit", "5", "1", "100", "", "1000"],
+            vec!["deposit", "5", "2", "10", "EUR", "2000"],
+            vec!["deposit", "5", "3", "5", "EUR", "2000"],
+            vec!["withdrawal", "5", "4", "40", "", "3000"],
+        ];
+        for record in records {
+            app.process_record(StringRecord::from(record)).unwrap();
+        }
+        let at_two = app.balance_at(5, 2000).unwrap().unwrap();
+        assert_eq!(at_two.timestamp, 2000);
+        assert_eq!(at_two.balance("USD").total(), Decimal::from(100));
+        assert_eq!(at_two.balance("EUR").total(), Decimal::from(15));
+        let at_three = app.balance_at(5, 3000).unwrap().unwrap();
+        assert_eq!(at_three.balance("USD").total(), Decimal::from(60));
+        assert_eq!(at_three.balance("EUR").total(), Decimal::from(15));
+    }
+
+    #[test]
+    fn convert_applies_the_rate_effective_at_the_transaction_timestamp() {
+        let mut app = App::new();
+        let mut rates = RateTable::new();
+        rates.set_rate("EUR".into(), "USD".into(), Decimal::from(1.05), 100);
+        rates.set_rate("EUR".into(), "USD".into(), Decimal::from(1.1), 200);
+        app.set_rates(rates);
+        let tx1 =
+            Transaction::from_record(StringRecord::from(vec!["deposit", "2", "4", "20", "EUR"]));
+        app.process(tx1.unwrap()).unwrap();
+        let tx2 = Transaction::from_record(StringRecord::from(vec![
+            "convert", "2", "5", "10", "EUR/USD", "150",
+        ]));
+        let converted = app.process(tx2.unwrap()).unwrap();
+        assert_eq!(converted.rate, Some(Decimal::from(1.05)));
+        let tx3 = Transaction::from_record(StringRecord::from(vec![
+            "convert", "2", "6", "1", "EUR/USD", "50",
+        ]));
+        let result = app.process(tx3.unwrap());
+        assert!(matches!(result, Err(RejectedTransaction::RateNotFound)));
+    }
+
+    #[test]
+    fn convert_without_a_timestamp_applies_the_rate_effective_now() {
+        let mut app = App::with_clock(SimulatedClock::new(100));
+        let mut rates = RateTable::new();
+        rates.set_rate("EUR".into(), "USD".into(), Decimal::from(1.05), 50);
+        rates.set_rate("EUR".into(), "USD".into(), Decimal::from(1.1), 200);
+        app.set_rates(rates);
+        let tx1 =
+            Transaction::from_record(StringRecord::from(vec!["deposit", "2", "4", "20", "EUR"]));
+        app.process(tx1.unwrap()).unwrap();
+        let tx2 = Transaction::from_record(StringRecord::from(vec![
+            "convert", "2", "5", "10", "EUR/USD",
+        ]));
+        let converted = app.process(tx2.unwrap()).unwrap();
+        assert_eq!(converted.rate, Some(Decimal::from(1.05)));
+        assert_eq!(app.get_available_balance(2), Decimal::from(10.5));
+    }
+
+    #[test]
+    fn converts_of_no_funds_are_rejected() {
+        let mut app = App::new();
+        let mut rates = RateTable::new();
+        rates.set_rate("EUR".into(), "USD".into(), Decimal::from(1.1), 0);
+        app.set_rates(rates);
+        let tx1 =
+            Transaction::from_record(StringRecord::from(vec!["deposit", "2", "4", "20", "EUR"]));
+        app.process(tx1.unwrap()).unwrap();
+        for (txid, amount) in [("5", "-10"), ("6", "0")] {
+            let tx = Transaction::from_record(StringRecord::from(vec![
+                "convert", "2", txid, amount, "EUR/USD",
+            ]));
+            let result = app.process(tx.unwrap());
+            assert!(matches!(result, Err(RejectedTransaction::InvalidInput)));
+        }
+        let account = app.get_account(2).unwrap();
+        assert_eq!(account.available_balance_in("EUR"), Decimal::from(20));
+        assert_eq!(account.available_balance_in("USD"), Decimal::from(0));
+    }
+
+    #[test]
+    fn simulated_clock_resets_the_daily_outflow_on_the_next_day_of_the_input() {
+        let mut app = App::with_clock(SimulatedClock::default());
+        let mut limits = LimitRules::new();
+        limits.add_rule(LimitRule::MaxDailyOutflow {
+            currency: "USD".to_string(),
+            amount: Decimal::from(100),
+        });
+        app.set_limits(limits);
+        let records = vec![
+            vec!["deposit", "6", "1", "500", "", "2022-09-01T08:00:00Z"],
+            vec!["withdrawal", "6", "2", "90", "", "2022-09-01T09:00:00Z"],
+        ];
+        for record in records {
+            app.process_record(StringRecord::from(record)).unwrap();
+        }
+        let same_day = StringRecord::from(vec![
+            "withdrawal",
+            "6",
+            "3",
+            "20",
+            "",
+            "2022-09-01T23:59:59Z",
+        ]);
+        let result = app.process_record(same_day);
+        assert!(matches!(
+            result,
+            Err(RejectedTransaction::LimitExceeded { .. })
+        ));
+        let next_day = StringRecord::from(vec![
+            "withdrawal",
+            "6",
+            "4",
+            "20",
+            "",
+            "2022-09-02T00:00:00Z",
+        ]);
+        app.process_record(next_day).unwrap();
+        assert_eq!(app.get_available_balance(6), Decimal::from(390));
+    }
+
+    #[test]
+    fn simulated_clock_expires_holds_after_the_seconds_elapsed_in_the_input() {
+        let mut app = App::with_clock(SimulatedClock::default());
+        app.set_authorization_expiry(AuthorizationExpiry {
+            transactions: None,
+            seconds: Some(3600),
+        });
+        let records = vec![
+            vec!["deposit", "6", "1", "100", "", "2022-09-02T09:00:00Z"],
+            vec!["authorize", "6", "2", "30", "", "2022-09-02T10:00:00Z"],
+            vec!["deposit", "6", "3", "1", "", "2022-09-02T11:00:00Z"],
+        ];
+        for record in records {
+            app.process_record(StringRecord::from(record)).unwrap();
+        }
+        assert_eq!(app.get_held_balance(6), Decimal::from(30));
+        let later = StringRecord::from(vec!["deposit", "6", "4", "1", "", "2022-09-02T11:00:01Z"]);
+        app.process_record(later).unwrap();
+        assert_eq!(app.get_held_balance(6), Decimal::from(0));
+        assert_eq!(app.get_available_balance(6), Decimal::from(102));
+    }
+
+    #[test]
+    fn simulated_clock_never_goes_back_in_time() {
+        let mut app = App::with_clock(SimulatedClock::new(200));
+        let tx1 = Transaction::from_record(StringRecord::from(vec![
+            "deposit", "6", "1", "10", "", "100",
+        ]));
+        app.process(tx1.unwrap()).unwrap();
+        assert_eq!(app.clock().now(), 200);
+        let tx2 = Transaction::from_record(StringRecord::from(vec!["deposit", "6", "2", "10"]));
+        app.process(tx2.unwrap()).unwrap();
+        assert_eq!(app.clock().now(), 200);
+        let tx3 = Transaction::from_record(StringRecord::from(vec![
+            "deposit", "6", "3", "10", "", "300",
+        ]));
+        app.process(tx3.unwrap()).unwrap();
+        assert_eq!(app.clock().now(), 300);
+    }
+
+    #[test]
+    fn disputes_after_a_number_of_transactions_are_rejected_and_the_transaction_evicted() {
+        let mut app = App::new();
+        app.set_dispute_window(DisputeWindow {
+            transactions: Some(2),
+            seconds: None,
+        });
+        let records = vec![
+            vec!["deposit", "3", "1", "100"],
+            vec!["deposit", "3", "2", "10"],
+            vec!["dispute", "3", "1", ""],
+            vec!["resolve", "3", "1", ""],
+        ];
+        for record in records {
+            app.process_record(StringRecord::from(record)).unwrap();
+        }
+        // The dispute of tx 1 was closed out of its window
+        assert_eq!(app.transactions_size().unwrap(), 1);
+        let tx5 = Transaction::from_record(StringRecord::from(vec!["dispute", "3", "1", ""]));
+        let result = app.process(tx5.unwrap());
+        assert!(matches!(
+            result,
+            Err(RejectedTransaction::DisputeWindowExpired)
+        ));
+        let tx6 = Transaction::from_record(StringRecord::from(vec!["dispute", "3", "2", ""]));
+        let result = app.process(tx6.unwrap());
+        assert!(matches!(
+            result,
+            Err(RejectedTransaction::DisputeWindowExpired)
+        ));
+        assert_eq!(app.transactions_size().unwrap(), 0);
+        assert_eq!(app.get_held_balance(3), Decimal::from(0));
+    }
+
+    #[test]
+    fn disputes_of_ids_never_stored_arent_taken_for_evicted_ones() {
+        let mut app = App::new();
+        app.set_dispute_window(DisputeWindow {
+            transactions: Some(1),
+            seconds: None,
+        });
+        for txid in ["1", "2", "5", "4", "3", "10", "11", "12"] {
+            let record = StringRecord::from(vec!["deposit", "3", txid, "1"]);
+            app.process_record(record).unwrap();
+        }
+        for txid in ["1", "3", "5", "10"] {
+            let record = StringRecord::from(vec!["dispute", "3", txid, ""]);
+            let result = app.process_record(record);
+            assert!(matches!(
+                result,
+                Err(RejectedTransaction::DisputeWindowExpired)
+            ));
+        }
+        for txid in ["6", "9", "100"] {
+            let record = StringRecord::from(vec!["dispute", "3", txid, ""]);
+            let result = app.process_record(record);
+            assert!(matches!(result, Err(RejectedTransaction::IDNotFound)));
+        }
+    }
+
+    #[test]
+    fn disputes_after_the_elapsed_time_are_rejected_but_open_disputes_are_kept() {
+        let mut app = App::with_clock(SimulatedClock::default());
+        app.set_dispute_window(DisputeWindow {
+            transactions: None,
+            seconds: Some(3600),
+        });
+        let records = vec![
+            vec!["deposit", "3", "1", "100", "", "1000"],
+            vec!["deposit", "3", "2", "10", "", "2000"],
+            vec!["dispute", "3", "2", "", "", "4600"],
+        ];
+        for record in records {
+            app.process_record(StringRecord::from(record)).unwrap();
+        }
+        let late = StringRecord::from(vec!["dispute", "3", "1", "", "", "4601"]);
+        let result = app.process_record(late);
+        assert!(matches!(
+            result,
+            Err(RejectedTransaction::DisputeWindowExpired)
+        ));
+        // The disputed tx 2 stays until its dispute is closed
+        let later = StringRecord::from(vec!["deposit", "3", "3", "1", "", "9000"]);
+        app.process_record(later).unwrap();
+        assert_eq!(app.transactions_size().unwrap(), 2);
+        let chargeback = StringRecord::from(vec!["chargeback", "3", "2", "", "", "9001"]);
+        app.process_record(chargeback).unwrap();
+        assert_eq!(app.transactions_size().unwrap(), 1);
+        assert_eq!(app.get_total_balance(3), Decimal::from(101));
+    }
+
+    #[test]
+    fn aged_disputes_are_resolved_with_a_synthetic_transaction() {
+        let mut app = App::new();
+        app.set_dispute_aging(DisputeAging {
+            transactions: Some(2),
+            seconds: None,
+            action: AgedDisputeAction::Resolve,
+        });
+        let records = vec![
+            vec!["deposit", "4", "1", "100"],
+            vec!["deposit", "4", "2", "10"],
+            vec!["dispute", "4", "1", ""],
+            vec!["deposit", "4", "3", "1"],
+            vec!["deposit", "4", "4", "1"],
+        ];
+        for record in records {
+            app.process_record(StringRecord::from(record)).unwrap();
+            assert!(app.age_disputes().is_empty());
+        }
+        assert_eq!(app.get_held_balance(4), Decimal::from(100));
+        let tx6 = Transaction::from_record(StringRecord::from(vec!["deposit", "4", "5", "1"]));
+        app.process(tx6.unwrap()).unwrap();
+        let aged = app.age_disputes();
+        assert_eq!(aged.len(), 1);
+        assert_eq!(aged[0].kind, TransactionType::Resolve);
+        assert_eq!(aged[0].id, 1);
+        assert_eq!(app.get_held_balance(4), Decimal::from(0));
+        assert_eq!(app.get_available_balance(4), Decimal::from(113));
+        assert!(app.age_disputes().is_empty());
+    }
+
+    #[test]
+    fn aged_disputes_can_be_escalated_to_a_chargeback() {
+        let mut app = App::with_clock(SimulatedClock::default());
+        app.set_dispute_aging(DisputeAging {
+            transactions: None,
+            seconds: Some(60),
+            action: AgedDisputeAction::Chargeback,
+        });
+        let records = vec![
+            vec!["deposit", "4", "1", "100", "", "1000"],
+            vec!["deposit", "4", "2", "10", "", "1000"],
+            vec!["dispute", "4", "2", "", "", "1030"],
+            vec!["deposit", "4", "3", "1", "", "1090"],
+        ];
+        for record in records {
+            app.process_record(StringRecord::from(record)).unwrap();
+            assert!(app.age_disputes().is_empty());
+        }
+        let tx5 = Transaction::from_record(StringRecord::from(vec![
+            "deposit", "4", "4", "1", "", "1091",
+        ]));
+        app.process(tx5.unwrap()).unwrap();
+        let aged = app.age_disputes();
+        assert_eq!(aged.len(), 1);
+        assert_eq!(aged[0].kind, TransactionType::Chargeback);
+        assert!(app.is_locked(4));
+        assert_eq!(app.get_total_balance(4), Decimal::from(102));
+    }
+
+    #[test]
+    fn aged_disputes_that_cant_be_closed_stay_open_and_get_rejected() {
+        let log = Arc::new(EventLog::new());
+        let mut app = App::new();
+        app.subscribe(log.clone());
+        app.set_dispute_aging(DisputeAging {
+            transactions: Some(2),
+            seconds: None,
+            action: AgedDisputeAction::Resolve,
+        });
+        let records = vec![
+            vec!["deposit", "4", "1", "100"],
+            vec!["deposit", "4", "2", "10"],
+            vec!["deposit", "4", "3", "5"],
+            vec!["dispute", "4", "1", ""],
+            vec!["dispute", "4", "2", ""],
+            vec!["chargeback", "4", "2", ""],
+        ];
+        for record in records {
+            app.process_record(StringRecord::from(record)).unwrap();
+            assert!(app.age_disputes().is_empty());
+        }
+        // The account is locked, the aged dispute of tx 1 can't be resolved on its behalf
+        let tx7 = Transaction::from_record(StringRecord::from(vec!["deposit", "5", "4", "1"]));
+        app.process(tx7.unwrap()).unwrap();
+        assert!(app.age_disputes().is_empty());
+        assert!(matches!(
+            log.events().last(),
+            Some(Event::TransactionRejected {
+                reason: RejectedTransaction::AccountLocked,
+                transaction,
+            }) if transaction.id == 1 && transaction.kind == TransactionType::Resolve
+        ));
+        assert_eq!(app.get_held_balance(4), Decimal::from(100));
+        // It stays open but stopped aging, it isn't tried again
+        let events = log.events().len();
+        let tx8 = Transaction::from_record(StringRecord::from(vec!["deposit", "5", "5", "1"]));
+        app.process(tx8.unwrap()).unwrap();
+        assert!(app.age_disputes().is_empty());
+        assert_eq!(log.events().len(), events + 1);
+        assert!(app.disputes.is_open(1));
+    }
+
+    #[test]
+    fn spilled_transactions_can_still_be_disputed_and_charged_back() {
+        let mut app = App::new();
+        // Room for about a single transaction in memory
+        app.set_memory_budget(200, None).unwrap();
+        for txid in 1..=5 {
+            let record = vec![
+                "deposit".to_string(),
+                "8".to_string(),
+                txid.to_string(),
+                "10".to_string(),
+            ];
+            app.process_record(StringRecord::from(record)).unwrap();
+        }
+        assert_eq!(app.transactions_size().unwrap(), 5);
+        let tx6 = Transaction::from_record(StringRecord::from(vec!["dispute", "8", "1", ""]));
+        app.process(tx6.unwrap()).unwrap();
+        assert_eq!(app.get_held_balance(8), Decimal::from(10));
+        let tx7 = Transaction::from_record(StringRecord::from(vec!["chargeback", "8", "1", ""]));
+        app.process(tx7.unwrap()).unwrap();
+        assert_eq!(app.get_total_balance(8), Decimal::from(40));
+        assert!(app.is_locked(8));
+    }
+
+    #[test]
+    fn transactions_needing_a_failing_store_are_rejected() {
+        let log = Arc::new(EventLog::new());
+        let mut app =
+            App::with_stores(SystemClock, Accounts::new(), FailingTransactions::default());
+        app.subscribe(log.clone());
+        for record in [
+            vec!["deposit", "9", "1", "50"],
+            vec!["deposit", "9", "2", "1"],
+        ] {
+            app.process_record(StringRecord::from(record)).unwrap();
+        }
+        app.transactions.failing = true;
+        let dispute = StringRecord::from(vec!["dispute", "9", "1", ""]);
+        let result = app.process_record(dispute);
+        assert!(matches!(result, Err(RejectedTransaction::StorageFailed)));
+        assert!(matches!(
+            log.events().last(),
+            Some(Event::TransactionRejected {
+                reason: RejectedTransaction::StorageFailed,
+                ..
+            })
+        ));
+        assert_eq!(app.get_held_balance(9), Decimal::from(0));
+        app.transactions.failing = false;
+        let dispute = StringRecord::from(vec!["dispute", "9", "1", ""]);
+        app.process_record(dispute).unwrap();
+        assert_eq!(app.get_held_balance(9), Decimal::from(50));
+    }
+
+    #[test]
+    fn a_failing_store_leaves_the_balances_fees_and_history_unchanged() {
+        let mut app =
+            App::with_stores(SystemClock, Accounts::new(), FailingTransactions::default());
+        let mut fee_schedule = FeeSchedule::new();
+        let rule = FeeRule::new(Decimal::from(1), Decimal::from(0), None, None);
+        fee_schedule.set_rule(TransactionType::Withdrawal, rule);
+        app.set_fee_schedule(fee_schedule);
+        let mut rates = RateTable::new();
+        rates.set_rate("USD".into(), "EUR".into(), Decimal::from(0.9), 0);
+        app.set_rates(rates);
+        let deposit = StringRecord::from(vec!["deposit", "9", "1", "50", "", "1000"]);
+        app.process_record(deposit).unwrap();
+        app.transactions.failing = true;
+        // Storing the transaction fails once the balances changed
+        let records = vec![
+            vec!["deposit", "9", "2", "10"],
+            vec!["withdrawal", "9", "3", "20"],
+            vec!["deposit", "10", "4", "5"],
+        ];
+        for record in records {
+            let result = app.process_record(StringRecord::from(record));
+            assert!(matches!(result, Err(RejectedTransaction::StorageFailed)));
+        }
+        // Journaling fails once the history recorded the conversion too
+        for timestamp in ["1000", "2000"] {
+            let convert = StringRecord::from(vec!["convert", "9", "5", "30", "USD/EUR", timestamp]);
+            let result = app.process_record(convert);
+            assert!(matches!(result, Err(RejectedTransaction::StorageFailed)));
+        }
+        assert_eq!(app.get_available_balance(9), Decimal::from(50));
+        assert_eq!(app.get_account(9).unwrap().currencies(), vec!["USD"]);
+        assert_eq!(app.fee_revenue().balance("USD"), Decimal::from(0));
+        assert!(app.fees_for(3).is_empty());
+        assert!(matches!(
+            app.get_account(10),
+            Err(RejectedTransaction::IDNotFound)
+        ));
+        let at_two = app.balance_at(9, 2000).unwrap().unwrap();
+        assert_eq!(at_two.timestamp, 1000);
+        assert_eq!(at_two.balance("USD").available(), Decimal::from(50));
+        assert_eq!(at_two.balances.len(), 1);
+        app.transactions.failing = false;
+        let withdrawal = StringRecord::from(vec!["withdrawal", "9", "3", "20"]);
+        app.process_record(withdrawal).unwrap();
+        assert_eq!(app.get_available_balance(9), Decimal::from(29));
+        assert_eq!(app.fee_revenue().balance("USD"), Decimal::from(1));
+    }
+
+    #[test]
+    fn the_memory_budget_counts_the_dispute_bookkeeping() {
+        let mut app = App::new();
+        app.set_dispute_window(DisputeWindow {
+            transactions: Some(1000),
+            seconds: None,
+        });
+        let budget = 2000;
+        app.set_memory_budget(budget, None).unwrap();
+        for txid in 1..=50 {
+            let record = vec![
+                "deposit".to_string(),
+                "8".to_string(),
+                txid.to_string(),
+                "10".to_string(),
+            ];
+            app.process_record(StringRecord::from(record)).unwrap();
+        }
+        // Without the bookkeeping of the dispute window, all of them would fit
+        let hot = app.transactions_size().unwrap() - app.transactions.cold_size();
+        assert!(hot < 20, "{} hot transactions", hot);
+        let used = app.transactions.memory_used();
+        assert!(used <= budget + 100, "{} bytes used", used);
+        assert_eq!(app.get_available_balance(8), Decimal::from(500));
+    }
+
+    #[test]
+    fn app_runs_against_a_custom_transaction_store() {
+        let mut app = App::with_stores(
+            SystemClock,
+            Accounts::new(),
+            CountingTransactions::default(),
+        );
+        let records = vec![
+            vec!["deposit", "9", "1", "50"],
+            vec!["deposit", "9", "2", "1"],
+            vec!["dispute", "9", "1", ""],
+            vec!["resolve", "9", "1", ""],
+        ];
+        for record in records {
+            app.process_record(StringRecord::from(record)).unwrap();
+        }
+        assert_eq!(app.transactions_size().unwrap(), 2);
+        assert_eq!(app.transactions.lookups.get(), 2);
+        assert_eq!(app.get_available_balance(9), Decimal::from(51));
+    }
+
+    #[test]
+    fn sqlite_stores_keep_balances_and_transactions_across_runs() {
+        let path = std::env::temp_dir().join(format!("integrator-{}.db", std::process::id()));
+        let filename = path.to_str().unwrap();
+        let (accounts, transactions) = sqlite::open(filename).unwrap();
+        let mut app = App::with_stores(SystemClock, accounts, transactions);
+        let records = vec![
+            vec!["deposit", "10", "1", "30"],
+            vec!["deposit", "10", "2", "5"],
+            vec!["withdrawal", "10", "3", "100"],
+        ];
+        for record in records {
+            let _ = app.process_record(StringRecord::from(record));
+        }
+        drop(app);
+        // A second run picks up where the first one left
+        let (accounts, transactions) = sqlite::open(filename).unwrap();
+        let mut app = App::with_stores(SystemClock, accounts, transactions);
+        assert_eq!(
+            app.get_account(10).unwrap().available_balance(),
+            Decimal::from(35)
+        );
+        assert_eq!(app.transactions_size().unwrap(), 2);
+        let tx4 = Transaction::from_record(StringRecord::from(vec!["dispute", "10", "1", ""]));
+        app.process(tx4.unwrap()).unwrap();
+        assert_eq!(
+            app.get_account(10).unwrap().held_balance(),
+            Decimal::from(30)
+        );
+        drop(app);
+        let connection = rusqlite::Connection::open(filename).unwrap();
+        let journaled: usize = connection
+            .query_row("SELECT COUNT(*) FROM journal", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(journaled, 3);
+        drop(connection);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", filename, suffix));
+        }
+    }
+
+    #[test]
+    fn sqlite_stores_keep_the_activity_of_the_limit_rules_across_runs() {
+        let path =
+            std::env::temp_dir().join(format!("integrator-limits-{}.db", std::process::id()));
+        let filename = path.to_str().unwrap();
+        let open = || {
+            let (accounts, transactions) = sqlite::open(filename).unwrap();
+            let mut app = App::with_stores(SimulatedClock::default(), accounts, transactions);
+            let mut limits = LimitRules::new();
+            limits.add_rule(LimitRule::MaxDailyOutflow {
+                currency: "USD".to_string(),
+                amount: Decimal::from(100),
+            });
+            limits.add_rule(LimitRule::MaxWithdrawalsPerTransactions {
+                count: 2,
+                transactions: 3,
+            });
+            app.set_limits(limits);
+            app
+        };
+        let mut app = open();
+        let records = vec![
+            vec!["deposit", "10", "1", "500", "", "2022-09-01T08:00:00Z"],
+            vec!["withdrawal", "10", "2", "90", "", "2022-09-01T09:00:00Z"],
+        ];
+        for record in records {
+            app.process_record(StringRecord::from(record)).unwrap();
+        }
+        drop(app);
+        // The second run still counts the outflow of the day
+        let mut app = open();
+        let same_day = vec!["withdrawal", "10", "3", "20", "", "2022-09-01T10:00:00Z"];
+        match app.process_record(StringRecord::from(same_day)) {
+            Err(RejectedTransaction::LimitExceeded { rule }) => {
+                assert_eq!(rule, "max_daily_outflow")
+            }
+            _ => unreachable!(),
+        }
+        let next_day = vec!["withdrawal", "10", "4", "20", "", "2022-09-02T10:00:00Z"];
+        app.process_record(StringRecord::from(next_day)).unwrap();
+        drop(app);
+        // and the withdrawals among the last transactions
+        let mut app = open();
+        let third = vec!["withdrawal", "10", "5", "20", "", "2022-09-02T11:00:00Z"];
+        match app.process_record(StringRecord::from(third)) {
+            Err(RejectedTransaction::LimitExceeded { rule }) => {
+                assert_eq!(rule, "max_withdrawals_per_transactions")
+            }
+            _ => unreachable!(),
+        }
+        assert_eq!(
+            app.get_account(10).unwrap().available_balance(),
+            Decimal::from(390)
+        );
+        drop(app);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", filename, suffix));
+        }
+    }
+
+    #[test]
+    fn sqlite_stores_keep_authorizations_disputes_and_fees_across_runs() {
+        let path = std::env::temp_dir().join(format!("integrator-book-{}.db", std::process::id()));
+        let filename = path.to_str().unwrap();
+        let open = || {
+            let (accounts, transactions) = sqlite::open(filename).unwrap();
+            let mut app = App::with_stores(SystemClock, accounts, transactions);
+            let mut fee_schedule = FeeSchedule::new();
+            let rule = FeeRule::new(Decimal::from(1), Decimal::from(0), None, None);
+            fee_schedule.set_rule(TransactionType::Withdrawal, rule);
+            app.set_fee_schedule(fee_schedule);
+            app.set_dispute_window(DisputeWindow {
+                transactions: Some(100),
+                seconds: None,
+            });
+            app
+        };
+        let mut app = open();
+        let records = vec![
+            vec!["deposit", "10", "1", "100", "", "1000"],
+            vec!["deposit", "10", "2", "20", "", "1000"],
+            vec!["dispute", "10", "2", "", "", "2000"],
+            vec!["authorize", "10", "3", "40", "", "2000"],
+            vec!["withdrawal", "10", "4", "10", "", "3000"],
+        ];
+        for record in records {
+            app.process_record(StringRecord::from(record)).unwrap();
+        }
+        drop(app);
+        // The open authorization, the open dispute and the fee are picked up again
+        let mut app = open();
+        let records = vec![
+            vec!["capture", "10", "3", "15", "", "4000"],
+            vec!["resolve", "10", "2", "", "", "4000"],
+        ];
+        for record in records {
+            app.process_record(StringRecord::from(record)).unwrap();
+        }
+        assert_eq!(app.fee_revenue().balance("USD"), Decimal::from(1));
+        let account = app.get_account(10).unwrap();
+        assert_eq!(account.available_balance(), Decimal::from(69));
+        assert_eq!(account.held_balance(), Decimal::from(25));
+        let at_two = app.balance_at(10, 2000).unwrap().unwrap();
+        assert_eq!(at_two.balance("USD").held(), Decimal::from(60));
+        assert_eq!(at_two.balance("USD").available(), Decimal::from(60));
+        drop(app);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", filename, suffix));
+        }
+    }
+
+    // A custom transaction type crediting promotional bonuses of up to 100.
+    #[derive(Debug)]
+    struct BonusHandler;
+
+    impl TransactionHandler for BonusHandler {
+        fn name(&self) -> &'static str {
+            "bonus"
+        }
+
+        fn validate(&self, transaction: &Transaction, _account: &Account) -> Result<()> {
+            match transaction.amount {
+                Some(amount) if amount <= Decimal::from(100) => Ok(()),
+                _ => Err(RejectedTransaction::InvalidInput),
+            }
+        }
+
+        fn apply(&self, transaction: &Transaction, account: &mut Account) -> Result<Transaction> {
+            account.process_deposit(transaction)
+        }
+    }
+
+    #[test]
+    fn custom_transaction_types_are_processed_by_their_registered_handler() {
+        let mut app = App::new();
+        let record = StringRecord::from(vec!["bonus", "11", "1", "25"]);
+        assert!(matches!(
+            app.process_record(record.clone()),
+            Err(RejectedTransaction::InvalidInput)
+        ));
+        app.register_handler(BonusHandler).unwrap();
+        let processed = app.process_record(record).unwrap();
+        assert_eq!(processed.kind, TransactionType::Custom("bonus"));
+        assert_eq!(app.get_available_balance(11), Decimal::from(25));
+        let too_big = StringRecord::from(vec!["bonus", "11", "2", "250"]);
+        assert!(app.process_record(too_big).is_err());
+        assert_eq!(app.get_total_balance(11), Decimal::from(25));
+    }
+
+    // A custom transaction type trying to take over deposits.
+    #[derive(Debug)]
+    struct DepositHandler;
+
+    impl TransactionHandler for DepositHandler {
+        fn name(&self) -> &'static str {
+            "deposit"
+        }
+
+        fn apply(&self, transaction: &Transaction, _account: &mut Account) -> Result<Transaction> {
+            Ok(transaction.clone())
+        }
+    }
+
+    #[test]
+    fn built_in_transaction_types_cant_be_replaced() {
+        let mut app = App::new();
+        assert_eq!(
+            app.register_handler(DepositHandler),
+            Err(ReservedName("deposit"))
+        );
+        let record = StringRecord::from(vec!["deposit", "11", "1", "25"]);
+        app.process_record(record).unwrap();
+        assert_eq!(app.get_available_balance(11), Decimal::from(25));
+    }
+
+    #[test]
+    fn rows_of_accepted_transactions_go_to_the_output_sink() {
+        let mut app = App::new().with_output(MemorySink::new());
+        let mut fee_schedule = FeeSchedule::new();
+        let rule = FeeRule::new(Decimal::from(0.5), Decimal::from(1), None, None);
+        fee_schedule.set_rule(TransactionType::Withdrawal, rule);
+        app.set_fee_schedule(fee_schedule);
+        let records = vec![
+            vec!["deposit", "12", "1", "100"],
+            vec!["deposit", "12", "2", "20", "EUR"],
+            vec!["withdrawal", "12", "3", "50"],
+        ];
+        for record in records {
+            let processed = app.process_record(StringRecord::from(record)).unwrap();
+            app.output_transaction(&processed).unwrap();
+        }
+        assert_eq!(
+            app.output().lines(),
+            [
+                "12,100,0,100,false",
+                "12,20,0,20,false,EUR",
+                "12,49,0,49,false",
+                "fees,1,0,1,false",
+            ]
+        );
+    }
+
+    #[test]
+    fn observers_get_the_events_of_each_transaction_once_it_is_processed() {
+        let log = Arc::new(EventLog::new());
+        let other = Arc::new(EventLog::new());
+        let mut app = App::new();
+        app.subscribe(log.clone());
+        app.subscribe(other.clone());
+        let records = vec![
+            vec!["deposit", "13", "1", "100"],
+            vec!["withdrawal", "13", "2", "500"],
+            vec!["deposit", "13", "3", "10"],
+            vec!["dispute", "13", "1", ""],
+            vec!["chargeback", "13", "1", ""],
+        ];
+        for record in records {
+            let _ = app.process_record(StringRecord::from(record));
+        }
+        let events = log.events();
+        let names: Vec<&str> = events.iter().map(|event| event.name()).collect();
+        assert_eq!(
+            names,
+            [
+                "TransactionAccepted",
+                "TransactionRejected",
+                "TransactionAccepted",
+                "TransactionAccepted",
+                "DisputeOpened",
+                "TransactionAccepted",
+                "ChargebackApplied",
+                "AccountLocked",
+            ]
+        );
+        assert!(events.iter().all(|event| event.client_id() == 13));
+        assert!(matches!(
+            &events[1],
+            Event::TransactionRejected {
+                reason: RejectedTransaction::InsufficientFunds,
+                transaction,
+            } if transaction.id == 2
+        ));
+        assert_eq!(other.events().len(), events.len());
+    }
 }
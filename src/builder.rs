@@ -0,0 +1,206 @@
+use std::{io, sync::Arc};
+
+use crate::{
+    app::App,
+    clock::{Clock, SystemClock},
+    models::{
+        authorizations::AuthorizationExpiry,
+        clients::CreditLimit,
+        disputes::{DisputeAging, DisputeWindow},
+        events::{Observer, Observers},
+        fees::FeeSchedule,
+        handlers::{ReservedName, TransactionHandler, TransactionHandlers},
+        limits::LimitRules,
+        output::{OutputSink, StdoutSink},
+        rates::RateTable,
+        stores::{AccountStore, Accounts, TransactionStore},
+        transactions::Transactions,
+    },
+};
+
+// The rules and policies of an app, which don't depend on where it keeps its state.
+#[derive(Debug, Clone, Default)]
+struct Settings {
+    rates: Option<RateTable>,
+    fee_schedule: Option<FeeSchedule>,
+    credit_limits: Vec<CreditLimit>,
+    limits: Option<LimitRules>,
+    authorization_expiry: AuthorizationExpiry,
+    dispute_window: DisputeWindow,
+    dispute_aging: DisputeAging,
+    handlers: TransactionHandlers,
+    observers: Observers,
+    outbox: bool,
+}
+
+// Configures an app to embed it as a library, all in one place.
+// Anything left out gets the same default as in `App::new()`.
+//
+//     let app = AppBuilder::new()
+//         .clock(SimulatedClock::default())
+//         .output(MemorySink::new())
+//         .dispute_window(DisputeWindow { transactions: Some(1000), seconds: None })
+//         .build()?;
+#[derive(Debug)]
+pub struct AppBuilder<
+    C: Clock = SystemClock,
+    A: AccountStore = Accounts,
+    T: TransactionStore = Transactions,
+    O: OutputSink = StdoutSink,
+> {
+    clock: C,
+    accounts: A,
+    transactions: T,
+    output: O,
+    settings: Settings,
+}
+
+impl AppBuilder {
+    pub fn new() -> Self {
+        Self {
+            clock: SystemClock,
+            accounts: Accounts::new(),
+            transactions: Transactions::new(),
+            output: StdoutSink::new(),
+            settings: Default::default(),
+        }
+    }
+}
+
+impl<C: Clock, A: AccountStore, O: OutputSink> AppBuilder<C, A, Transactions, O> {
+    // Bounds the memory used by the stored transactions, spilling the oldest ones to disk.
+    pub fn memory_budget(
+        mut self,
+        bytes: usize,
+        spill_filename: Option<String>,
+    ) -> io::Result<Self> {
+        self.transactions.set_memory_budget(bytes, spill_filename)?;
+        Ok(self)
+    }
+}
+
+impl<C: Clock, A: AccountStore, T: TransactionStore, O: OutputSink> AppBuilder<C, A, T, O> {
+    pub fn clock<D: Clock>(self, clock: D) -> AppBuilder<D, A, T, O> {
+        AppBuilder {
+            clock,
+            accounts: self.accounts,
+            transactions: self.transactions,
+            output: self.output,
+            settings: self.settings,
+        }
+    }
+
+    // Where the accounts and the transactions to be disputed or refunded are kept.
+    pub fn stores<B: AccountStore, U: TransactionStore>(
+        self,
+        accounts: B,
+        transactions: U,
+    ) -> AppBuilder<C, B, U, O> {
+        AppBuilder {
+            clock: self.clock,
+            accounts,
+            transactions,
+            output: self.output,
+            settings: self.settings,
+        }
+    }
+
+    pub fn output<P: OutputSink>(self, output: P) -> AppBuilder<C, A, T, P> {
+        AppBuilder {
+            clock: self.clock,
+            accounts: self.accounts,
+            transactions: self.transactions,
+            output,
+            settings: self.settings,
+        }
+    }
+
+    pub fn rates(mut self, rates: RateTable) -> Self {
+        self.settings.rates = Some(rates);
+        self
+    }
+
+    pub fn fee_schedule(mut self, fee_schedule: FeeSchedule) -> Self {
+        self.settings.fee_schedule = Some(fee_schedule);
+        self
+    }
+
+    pub fn credit_limit(mut self, credit_limit: CreditLimit) -> Self {
+        self.settings.credit_limits.push(credit_limit);
+        self
+    }
+
+    pub fn limits(mut self, limits: LimitRules) -> Self {
+        self.settings.limits = Some(limits);
+        self
+    }
+
+    pub fn authorization_expiry(mut self, expiry: AuthorizationExpiry) -> Self {
+        self.settings.authorization_expiry = expiry;
+        self
+    }
+
+    pub fn dispute_window(mut self, window: DisputeWindow) -> Self {
+        self.settings.dispute_window = window;
+        self
+    }
+
+    pub fn dispute_aging(mut self, aging: DisputeAging) -> Self {
+        self.settings.dispute_aging = aging;
+        self
+    }
+
+    // Registers a custom transaction type, built-in ones can't be replaced.
+    pub fn handler(
+        mut self,
+        handler: impl TransactionHandler + 'static,
+    ) -> Result<Self, ReservedName> {
+        self.settings.handlers.register(Arc::new(handler))?;
+        Ok(self)
+    }
+
+    // Subscribes an observer to the events of the app, keep a clone to look at what it got.
+    pub fn observer(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.settings.observers.subscribe(observer);
+        self
+    }
+
+    // Writes the events of each transaction to the outbox of the transaction store.
+    pub fn outbox(mut self) -> Self {
+        self.settings.outbox = true;
+        self
+    }
+
+    // Reads what persistent stores kept, so the app picks up where it was left.
+    pub fn build(self) -> io::Result<App<C, A, T, O>> {
+        let settings = self.settings;
+        let mut app =
+            App::with_stores(self.clock, self.accounts, self.transactions).with_output(self.output);
+        if let Some(rates) = settings.rates {
+            app.set_rates(rates);
+        }
+        if let Some(fee_schedule) = settings.fee_schedule {
+            app.set_fee_schedule(fee_schedule);
+        }
+        app.restore()?;
+        for credit_limit in settings.credit_limits {
+            app.set_credit_limit(&credit_limit)?;
+        }
+        if let Some(limits) = settings.limits {
+            app.set_limits(limits);
+        }
+        app.set_authorization_expiry(settings.authorization_expiry);
+        app.set_dispute_window(settings.dispute_window);
+        app.set_dispute_aging(settings.dispute_aging);
+        app.set_handlers(settings.handlers);
+        app.set_observers(settings.observers);
+        app.set_outbox(settings.outbox);
+        Ok(app)
+    }
+}
+
+impl Default for AppBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,14 +1,164 @@
 extern crate clap;
 use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
 
+use crate::models::transaction::{parse_timestamp, ClientID, Timestamp};
+
 pub fn get_input_filename() -> String {
     let args = get_arguments();
+    // The query subcommand brings its own input filename
     let found = args
         .get_one::<String>("input_filename")
         .expect("Failed to get the CSV filename to use as input");
     found.to_string()
 }
 
+// The client and time of a balance query, when running the `query` subcommand.
+pub fn get_query() -> Option<(ClientID, Timestamp)> {
+    let matches = get_command().get_matches();
+    let query = matches.subcommand_matches("query")?;
+    let client = query.get_one::<ClientID>("client")?;
+    let at = query.get_one::<Timestamp>("at")?;
+    Some((*client, *at))
+}
+
+pub fn get_rates_filename() -> Option<String> {
+    get_arguments().get_one::<String>("rates").cloned()
+}
+
+pub fn get_rounding_filename() -> Option<String> {
+    get_arguments().get_one::<String>("rounding").cloned()
+}
+
+pub fn get_fees_filename() -> Option<String> {
+    get_arguments().get_one::<String>("fees").cloned()
+}
+
+pub fn get_clients_filename() -> Option<String> {
+    get_arguments().get_one::<String>("clients").cloned()
+}
+
+pub fn get_limits_filename() -> Option<String> {
+    get_arguments().get_one::<String>("limits").cloned()
+}
+
+pub fn get_hold_expiry_transactions() -> Option<u64> {
+    get_arguments()
+        .get_one::<u64>("hold_expiry_transactions")
+        .copied()
+}
+
+pub fn get_hold_expiry_seconds() -> Option<u64> {
+    get_arguments()
+        .get_one::<u64>("hold_expiry_seconds")
+        .copied()
+}
+
+pub fn get_dispute_window_transactions() -> Option<u64> {
+    get_arguments()
+        .get_one::<u64>("dispute_window_transactions")
+        .copied()
+}
+
+pub fn get_dispute_window_seconds() -> Option<u64> {
+    get_arguments()
+        .get_one::<u64>("dispute_window_seconds")
+        .copied()
+}
+
+pub fn get_dispute_aging_transactions() -> Option<u64> {
+    get_arguments()
+        .get_one::<u64>("dispute_aging_transactions")
+        .copied()
+}
+
+pub fn get_dispute_aging_seconds() -> Option<u64> {
+    get_arguments()
+        .get_one::<u64>("dispute_aging_seconds")
+        .copied()
+}
+
+pub fn get_dispute_aging_action() -> Option<String> {
+    get_arguments()
+        .get_one::<String>("dispute_aging_action")
+        .cloned()
+}
+
+pub fn get_memory_budget() -> Option<usize> {
+    get_arguments().get_one::<usize>("memory_budget").copied()
+}
+
+pub fn get_spill_filename() -> Option<String> {
+    get_arguments().get_one::<String>("spill").cloned()
+}
+
+// The files to write the rows to, none meaning stdout.
+pub fn get_output_filenames() -> Vec<String> {
+    get_arguments()
+        .get_many::<String>("output")
+        .map(|values| values.cloned().collect())
+        .unwrap_or_default()
+}
+
+pub fn get_db_filename() -> Option<String> {
+    get_arguments().get_one::<String>("db").cloned()
+}
+
+pub fn get_webhooks_filename() -> Option<String> {
+    get_arguments().get_one::<String>("webhooks").cloned()
+}
+
+pub fn get_webhook_log_filename() -> Option<String> {
+    get_arguments().get_one::<String>("webhook_log").cloned()
+}
+
+// Whether the events of each transaction get written to the outbox of the database.
+pub fn get_outbox() -> bool {
+    get_arguments()
+        .get_one::<bool>("outbox")
+        .copied()
+        .unwrap_or(false)
+}
+
+// The endpoint and retries of the relay, when running the `outbox-relay` subcommand.
+pub fn get_outbox_relay() -> Option<(String, u32)> {
+    let matches = get_command().get_matches();
+    let relay = matches.subcommand_matches("outbox-relay")?;
+    let endpoint = relay.get_one::<String>("endpoint")?;
+    let retries = relay.get_one::<u32>("retries")?;
+    Some((endpoint.clone(), *retries))
+}
+
+// The address and threads of the server, when running the `serve` subcommand.
+pub fn get_serve() -> Option<(String, usize)> {
+    let matches = get_command().get_matches();
+    let serve = matches.subcommand_matches("serve")?;
+    let listen = serve.get_one::<String>("listen")?;
+    let threads = serve.get_one::<usize>("threads")?;
+    Some((listen.clone(), *threads))
+}
+
+// The address of the gRPC service, when served along with the HTTP API.
+pub fn get_grpc_address() -> Option<String> {
+    let matches = get_command().get_matches();
+    let serve = matches.subcommand_matches("serve")?;
+    serve.get_one::<String>("grpc").cloned()
+}
+
+// The address of the line-oriented TCP ingestion, when served along with the HTTP API.
+pub fn get_tcp_address() -> Option<String> {
+    let matches = get_command().get_matches();
+    let serve = matches.subcommand_matches("serve")?;
+    serve.get_one::<String>("tcp").cloned()
+}
+
+// Whether time comes from the timestamps of the input instead of the system clock.
+pub fn get_simulated_clock() -> bool {
+    get_arguments()
+        .get_one::<bool>("simulated_clock")
+        .copied()
+        .unwrap_or(false)
+}
+
 pub fn get_command<'a>() -> Command<'a> {
     Command::new("integrator")
     .version("1.0")
@@ -24,8 +174,290 @@ pub fn get_command<'a>() -> Command<'a> {
             .value_name("FILENAME")
             .takes_value(true),
     )
+    .arg(
+        Arg::new("rates")
+            .long("rates")
+            .value_parser(value_parser!(String))
+            .help("Defines the CSV filename with the exchange rates (pair, rate, effective timestamp).")
+            .value_name("FILE")
+            .global(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("rounding")
+            .long("rounding")
+            .requires("rates")
+            .value_parser(value_parser!(String))
+            .help("Defines the CSV filename with the rounding rules per currency (currency, scale, mode).")
+            .value_name("FILE")
+            .global(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("fees")
+            .long("fees")
+            .value_parser(value_parser!(String))
+            .help("Defines the CSV filename with the fee schedule (type, flat, percentage, min, max).")
+            .value_name("FILE")
+            .global(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("clients")
+            .long("clients")
+            .value_parser(value_parser!(String))
+            .help("Defines the CSV filename with the client registry (client, credit_limit, currency).")
+            .value_name("FILE")
+            .global(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("limits")
+            .long("limits")
+            .value_parser(value_parser!(String))
+            .help("Defines the CSV filename with the limit rules (rule, limit, window).")
+            .value_name("FILE")
+            .global(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("hold_expiry_transactions")
+            .long("hold-expiry-transactions")
+            .value_parser(value_parser!(u64))
+            .help("Releases authorizations not captured nor voided after this number of transactions.")
+            .value_name("COUNT")
+            .global(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("hold_expiry_seconds")
+            .long("hold-expiry-seconds")
+            .value_parser(value_parser!(u64))
+            .help("Releases authorizations not captured nor voided after this number of seconds.")
+            .value_name("SECONDS")
+            .global(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("dispute_window_transactions")
+            .long("dispute-window-transactions")
+            .value_parser(value_parser!(u64))
+            .help("Rejects disputes of transactions processed more than this number of transactions ago.")
+            .value_name("COUNT")
+            .global(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("dispute_window_seconds")
+            .long("dispute-window-seconds")
+            .value_parser(value_parser!(u64))
+            .help("Rejects disputes of transactions processed more than this number of seconds ago.")
+            .value_name("SECONDS")
+            .global(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("dispute_aging_transactions")
+            .long("dispute-aging-transactions")
+            .value_parser(value_parser!(u64))
+            .help("Closes disputes still open after this number of transactions.")
+            .value_name("COUNT")
+            .global(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("dispute_aging_seconds")
+            .long("dispute-aging-seconds")
+            .value_parser(value_parser!(u64))
+            .help("Closes disputes still open after this number of seconds.")
+            .value_name("SECONDS")
+            .global(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("dispute_aging_action")
+            .long("dispute-aging-action")
+            .value_parser(["resolve", "chargeback"])
+            .default_value("resolve")
+            .help("How aged disputes get closed, resolving them or escalating them to a chargeback.")
+            .value_name("ACTION")
+            .global(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("memory_budget")
+            .long("memory-budget")
+            .value_parser(value_parser!(usize))
+            .help("Keeps about this number of bytes of transactions in memory, spilling the oldest ones to disk.")
+            .value_name("BYTES")
+            .global(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("spill")
+            .long("spill")
+            .requires("memory_budget")
+            .value_parser(value_parser!(String))
+            .help("Defines the file where transactions over the memory budget go, a temporary one by default.")
+            .value_name("FILE")
+            .global(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("output")
+            .long("output")
+            .action(ArgAction::Append)
+            .value_parser(value_parser!(String))
+            .help("Writes the rows to this file instead of stdout, `-` being stdout. Can be given more than once to write to several places.")
+            .value_name("FILE")
+            .global(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("db")
+            .long("db")
+            .conflicts_with("memory_budget")
+            .value_parser(value_parser!(String))
+            .help("Keeps the accounts and transactions in this SQLite database, created if missing, instead of in memory.")
+            .value_name("FILE")
+            .global(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("outbox")
+            .long("outbox")
+            .requires("db")
+            .action(ArgAction::SetTrue)
+            .help("Writes the events of each transaction to an outbox in the database, along with its journal entry.")
+            .global(true),
+    )
+    .arg(
+        Arg::new("webhooks")
+            .long("webhooks")
+            .requires("webhook_log")
+            .value_parser(value_parser!(String))
+            .help("Posts disputes, chargebacks and locked accounts to the webhooks in this CSV file (url, secret), signed with HMAC-SHA256.")
+            .value_name("FILE")
+            .global(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("webhook_log")
+            .long("webhook-log")
+            .requires("webhooks")
+            .value_parser(value_parser!(String))
+            .help("Appends every webhook delivery attempt to this file, deliveries are numbered on from the last one it has.")
+            .value_name("FILE")
+            .global(true)
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new("simulated_clock")
+            .long("simulated-clock")
+            .action(ArgAction::SetTrue)
+            .help("Uses the timestamps of the input as the current time, making time-based rules deterministic.")
+            .global(true),
+    )
+    .subcommand_negates_reqs(true)
+    .subcommand(
+        Command::new("query")
+            .about("Processes the input and shows the balances a client had at a given time.")
+            .arg(
+                Arg::new("input_filename")
+                    .value_parser(value_parser!(String))
+                    .help("Defines the CSV filename to use as input.")
+                    .required(true)
+                    .value_name("FILENAME")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("client")
+                    .long("client")
+                    .value_parser(value_parser!(ClientID))
+                    .help("The client whose balances to show.")
+                    .required(true)
+                    .value_name("CLIENT")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("at")
+                    .long("at")
+                    .value_parser(|value: &str| {
+                        parse_timestamp(value).ok_or("expected seconds since the Unix epoch or a UTC date and time like 2022-08-30T17:00:00Z")
+                    })
+                    .help("The time of the balances, in seconds since the Unix epoch or as a UTC date and time.")
+                    .required(true)
+                    .value_name("TIMESTAMP")
+                    .takes_value(true),
+            ),
+    )
+    .subcommand(
+        Command::new("serve")
+            .about("Serves a local HTTP API to post transactions to and look at the balances of the accounts.")
+            .arg(
+                Arg::new("listen")
+                    .long("listen")
+                    .value_parser(value_parser!(String))
+                    .default_value("127.0.0.1:8080")
+                    .help("The address to listen on.")
+                    .value_name("ADDRESS")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("threads")
+                    .long("threads")
+                    .value_parser(value_parser!(usize))
+                    .default_value("4")
+                    .help("How many requests are served at once.")
+                    .value_name("COUNT")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("grpc")
+                    .long("grpc")
+                    .value_parser(value_parser!(String))
+                    .help("Also serves the gRPC service of proto/integrator.proto on this address.")
+                    .value_name("ADDRESS")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("tcp")
+                    .long("tcp")
+                    .value_parser(value_parser!(String))
+                    .help("Also takes CSV rows line by line on this address, answering an ACK line per row.")
+                    .value_name("ADDRESS")
+                    .takes_value(true),
+            ),
+    )
+    .subcommand(
+        Command::new("outbox-relay")
+            .about("Delivers the events in the outbox of the database to an HTTP endpoint, marking them delivered.")
+            .arg(
+                Arg::new("endpoint")
+                    .long("endpoint")
+                    .value_parser(value_parser!(String))
+                    .help("The URL each event gets POSTed to as JSON.")
+                    .required(true)
+                    .value_name("URL")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("retries")
+                    .long("retries")
+                    .value_parser(value_parser!(u32))
+                    .default_value("5")
+                    .help("How many times to retry an event the endpoint refuses, waiting twice as long each time.")
+                    .value_name("COUNT")
+                    .takes_value(true),
+            ),
+    )
 }
 
-fn get_arguments<'a>() -> ArgMatches {
-    get_command().get_matches()
-}
\ No newline at end of file
+// The arguments of the subcommand when there is one, global options included.
+fn get_arguments() -> ArgMatches {
+    let matches = get_command().get_matches();
+    match matches.subcommand() {
+        Some((_, subcommand_matches)) => subcommand_matches.clone(),
+        None => matches,
+    }
+}
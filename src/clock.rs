@@ -0,0 +1,57 @@
+use std::{
+    fmt::Debug,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::models::transaction::Timestamp;
+
+// The source of time for the time-based rules (limits, authorization expiry, etc.).
+pub trait Clock: Debug {
+    // Seconds since the Unix epoch.
+    fn now(&self) -> Timestamp;
+
+    // Lets the clock know about the timestamp of a transaction being processed.
+    fn observe(&mut self, _timestamp: Timestamp) {}
+}
+
+// The wall clock of the machine, it ignores the timestamps of the transactions.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default()
+    }
+}
+
+// A deterministic clock driven by the timestamps found in the input.
+// It never goes back in time and it stays still for transactions without a timestamp.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SimulatedClock {
+    now: Timestamp,
+}
+
+impl SimulatedClock {
+    pub fn new(now: Timestamp) -> Self {
+        Self { now }
+    }
+
+    pub fn advance(&mut self, seconds: Timestamp) {
+        self.now += seconds;
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Timestamp {
+        self.now
+    }
+
+    fn observe(&mut self, timestamp: Timestamp) {
+        if timestamp > self.now {
+            self.now = timestamp;
+        }
+    }
+}
@@ -1,14 +1,120 @@
-use std::fs::File;
+use std::{fs::File, io::Read};
 
 use csv::{Reader, ReaderBuilder, Trim};
 
+use crate::{
+    models::{
+        clients::CreditLimit,
+        fees::{FeeRule, FeeSchedule},
+        limits::{LimitRule, LimitRules},
+        rates::{RateTable, RoundingRule},
+    },
+    webhooks::Webhook,
+};
+
 pub fn get_transactions_iter(filename: String) -> Reader<File> {
     let path = filename;
     let msg = format!("Couldn't read from {}", path);
+    get_transactions_reader(File::open(path).expect(&msg))
+}
+
+// Transactions from any source, like a socket or an in-memory buffer.
+pub fn get_transactions_reader<R: Read>(reader: R) -> Reader<R> {
     ReaderBuilder::new()
         .has_headers(false)
+        .flexible(true)
+        .trim(Trim::All)
+        .delimiter(b',')
+        .from_reader(reader)
+}
+
+// Configuration files are expected to have headers.
+pub fn get_config_iter(filename: String) -> Reader<File> {
+    let path = filename;
+    let msg = format!("Couldn't read from {}", path);
+    ReaderBuilder::new()
+        .has_headers(true)
+        .flexible(true)
         .trim(Trim::All)
         .delimiter(b',')
         .from_path(path)
         .expect(&msg)
-}
\ No newline at end of file
+}
+
+// Loads the rates (pair, rate, effective timestamp) and optionally the rounding rules (currency, scale, mode).
+pub fn load_rates(rates_filename: String, rounding_filename: Option<String>) -> RateTable {
+    let mut rates = RateTable::new();
+    let mut reader = get_config_iter(rates_filename.clone());
+    for (index, record) in reader.records().enumerate() {
+        let msg = format!("Invalid rate at record {} of {}", index + 1, rates_filename);
+        let record = record.expect(&msg);
+        rates.add_record(&record).expect(&msg);
+    }
+    if let Some(filename) = rounding_filename {
+        let mut reader = get_config_iter(filename.clone());
+        for (index, record) in reader.records().enumerate() {
+            let msg = format!(
+                "Invalid rounding rule at record {} of {}",
+                index + 1,
+                filename
+            );
+            let record = record.expect(&msg);
+            let (currency, rule) = RoundingRule::from_record(&record).expect(&msg);
+            rates.set_rounding(currency, rule);
+        }
+    }
+    rates
+}
+
+// Loads the fee rules (type, flat, percentage, min, max, currency).
+pub fn load_fee_schedule(filename: String) -> FeeSchedule {
+    let mut fee_schedule = FeeSchedule::new();
+    let mut reader = get_config_iter(filename.clone());
+    for (index, record) in reader.records().enumerate() {
+        let msg = format!("Invalid fee rule at record {} of {}", index + 1, filename);
+        let record = record.expect(&msg);
+        let (kind, currency, rule) = FeeRule::from_record(&record).expect(&msg);
+        fee_schedule.set_rule_in(kind, &currency, rule);
+    }
+    fee_schedule
+}
+
+// Loads the credit limits of the client registry (client, credit_limit, currency).
+pub fn load_credit_limits(filename: String) -> Vec<CreditLimit> {
+    let mut reader = get_config_iter(filename.clone());
+    reader
+        .records()
+        .enumerate()
+        .map(|(index, record)| {
+            let msg = format!("Invalid client at record {} of {}", index + 1, filename);
+            let record = record.expect(&msg);
+            CreditLimit::from_record(&record).expect(&msg)
+        })
+        .collect()
+}
+
+// Loads the limit rules (rule, limit, window).
+pub fn load_limits(filename: String) -> LimitRules {
+    let mut limits = LimitRules::new();
+    let mut reader = get_config_iter(filename.clone());
+    for (index, record) in reader.records().enumerate() {
+        let msg = format!("Invalid limit rule at record {} of {}", index + 1, filename);
+        let record = record.expect(&msg);
+        limits.add_rule(LimitRule::from_record(&record).expect(&msg));
+    }
+    limits
+}
+
+// Loads the webhooks to post account events to (url, secret).
+pub fn load_webhooks(filename: String) -> Vec<Webhook> {
+    let mut reader = get_config_iter(filename.clone());
+    reader
+        .records()
+        .enumerate()
+        .map(|(index, record)| {
+            let msg = format!("Invalid webhook at record {} of {}", index + 1, filename);
+            let record = record.expect(&msg);
+            Webhook::from_record(&record).expect(&msg)
+        })
+        .collect()
+}
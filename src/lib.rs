@@ -1,6 +1,12 @@
 pub mod app;
+pub mod builder;
 pub mod cli;
+pub mod clock;
 pub mod models;
+pub mod processor;
+pub mod relay;
+pub mod server;
+pub mod webhooks;
 pub mod csv;
 
 #[cfg(test)]
@@ -1,32 +1,208 @@
 extern crate lazy_static;
 extern crate mut_static;
 
-use integrator::{app::App, cli::get_input_filename, csv::get_transactions_iter};
+use std::{fs::File, io, sync::Arc};
+
+use integrator::{
+    builder::AppBuilder,
+    cli::{
+        get_clients_filename, get_db_filename, get_dispute_aging_action, get_dispute_aging_seconds,
+        get_dispute_aging_transactions, get_dispute_window_seconds,
+        get_dispute_window_transactions, get_fees_filename, get_grpc_address,
+        get_hold_expiry_seconds, get_hold_expiry_transactions, get_input_filename,
+        get_limits_filename, get_memory_budget, get_outbox, get_outbox_relay, get_output_filenames,
+        get_query, get_rates_filename, get_rounding_filename, get_serve, get_simulated_clock,
+        get_spill_filename, get_tcp_address, get_webhook_log_filename, get_webhooks_filename,
+    },
+    clock::{Clock, SimulatedClock, SystemClock},
+    csv::{load_credit_limits, load_fee_schedule, load_limits, load_rates, load_webhooks},
+    models::{
+        authorizations::AuthorizationExpiry,
+        disputes::{AgedDisputeAction, DisputeAging, DisputeWindow},
+        output::{FanOut, FileSink, OutputSink, StdoutSink},
+        sqlite,
+        stores::{AccountStore, Accounts, TransactionStore},
+        transactions::Transactions,
+    },
+    processor::Processor,
+    relay::{Backoff, OutboxRelay},
+    server::{self, http::HttpServer, tcp::TcpServer},
+    webhooks::{DeliveryLog, WebhookDispatcher},
+};
 
 fn main() {
-    let mut app = App::new();
+    if let Some((endpoint, retries)) = get_outbox_relay() {
+        relay(&endpoint, retries);
+        return;
+    }
+    let builder = configure(AppBuilder::new().output(output_sink()));
+    if get_simulated_clock() {
+        run_with_clock(builder.clock(SimulatedClock::default()));
+    } else {
+        run_with_clock(builder);
+    }
+}
+
+// The rules and policies given in the command line.
+fn configure<O: OutputSink>(
+    mut builder: AppBuilder<SystemClock, Accounts, Transactions, O>,
+) -> AppBuilder<SystemClock, Accounts, Transactions, O> {
+    if let Some(rates_filename) = get_rates_filename() {
+        builder = builder.rates(load_rates(rates_filename, get_rounding_filename()));
+    }
+    if let Some(fees_filename) = get_fees_filename() {
+        builder = builder.fee_schedule(load_fee_schedule(fees_filename));
+    }
+    if let Some(clients_filename) = get_clients_filename() {
+        for credit_limit in load_credit_limits(clients_filename) {
+            builder = builder.credit_limit(credit_limit);
+        }
+    }
+    if let Some(limits_filename) = get_limits_filename() {
+        builder = builder.limits(load_limits(limits_filename));
+    }
+    if let Some(webhooks_filename) = get_webhooks_filename() {
+        let log_filename = get_webhook_log_filename().expect("The webhooks need a delivery log");
+        let log =
+            DeliveryLog::open(&log_filename).expect("Failed to open the webhook delivery log");
+        let dispatcher =
+            WebhookDispatcher::new(load_webhooks(webhooks_filename), Backoff::default(), log);
+        builder = builder.observer(Arc::new(dispatcher));
+    }
+    builder
+        .authorization_expiry(AuthorizationExpiry {
+            transactions: get_hold_expiry_transactions(),
+            seconds: get_hold_expiry_seconds(),
+        })
+        .dispute_window(DisputeWindow {
+            transactions: get_dispute_window_transactions(),
+            seconds: get_dispute_window_seconds(),
+        })
+        .dispute_aging(DisputeAging {
+            transactions: get_dispute_aging_transactions(),
+            seconds: get_dispute_aging_seconds(),
+            action: get_dispute_aging_action()
+                .and_then(|name| AgedDisputeAction::from_name(&name))
+                .unwrap_or_default(),
+        })
+}
+
+// Accounts and transactions are kept in memory unless a database is given.
+fn run_with_clock<C: Clock>(builder: AppBuilder<C, Accounts, Transactions, Box<dyn OutputSink>>) {
+    match get_db_filename() {
+        Some(filename) => {
+            let (accounts, transactions) =
+                sqlite::open(&filename).expect("Failed to open the database");
+            let builder = builder.stores(accounts, transactions);
+            if get_outbox() {
+                run(builder.outbox());
+            } else {
+                run(builder);
+            }
+        }
+        None => match get_memory_budget() {
+            Some(budget) => run(builder
+                .memory_budget(budget, get_spill_filename())
+                .expect("Failed to create the file to spill transactions to")),
+            None => run(builder),
+        },
+    }
+}
+
+// Rows go to stdout unless output files are given, `-` standing for stdout.
+fn output_sink() -> Box<dyn OutputSink> {
+    let filenames = get_output_filenames();
+    if filenames.is_empty() {
+        return Box::new(StdoutSink::new());
+    }
+    let mut fan_out = FanOut::new();
+    for filename in filenames {
+        if filename == "-" {
+            fan_out.add(Box::new(StdoutSink::new()));
+        } else {
+            let sink = FileSink::create(&filename).expect("Failed to create the output file");
+            fan_out.add(Box::new(sink));
+        }
+    }
+    Box::new(fan_out)
+}
+
+fn run<C: Clock, A: AccountStore, T: TransactionStore, O: OutputSink>(
+    builder: AppBuilder<C, A, T, O>,
+) {
+    if let Some((address, threads)) = get_serve() {
+        serve(builder, &address, threads);
+        return;
+    }
     let input_filename = get_input_filename();
-    let mut reader = get_transactions_iter(input_filename);
-    for record in reader.records() {
-        match record {
-            Ok(r) => match app.process_record(r) {
-                Ok(tx) => {
-                    let client = tx.client_id;
-                    let account = app
-                        .get_account(client)
-                        .expect("ClientID always returns an account");
-                    let available = format!("{:.4}", account.available_balance());
-                    let held = format!("{:.4}", account.held_balance());
-                    let total = format!("{:.4}", account.total_balance());
-                    let locked = account.is_locked();
-                    let message = format!("{},{},{},{},{}", client, available, held, total, locked);
-                    app.output_write(message);
-                }
-                _ => {
-                    // Silently ignore rejected transactions
-                }
-            },
-            Err(_) => {}
-        };
+    let msg = format!("Couldn't read from {}", input_filename);
+    let input = File::open(&input_filename).expect(&msg);
+    let mut processor = Processor::new(builder.build().expect("Failed to read the stores"));
+    let written = match get_query() {
+        Some((client_id, timestamp)) => {
+            processor.replay(input);
+            let app = processor.app_mut();
+            app.output_balance_at(client_id, timestamp)
+                .and_then(|_| app.flush_output())
+        }
+        None => match processor.run(input).output_failures {
+            0 => Ok(()),
+            failures => Err(io::Error::other(format!("{} writes failed", failures))),
+        },
+    };
+    if let Err(error) = written {
+        eprintln!("Couldn't write the output: {}", error);
+        std::process::exit(1);
+    }
+}
+
+// The app keeps serving on this thread, the HTTP server takes requests on threads of its own.
+fn serve<C: Clock, A: AccountStore, T: TransactionStore, O: OutputSink>(
+    builder: AppBuilder<C, A, T, O>,
+    address: &str,
+    threads: usize,
+) {
+    let mut processor = Processor::new(builder.build().expect("Failed to read the stores"));
+    let (handle, commands) = server::channel();
+    let http = HttpServer::start(address, handle.clone(), threads)
+        .expect("Failed to listen on the address");
+    eprintln!("Listening on http://{}", http.address());
+    // The gRPC server stops once dropped, it lives as long as the app is served
+    #[cfg(feature = "grpc")]
+    let _grpc = get_grpc_address().map(|address| {
+        let grpc = server::grpc::GrpcServer::start(&address, handle.clone())
+            .expect("Failed to listen on the gRPC address");
+        eprintln!("Serving gRPC on {}", grpc.address());
+        grpc
+    });
+    #[cfg(not(feature = "grpc"))]
+    if get_grpc_address().is_some() {
+        eprintln!("Built without gRPC, build with `--features grpc` to serve it");
+        std::process::exit(1);
+    }
+    let _tcp = get_tcp_address().map(|address| {
+        let tcp = TcpServer::start(&address, handle).expect("Failed to listen on the TCP address");
+        eprintln!("Taking rows over TCP on {}", tcp.address());
+        tcp
+    });
+    server::serve(&mut processor, commands);
+}
+
+fn relay(endpoint: &str, retries: u32) {
+    let filename = get_db_filename().expect("The outbox relay needs the database given with --db");
+    let outbox = sqlite::Outbox::open(&filename).expect("Failed to open the database");
+    let summary = match OutboxRelay::new(outbox, endpoint).retries(retries).run() {
+        Ok(value) => value,
+        Err(error) => {
+            eprintln!("Couldn't use the outbox: {}", error);
+            std::process::exit(1);
+        }
+    };
+    eprintln!(
+        "Delivered {} events, {} pending",
+        summary.delivered, summary.pending
+    );
+    if summary.pending > 0 {
+        std::process::exit(1);
     }
 }
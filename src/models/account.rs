@@ -1,14 +1,20 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, io};
 
 use fraction::Decimal;
 
 use super::{
-    transaction::{Amount, ClientID, Transaction},
-    transactions::Transactions,
+    authorizations::Authorization,
+    fees::Fee,
+    rates::RateTable,
+    stores::TransactionStore,
+    transaction::{
+        Amount, ClientID, Currency, Timestamp, Transaction, TransactionType, DEFAULT_CURRENCY,
+    },
 };
 
 pub type Result<T> = std::result::Result<T, RejectedTransaction>;
 pub type Disputes = HashMap<ClientID, Transaction>;
+pub type Balances = HashMap<Currency, Balance>;
 
 #[derive(Debug, Clone)]
 pub enum RejectedTransaction {
@@ -19,25 +25,219 @@ pub enum RejectedTransaction {
     InvalidInput,
     TargetTransactionAmountMissing,
     AccountLocked,
+    RateNotFound,
+    CreditLimitExceeded,
+    LimitExceeded { rule: String },
+    ClientMismatch,
+    RefundExceedsWithdrawal,
+    DisputeWindowExpired,
+    // A store failed to read or write what the transaction needed, nothing it did was kept.
+    StorageFailed,
 }
 
-#[derive(Debug, Clone)]
-pub struct Account {
-    pub client_id: ClientID,
+impl RejectedTransaction {
+    // The name of the reason, without the details some reasons bring.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::InvalidType => "InvalidType",
+            Self::InsufficientFunds => "InsufficientFunds",
+            Self::IDNotFound => "IDNotFound",
+            Self::InconsistentWithValueHeld => "InconsistentWithValueHeld",
+            Self::InvalidInput => "InvalidInput",
+            Self::TargetTransactionAmountMissing => "TargetTransactionAmountMissing",
+            Self::AccountLocked => "AccountLocked",
+            Self::RateNotFound => "RateNotFound",
+            Self::CreditLimitExceeded => "CreditLimitExceeded",
+            Self::LimitExceeded { .. } => "LimitExceeded",
+            Self::ClientMismatch => "ClientMismatch",
+            Self::RefundExceedsWithdrawal => "RefundExceedsWithdrawal",
+            Self::DisputeWindowExpired => "DisputeWindowExpired",
+            Self::StorageFailed => "StorageFailed",
+        }
+    }
+}
+
+impl From<io::Error> for RejectedTransaction {
+    fn from(_: io::Error) -> Self {
+        Self::StorageFailed
+    }
+}
+
+// The available, held and total funds of an account in a single currency.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Balance {
     available: Amount,
     held: Amount,
     total: Amount,
+}
+
+impl Balance {
+    pub fn new() -> Self {
+        Self {
+            available: Decimal::from(0),
+            held: Decimal::from(0),
+            total: Decimal::from(0),
+        }
+    }
+
+    // A balance as it was kept somewhere else, like in a database.
+    pub fn from_funds(available: Amount, held: Amount, total: Amount) -> Self {
+        Self {
+            available,
+            held,
+            total,
+        }
+    }
+
+    pub fn available(&self) -> Amount {
+        self.available
+    }
+
+    pub fn held(&self) -> Amount {
+        self.held
+    }
+
+    pub fn total(&self) -> Amount {
+        self.total
+    }
+}
+
+impl Default for Balance {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The state of an account right after processing a timestamped transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub timestamp: Timestamp,
+    pub balances: Balances,
+    pub locked: bool,
+}
+
+impl Snapshot {
+    pub fn balance(&self, currency: &str) -> Balance {
+        self.balances.get(currency).cloned().unwrap_or_default()
+    }
+}
+
+// The current balances of an account, without its history, as other systems get them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceView {
+    pub client_id: ClientID,
+    pub balances: Balances,
+    pub locked: bool,
+}
+
+impl BalanceView {
+    pub fn balance(&self, currency: &str) -> Balance {
+        self.balances.get(currency).cloned().unwrap_or_default()
+    }
+
+    // Sorted for a stable output.
+    pub fn currencies(&self) -> Vec<Currency> {
+        let mut currencies: Vec<Currency> = self.balances.keys().cloned().collect();
+        currencies.sort();
+        currencies
+    }
+}
+
+// What recording a change replaced in the history, none when there was no entry at that time.
+#[derive(Debug, Clone)]
+enum Replaced {
+    Balance(Currency, Timestamp, Option<Balance>),
+    Lock(Timestamp, Option<bool>),
+}
+
+// What timestamped transactions changed in an account, each list sorted by timestamp.
+// Only the balances that changed and the lock get recorded, not the whole account.
+#[derive(Debug, Clone, Default)]
+struct History {
+    balances: HashMap<Currency, Vec<(Timestamp, Balance)>>,
+    locks: Vec<(Timestamp, bool)>,
+    // What got replaced since the last checkpoint of the account, latest last.
+    replaced: Vec<Replaced>,
+}
+
+impl History {
+    // A later change at the same timestamp replaces the earlier one, which is answered.
+    fn record<V>(entries: &mut Vec<(Timestamp, V)>, timestamp: Timestamp, value: V) -> Option<V> {
+        let index = entries.partition_point(|(at, _)| *at <= timestamp);
+        match index.checked_sub(1) {
+            Some(last) if entries[last].0 == timestamp => {
+                Some(std::mem::replace(&mut entries[last].1, value))
+            }
+            _ => {
+                entries.insert(index, (timestamp, value));
+                None
+            }
+        }
+    }
+
+    // Puts back what recording at the given time replaced.
+    fn unrecord<V>(entries: &mut Vec<(Timestamp, V)>, timestamp: Timestamp, previous: Option<V>) {
+        let index = entries.partition_point(|(at, _)| *at < timestamp);
+        if entries.get(index).map(|(at, _)| *at) != Some(timestamp) {
+            return;
+        }
+        match previous {
+            Some(value) => entries[index].1 = value,
+            None => {
+                entries.remove(index);
+            }
+        }
+    }
+
+    // The latest entry at or before the given time.
+    fn at<V>(entries: &[(Timestamp, V)], timestamp: Timestamp) -> Option<&(Timestamp, V)> {
+        let index = entries.partition_point(|(at, _)| *at <= timestamp);
+        index.checked_sub(1).map(|last| &entries[last])
+    }
+}
+
+// The state of an account before a transaction changed it, to roll it back.
+// The history isn't copied, what the transaction recorded in it gets taken back instead.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    balances: Balances,
+    credit_limits: HashMap<Currency, Amount>,
+    locked: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub client_id: ClientID,
+    balances: Balances,
+    credit_limits: HashMap<Currency, Amount>,
     locked: bool,
+    history: History,
 }
 
 impl Account {
     pub fn new(id: ClientID) -> Self {
         Self {
             client_id: id,
-            available: Decimal::from(0),
-            held: Decimal::from(0),
-            total: Decimal::from(0),
+            balances: Default::default(),
+            credit_limits: Default::default(),
             locked: false,
+            history: Default::default(),
+        }
+    }
+
+    // An account as it was kept somewhere else, like in a database.
+    pub fn restore(
+        id: ClientID,
+        balances: Balances,
+        credit_limits: HashMap<Currency, Amount>,
+        locked: bool,
+    ) -> Self {
+        Self {
+            client_id: id,
+            balances,
+            credit_limits,
+            locked,
+            history: Default::default(),
         }
     }
 
@@ -46,66 +246,198 @@ impl Account {
         if self.locked {
             return Err(RejectedTransaction::AccountLocked);
         };
-        let amount;
-        match transaction.amount {
+        let amount = match transaction.amount {
             None => return Err(RejectedTransaction::TargetTransactionAmountMissing),
-            Some(value) => amount = value,
+            Some(value) => value,
         };
-        self.available += amount;
-        self.total += amount;
+        let balance = self.balance_mut(&transaction.currency);
+        balance.available += amount;
+        balance.total += amount;
         Ok(transaction.clone())
     }
 
     // A withdraw is a debit to the client's asset account, meaning it should decrease the available and total funds of the client account.
     pub fn process_withdrawal(&mut self, transaction: &Transaction) -> Result<Transaction> {
+        self.process_withdrawal_with_fee(transaction, None)
+    }
+
+    // The available funds should cover both the withdrawn amount and its fee, the fee itself gets charged apart.
+    // Clients with a credit line can go below zero available funds down to their credit limit.
+    pub fn process_withdrawal_with_fee(
+        &mut self,
+        transaction: &Transaction,
+        fee: Option<Amount>,
+    ) -> Result<Transaction> {
+        if self.locked {
+            return Err(RejectedTransaction::AccountLocked);
+        };
+        let amount = match transaction.amount {
+            None => return Err(RejectedTransaction::TargetTransactionAmountMissing),
+            Some(value) => value,
+        };
+        let credit_limit = self.credit_limit_in(&transaction.currency);
+        let balance = self.balance_mut(&transaction.currency);
+        if balance.available + credit_limit > amount + fee.unwrap_or_default() {
+            balance.available -= amount;
+            balance.total -= amount;
+            Ok(transaction.clone())
+        } else if credit_limit > Decimal::from(0) {
+            Err(RejectedTransaction::CreditLimitExceeded)
+        } else {
+            Err(RejectedTransaction::InsufficientFunds)
+        }
+    }
+
+    pub fn set_credit_limit(&mut self, currency: &str, limit: Amount) {
+        self.credit_limits.insert(currency.to_string(), limit);
+    }
+
+    pub fn credit_limits(&self) -> &HashMap<Currency, Amount> {
+        &self.credit_limits
+    }
+
+    pub fn credit_limit_in(&self, currency: &str) -> Amount {
+        self.credit_limits
+            .get(currency)
+            .copied()
+            .unwrap_or_else(|| Decimal::from(0))
+    }
+
+    // An authorization holds funds for a later capture.
+    // This means that the clients available funds should decrease by the amount authorized,
+    // their held funds should increase by the amount authorized,
+    // while their total funds should remain the same.
+    pub fn process_authorize(&mut self, transaction: &Transaction) -> Result<Transaction> {
         if self.locked {
             return Err(RejectedTransaction::AccountLocked);
         };
-        let amount;
-        match transaction.amount {
+        let amount = match transaction.amount {
             None => return Err(RejectedTransaction::TargetTransactionAmountMissing),
-            Some(value) => amount = value,
+            Some(value) => value,
         };
-        if self.available > amount {
-            self.available -= amount;
-            self.total -= amount;
+        if amount <= Decimal::from(0) {
+            return Err(RejectedTransaction::InvalidInput);
+        }
+        let balance = self.balance_mut(&transaction.currency);
+        if balance.available > amount {
+            balance.available -= amount;
+            balance.held += amount;
             Ok(transaction.clone())
         } else {
             Err(RejectedTransaction::InsufficientFunds)
         }
     }
 
+    // A capture takes authorized funds out of the account.
+    // This means that the clients held and total funds should decrease by the amount captured.
+    pub fn process_capture(
+        &mut self,
+        transaction: &Transaction,
+        authorization: &Authorization,
+    ) -> Result<Transaction> {
+        if self.locked {
+            return Err(RejectedTransaction::AccountLocked);
+        };
+        // Without an amount the whole remaining authorization gets captured
+        let amount = transaction
+            .amount
+            .unwrap_or_else(|| authorization.remaining());
+        if amount <= Decimal::from(0) || amount > authorization.remaining() {
+            return Err(RejectedTransaction::InconsistentWithValueHeld);
+        }
+        let balance = self.balance_mut(&authorization.currency);
+        if amount > balance.held {
+            return Err(RejectedTransaction::InconsistentWithValueHeld);
+        }
+        balance.held -= amount;
+        balance.total -= amount;
+        Ok(Transaction {
+            amount: Some(amount),
+            ..transaction.in_currency(&authorization.currency)
+        })
+    }
+
+    // Voiding, or letting an authorization expire, gives its remaining held funds back to available.
+    pub fn release_hold(&mut self, authorization: &Authorization) -> Result<()> {
+        let amount = authorization.remaining();
+        let balance = self.balance_mut(&authorization.currency);
+        if amount > balance.held {
+            return Err(RejectedTransaction::InconsistentWithValueHeld);
+        }
+        balance.held -= amount;
+        balance.available += amount;
+        Ok(())
+    }
+
+    // A refund returns money for a withdrawal, meaning it should increase the available and total funds of the client account.
+    // The tx id of a refund is the one of the original withdrawal, partial refunds can't add up to more than was withdrawn.
+    pub fn process_refund<T: TransactionStore>(
+        &mut self,
+        transaction: &Transaction,
+        transactions: &T,
+    ) -> Result<Transaction> {
+        if self.locked {
+            return Err(RejectedTransaction::AccountLocked);
+        };
+        let amount = match transaction.amount {
+            None => return Err(RejectedTransaction::TargetTransactionAmountMissing),
+            Some(value) => value,
+        };
+        let withdrawal = match transactions.get(transaction.id)? {
+            Some(tx) if tx.kind == TransactionType::Withdrawal => tx,
+            _ => return Err(RejectedTransaction::IDNotFound),
+        };
+        if withdrawal.client_id != transaction.client_id {
+            return Err(RejectedTransaction::ClientMismatch);
+        }
+        let withdrawn = match withdrawal.amount {
+            None => return Err(RejectedTransaction::TargetTransactionAmountMissing),
+            Some(value) => value,
+        };
+        if amount <= Decimal::from(0) {
+            return Err(RejectedTransaction::InvalidInput);
+        }
+        if transactions.refunded(transaction.id)? + amount > withdrawn {
+            return Err(RejectedTransaction::RefundExceedsWithdrawal);
+        }
+        let balance = self.balance_mut(&withdrawal.currency);
+        balance.available += amount;
+        balance.total += amount;
+        Ok(transaction.in_currency(&withdrawal.currency))
+    }
+
     // A dispute represents a client's claim that a transaction was erroneous and should be reversed.
     // The transaction shouldn't be reversed yet but the associated funds should be held.
     // This means that the clients available funds should decrease by the amount disputed,
     // their held funds should increase by the amount disputed,
     // while their total funds should remain the same.
-    pub fn process_dispute(
+    // The funds are held in the currency of the disputed transaction.
+    pub fn process_dispute<T: TransactionStore>(
         &mut self,
         transaction: &Transaction,
-        transactions: &Transactions,
+        transactions: &T,
     ) -> Result<Transaction> {
         if self.locked {
             return Err(RejectedTransaction::AccountLocked);
         };
-        let disputed_tx = transactions.get(transaction.id);
+        let disputed_tx = transactions.get(transaction.id)?;
         match disputed_tx {
             None => {
                 // Ignoring invalid disputed transaction ID
                 Err(RejectedTransaction::IDNotFound)
             }
             Some(tx) => {
-                let amount;
-                match tx.amount {
+                let amount = match tx.amount {
                     None => return Err(RejectedTransaction::TargetTransactionAmountMissing),
-                    Some(value) => amount = value,
-                }
+                    Some(value) => value,
+                };
                 // Ok, but what the process should do with a dispute that is greater than the available balance?
                 // Until other clarification, I'm coding it to reject that claim.
-                if self.available > amount {
-                    self.held += amount;
-                    self.available -= amount;
-                    Ok(transaction.clone())
+                let balance = self.balance_mut(&tx.currency);
+                if balance.available > amount {
+                    balance.held += amount;
+                    balance.available -= amount;
+                    Ok(transaction.in_currency(&tx.currency))
                 } else {
                     Err(RejectedTransaction::InsufficientFunds)
                 }
@@ -118,37 +450,37 @@ impl Account {
     // This means that the clients held funds should decrease by the amount no longer disputed,
     // their available funds should increase by the amount no longer disputed,
     // and their total funds should remain the same.
-    pub fn process_resolve(
+    pub fn process_resolve<T: TransactionStore>(
         &mut self,
         transaction: &Transaction,
-        transactions: &Transactions,
+        transactions: &T,
     ) -> Result<Transaction> {
         if self.locked {
             return Err(RejectedTransaction::AccountLocked);
         };
-        let resolved_tx = transactions.get(transaction.id);
+        let resolved_tx = transactions.get(transaction.id)?;
         match resolved_tx {
             None => {
                 // Ignoring invalid resolved transaction ID
                 Err(RejectedTransaction::IDNotFound)
             }
             Some(tx) => {
-                let amount;
-                match tx.amount {
+                let amount = match tx.amount {
                     None => return Err(RejectedTransaction::TargetTransactionAmountMissing),
-                    Some(value) => amount = value,
-                }
+                    Some(value) => value,
+                };
                 // Ok, but what the process should do with a resolve that has a greater amount value than the held balance?
                 // Until other clarification, I'm coding it to reject that resolution.
-                if amount > self.held {
+                let balance = self.balance_mut(&tx.currency);
+                if amount > balance.held {
                     // This means there is a transaction value inconsistency?
                     // Some kind of warning should be triggered for someone to supervise?
                     // Rejecting this resolve transaction to evade potential mistakes on account balances.
                     Err(RejectedTransaction::InconsistentWithValueHeld)
                 } else {
-                    self.held -= amount;
-                    self.available += amount;
-                    Ok(transaction.clone())
+                    balance.held -= amount;
+                    balance.available += amount;
+                    Ok(transaction.in_currency(&tx.currency))
                 }
             }
         }
@@ -158,53 +490,243 @@ impl Account {
     // Funds that were held have now been withdrawn.
     // This means that the clients held funds and total funds should decrease by the amount previously disputed.
     // If a chargeback occurs the client's account should be immediately frozen.
-    pub fn process_chargeback(
+    pub fn process_chargeback<T: TransactionStore>(
         &mut self,
         transaction: &Transaction,
-        transactions: &Transactions,
+        transactions: &T,
     ) -> Result<Transaction> {
         if self.locked {
             return Err(RejectedTransaction::AccountLocked);
         };
-        let disputed_tx = transactions.get(transaction.id);
+        let disputed_tx = transactions.get(transaction.id)?;
         match disputed_tx {
             None => {
                 // Ignoring invalid chargeback transaction ID
                 Err(RejectedTransaction::IDNotFound)
             }
             Some(tx) => {
-                let amount;
-                match tx.amount {
+                let amount = match tx.amount {
                     None => return Err(RejectedTransaction::TargetTransactionAmountMissing),
-                    Some(value) => amount = value,
-                }
+                    Some(value) => value,
+                };
 
                 // What the integrator should do when there are insufficient funds for a chargeback?
-                if amount > self.held {
+                let balance = self.balance_mut(&tx.currency);
+                if amount > balance.held {
                     return Err(RejectedTransaction::InsufficientFunds);
                 } else {
-                    self.held -= amount;
-                    self.total -= amount;
+                    balance.held -= amount;
+                    balance.total -= amount;
+                    // Locking applies to the whole account, not only to the charged back currency.
                     self.locked = true;
                 }
-                Ok(transaction.clone())
+                Ok(transaction.in_currency(&tx.currency))
             }
         }
     }
 
+    // A conversion debits the available and total funds in one currency and credits
+    // the counter currency with the amount at the applied rate, rounded as per the counter currency rule.
+    // The rate is the one effective at the timestamp of the transaction, or now without one.
+    pub fn process_convert(
+        &mut self,
+        transaction: &Transaction,
+        rates: &RateTable,
+        now: Timestamp,
+    ) -> Result<Transaction> {
+        if self.locked {
+            return Err(RejectedTransaction::AccountLocked);
+        };
+        let amount = match transaction.amount {
+            None => return Err(RejectedTransaction::TargetTransactionAmountMissing),
+            Some(value) => value,
+        };
+        if amount <= Decimal::from(0) {
+            return Err(RejectedTransaction::InvalidInput);
+        }
+        let counter_currency = match &transaction.counter_currency {
+            None => return Err(RejectedTransaction::InvalidInput),
+            Some(value) => value,
+        };
+        let rate = rates
+            .rate(
+                &transaction.currency,
+                counter_currency,
+                transaction.timestamp.unwrap_or(now),
+            )
+            .ok_or(RejectedTransaction::RateNotFound)?;
+        let credited = rates.round(counter_currency, amount * rate);
+        let debited = self.balance_mut(&transaction.currency);
+        if debited.available > amount {
+            debited.available -= amount;
+            debited.total -= amount;
+        } else {
+            return Err(RejectedTransaction::InsufficientFunds);
+        }
+        let balance = self.balance_mut(counter_currency);
+        balance.available += credited;
+        balance.total += credited;
+        Ok(Transaction {
+            rate: Some(rate),
+            ..transaction.clone()
+        })
+    }
+
+    // A fee is a debit of the available and total funds of the client account.
+    // Fees are charged even on locked accounts since they come from already accepted transactions,
+    // as long as the available funds, and the credit line, cover them.
+    pub fn charge_fee(&mut self, fee: &Fee) -> Result<()> {
+        let credit_limit = self.credit_limit_in(&fee.currency);
+        let balance = self.balance_mut(&fee.currency);
+        if balance.available + credit_limit < fee.amount {
+            return Err(RejectedTransaction::InsufficientFunds);
+        }
+        balance.available -= fee.amount;
+        balance.total -= fee.amount;
+        Ok(())
+    }
+
+    fn balance_mut(&mut self, currency: &str) -> &mut Balance {
+        self.balances.entry(currency.to_string()).or_default()
+    }
+
+    pub fn balance(&self, currency: &str) -> Balance {
+        self.balances.get(currency).cloned().unwrap_or_default()
+    }
+
+    pub fn view(&self) -> BalanceView {
+        BalanceView {
+            client_id: self.client_id,
+            balances: self.balances.clone(),
+            locked: self.locked,
+        }
+    }
+
+    // The currencies this account has ever held funds in, sorted for a stable output.
+    pub fn currencies(&self) -> Vec<Currency> {
+        let mut currencies: Vec<Currency> = self.balances.keys().cloned().collect();
+        currencies.sort();
+        currencies
+    }
+
     pub fn available_balance(&self) -> Amount {
-        self.available
+        self.available_balance_in(DEFAULT_CURRENCY)
     }
 
     pub fn held_balance(&self) -> Amount {
-        self.held
+        self.held_balance_in(DEFAULT_CURRENCY)
     }
 
     pub fn total_balance(&self) -> Amount {
-        self.total
+        self.total_balance_in(DEFAULT_CURRENCY)
+    }
+
+    pub fn available_balance_in(&self, currency: &str) -> Amount {
+        self.balance(currency).available()
+    }
+
+    pub fn held_balance_in(&self, currency: &str) -> Amount {
+        self.balance(currency).held()
+    }
+
+    pub fn total_balance_in(&self, currency: &str) -> Amount {
+        self.balance(currency).total()
     }
 
     pub fn is_locked(&self) -> bool {
         self.locked
     }
+
+    // Records the balances, and the lock, that changed since the state at the given time.
+    pub fn record_snapshot(&mut self, timestamp: Timestamp) {
+        for (currency, balance) in self.balances.iter() {
+            let entries = self.history.balances.entry(currency.clone()).or_default();
+            let changed = History::at(entries, timestamp).map(|(_, recorded)| recorded);
+            if changed != Some(balance) {
+                let previous = History::record(entries, timestamp, balance.clone());
+                self.history.replaced.push(Replaced::Balance(
+                    currency.clone(),
+                    timestamp,
+                    previous,
+                ));
+            }
+        }
+        let locked = History::at(&self.history.locks, timestamp).is_some_and(|(_, locked)| *locked);
+        if locked != self.locked {
+            let previous = History::record(&mut self.history.locks, timestamp, self.locked);
+            self.history
+                .replaced
+                .push(Replaced::Lock(timestamp, previous));
+        }
+    }
+
+    // The state to roll back to, from now on the history keeps what gets recorded to take it back.
+    pub fn checkpoint(&mut self) -> Checkpoint {
+        self.history.replaced.clear();
+        Checkpoint {
+            balances: self.balances.clone(),
+            credit_limits: self.credit_limits.clone(),
+            locked: self.locked,
+        }
+    }
+
+    // Undoes what changed since the checkpoint was taken.
+    pub fn roll_back(&mut self, checkpoint: Checkpoint) {
+        self.balances = checkpoint.balances;
+        self.credit_limits = checkpoint.credit_limits;
+        self.locked = checkpoint.locked;
+        while let Some(replaced) = self.history.replaced.pop() {
+            match replaced {
+                Replaced::Balance(currency, timestamp, previous) => {
+                    if let Some(entries) = self.history.balances.get_mut(&currency) {
+                        History::unrecord(entries, timestamp, previous);
+                    }
+                }
+                Replaced::Lock(timestamp, previous) => {
+                    History::unrecord(&mut self.history.locks, timestamp, previous)
+                }
+            }
+        }
+    }
+
+    // Answers the latest state recorded at or before the given time.
+    pub fn snapshot_at(&self, timestamp: Timestamp) -> Option<Snapshot> {
+        let mut latest = None;
+        let mut balances = Balances::new();
+        for (currency, entries) in self.history.balances.iter() {
+            if let Some((at, balance)) = History::at(entries, timestamp) {
+                balances.insert(currency.clone(), balance.clone());
+                latest = latest.max(Some(*at));
+            }
+        }
+        let locked = match History::at(&self.history.locks, timestamp) {
+            None => false,
+            Some((at, locked)) => {
+                latest = latest.max(Some(*at));
+                *locked
+            }
+        };
+        Some(Snapshot {
+            timestamp: latest?,
+            balances,
+            locked,
+        })
+    }
+
+    // The balances recorded so far, for stores keeping the history somewhere else.
+    pub fn recorded_balances(&self) -> impl Iterator<Item = (&Currency, Timestamp, &Balance)> {
+        self.history
+            .balances
+            .iter()
+            .flat_map(|(currency, entries)| {
+                entries
+                    .iter()
+                    .map(move |(timestamp, balance)| (currency, *timestamp, balance))
+            })
+    }
+
+    // The locks recorded so far, sorted by timestamp.
+    pub fn recorded_locks(&self) -> &[(Timestamp, bool)] {
+        &self.history.locks
+    }
 }
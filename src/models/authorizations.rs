@@ -0,0 +1,155 @@
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+};
+
+use super::{
+    bookkeeping::Bookkeeping,
+    transaction::{Amount, Arrival, ClientID, Currency, Timestamp, Transaction, TransactionID},
+};
+
+// Funds moved from available to held by an authorize transaction, waiting to be captured or voided.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Authorization {
+    pub client_id: ClientID,
+    pub id: TransactionID,
+    pub currency: Currency,
+    pub amount: Amount,
+    pub captured: Amount,
+    pub authorized: Arrival,
+}
+
+impl Authorization {
+    // The held amount that can still be captured or released.
+    pub fn remaining(&self) -> Amount {
+        self.amount - self.captured
+    }
+}
+
+// When open authorizations become stale and get their remaining funds released.
+// Either limit, or both, can be set. Without any, authorizations never expire.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AuthorizationExpiry {
+    pub transactions: Option<u64>,
+    pub seconds: Option<Timestamp>,
+}
+
+impl AuthorizationExpiry {
+    pub fn is_expired(&self, authorization: &Authorization, sequence: u64, now: Timestamp) -> bool {
+        authorization
+            .authorized
+            .is_older_than(self.transactions, self.seconds, sequence, now)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Authorizations {
+    store: HashMap<TransactionID, Authorization>,
+    expiry: AuthorizationExpiry,
+    // The authorizations opened, captured or closed since persistent stores were last told.
+    changed: HashSet<TransactionID>,
+}
+
+impl Authorizations {
+    pub fn new() -> Self {
+        Self {
+            store: Default::default(),
+            expiry: Default::default(),
+            changed: Default::default(),
+        }
+    }
+
+    pub fn set_expiry(&mut self, expiry: AuthorizationExpiry) {
+        self.expiry = expiry;
+    }
+
+    pub fn open(&mut self, transaction: &Transaction, sequence: u64, now: Timestamp) {
+        let authorization = Authorization {
+            client_id: transaction.client_id,
+            id: transaction.id,
+            currency: transaction.currency.clone(),
+            amount: transaction.amount.unwrap_or_default(),
+            captured: Default::default(),
+            authorized: Arrival {
+                sequence,
+                timestamp: now,
+            },
+        };
+        self.store.insert(transaction.id, authorization);
+        self.changed.insert(transaction.id);
+    }
+
+    pub fn get(&self, txid: TransactionID) -> Option<&Authorization> {
+        self.store.get(&txid)
+    }
+
+    // Whoever asks for a mutable authorization might change it.
+    pub fn get_mut(&mut self, txid: TransactionID) -> Option<&mut Authorization> {
+        self.changed.insert(txid);
+        self.store.get_mut(&txid)
+    }
+
+    pub fn close(&mut self, txid: TransactionID) -> Option<Authorization> {
+        self.changed.insert(txid);
+        self.store.remove(&txid)
+    }
+
+    pub fn contains(&self, txid: TransactionID) -> bool {
+        self.store.contains_key(&txid)
+    }
+
+    // Removes and answers the authorizations that went stale.
+    pub fn take_expired(&mut self, sequence: u64, now: Timestamp) -> Vec<Authorization> {
+        if self.expiry == AuthorizationExpiry::default() {
+            return vec![];
+        }
+        let expired: Vec<TransactionID> = self
+            .store
+            .values()
+            .filter(|authorization| self.expiry.is_expired(authorization, sequence, now))
+            .map(|authorization| authorization.id)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|txid| self.close(txid))
+            .collect()
+    }
+
+    pub fn size(&self) -> usize {
+        self.store.len()
+    }
+
+    // Tells what changed since the last time, for persistent stores to keep it.
+    pub fn take_changes(&mut self, changes: &mut Bookkeeping) {
+        for txid in self.changed.drain() {
+            changes
+                .authorizations
+                .push((txid, self.store.get(&txid).cloned()));
+        }
+    }
+
+    // Picks up the authorizations kept by a persistent store, in place of the current ones.
+    pub fn restore(&mut self, bookkeeping: &Bookkeeping) {
+        self.store = bookkeeping
+            .authorizations
+            .iter()
+            .filter_map(|(txid, authorization)| {
+                authorization
+                    .clone()
+                    .map(|authorization| (*txid, authorization))
+            })
+            .collect();
+        self.changed.clear();
+    }
+
+    // An estimate of the memory taken by the open authorizations, in bytes.
+    pub fn footprint(&self) -> usize {
+        self.store.len() * (mem::size_of::<TransactionID>() + mem::size_of::<Authorization>())
+    }
+}
+
+impl Default for Authorizations {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,28 @@
+use super::{
+    authorizations::Authorization,
+    disputes::OpenDispute,
+    fees::Fee,
+    limits::Activity,
+    transaction::{Arrival, ClientID, TransactionID},
+};
+
+// What the app keeps besides the accounts and the stored transactions: open authorizations,
+// when transactions arrived and disputes opened, the fees and the recent activity of the clients. Persistent stores keep it along
+// with the rest, so an app opening them again picks up where it was left.
+// Given to a store it holds what processing a transaction changed, `None` standing for an entry
+// gone. Answered by a store it holds every entry kept.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Bookkeeping {
+    // How many transactions were given to process.
+    pub sequence: u64,
+    // The ranges of ids evicted out of the dispute window, by their first id with their last one.
+    pub evicted: Vec<(TransactionID, Option<TransactionID>)>,
+    pub authorizations: Vec<(TransactionID, Option<Authorization>)>,
+    // Only tracked when there is a dispute window.
+    pub arrivals: Vec<(TransactionID, Option<Arrival>)>,
+    pub disputes: Vec<(TransactionID, Option<OpenDispute>)>,
+    // Given to a store, the entries posted since the last time.
+    pub fees: Vec<Fee>,
+    // What the limit rules look back at, only tracked when there are some.
+    pub activity: Vec<(ClientID, Option<Activity>)>,
+}
@@ -0,0 +1,32 @@
+use csv::StringRecord;
+use fraction::Decimal;
+
+use super::transaction::{parse_currency_code, Amount, ClientID, Currency, DEFAULT_CURRENCY};
+
+// An approved credit line of a client as listed in the client registry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreditLimit {
+    pub client_id: ClientID,
+    pub limit: Amount,
+    pub currency: Currency,
+}
+
+impl CreditLimit {
+    // client, credit_limit, currency (optional)
+    pub fn from_record(record: &StringRecord) -> Option<Self> {
+        let client_id = record.get(0)?.trim().parse::<ClientID>().ok()?;
+        let limit = record.get(1)?.trim().parse::<Amount>().ok()?;
+        if limit < Decimal::from(0) {
+            return None;
+        }
+        let currency = match record.get(2).map(|value| value.trim()) {
+            None | Some("") => DEFAULT_CURRENCY.to_string(),
+            Some(value) => parse_currency_code(value)?,
+        };
+        Some(Self {
+            client_id,
+            limit,
+            currency,
+        })
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    mem,
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use csv::StringRecord;
+
+use super::transaction::{Amount, Transaction, TransactionID};
+
+// Tells apart the temporary files of the storages created by the same process.
+static TEMPORARY_FILES: AtomicUsize = AtomicUsize::new(0);
+
+// An index entry holds the offset of a line plus one, zero meaning no transaction.
+const ENTRY_SIZE: u64 = mem::size_of::<u64>() as u64;
+
+#[derive(Debug)]
+struct SpillFile {
+    path: PathBuf,
+    file: File,
+    // Temporary files are removed along with the storage.
+    temporary: bool,
+}
+
+impl SpillFile {
+    // Temporary files are always new ones. A given file is only used if it is empty,
+    // it may hold something else than spilled transactions.
+    fn open(path: PathBuf, temporary: bool) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .create_new(temporary)
+            .open(&path)?;
+        if file.metadata()?.len() > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} isn't empty", path.display()),
+            ));
+        }
+        Ok(Self {
+            path,
+            file,
+            temporary,
+        })
+    }
+
+    // A temporary file with the same content.
+    fn copy(&self, path: PathBuf) -> io::Result<Self> {
+        let copy = Self::open(path, true)?;
+        let mut source = &self.file;
+        source.seek(SeekFrom::Start(0))?;
+        io::copy(&mut source, &mut &copy.file)?;
+        Ok(copy)
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+// Transactions spilled out of memory to an append-only file, found back through an index file.
+// Each line is a record: type, client, tx, amount, currency, timestamp, fee, refunded
+// The index has an entry of 8 bytes at the position of each id, with the offset of its latest line.
+// Ids never spilled are holes of a sparse file, taking no disk space on most file systems,
+// so nothing grows in memory with the number of spilled transactions.
+// Clones share the files until one of them changes, which then goes on with copies of its own.
+#[derive(Debug, Clone)]
+pub struct ColdStorage {
+    records: Arc<SpillFile>,
+    index: Arc<SpillFile>,
+    // How many transactions the index points to.
+    len: usize,
+}
+
+impl ColdStorage {
+    // Without a filename it spills to a temporary file. The index goes next to the records,
+    // with an `.index` extension added.
+    pub fn create(filename: Option<String>) -> io::Result<Self> {
+        let (records, index) = match filename {
+            Some(value) => {
+                let records = SpillFile::open(PathBuf::from(&value), false)?;
+                let index = SpillFile::open(PathBuf::from(format!("{}.index", value)), false)?;
+                (records, index)
+            }
+            None => {
+                let (records, index) = Self::temporary_paths();
+                (
+                    SpillFile::open(records, true)?,
+                    SpillFile::open(index, true)?,
+                )
+            }
+        };
+        Ok(Self {
+            records: Arc::new(records),
+            index: Arc::new(index),
+            len: 0,
+        })
+    }
+
+    fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.records) > 1 || Arc::strong_count(&self.index) > 1
+    }
+
+    // Copies the files before changing them while a clone still reads them.
+    fn unshare(&mut self) -> io::Result<()> {
+        if self.is_shared() {
+            let (records, index) = Self::temporary_paths();
+            let records = self.records.copy(records)?;
+            let index = self.index.copy(index)?;
+            self.records = Arc::new(records);
+            self.index = Arc::new(index);
+        }
+        Ok(())
+    }
+
+    // The records and index files of a new temporary storage.
+    fn temporary_paths() -> (PathBuf, PathBuf) {
+        let records = env::temp_dir().join(format!(
+            "integrator-{}-{}.spill",
+            process::id(),
+            TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut index = records.clone().into_os_string();
+        index.push(".index");
+        (records, PathBuf::from(index))
+    }
+
+    fn offset(&self, txid: TransactionID) -> io::Result<Option<u64>> {
+        let mut file = &self.index.file;
+        file.seek(SeekFrom::Start(txid as u64 * ENTRY_SIZE))?;
+        let mut entry = [0; ENTRY_SIZE as usize];
+        match file.read_exact(&mut entry) {
+            Ok(()) => Ok(u64::from_le_bytes(entry).checked_sub(1)),
+            // Past the largest id spilled
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn set_offset(&mut self, txid: TransactionID, offset: Option<u64>) -> io::Result<()> {
+        let entry = offset.map(|offset| offset + 1).unwrap_or(0);
+        let mut file = &self.index.file;
+        file.seek(SeekFrom::Start(txid as u64 * ENTRY_SIZE))?;
+        file.write_all(&entry.to_le_bytes())
+    }
+
+    // Writes the transaction along with the amount refunded so far, replacing any previous line.
+    pub fn write(&mut self, transaction: &Transaction, refunded: Amount) -> io::Result<()> {
+        let line = format!(
+            "{},{},{},{},{},{},{},{}\n",
+            transaction.kind.name(),
+            transaction.client_id,
+            transaction.id,
+            transaction
+                .amount
+                .map(|amount| amount.to_string())
+                .unwrap_or_default(),
+            transaction.currency,
+            transaction
+                .timestamp
+                .map(|timestamp| timestamp.to_string())
+                .unwrap_or_default(),
+            transaction
+                .fee
+                .map(|fee| fee.to_string())
+                .unwrap_or_default(),
+            refunded,
+        );
+        self.unshare()?;
+        let is_new = self.offset(transaction.id)?.is_none();
+        let mut file = &self.records.file;
+        let offset = file.seek(SeekFrom::End(0))?;
+        file.write_all(line.as_bytes())?;
+        self.set_offset(transaction.id, Some(offset))?;
+        if is_new {
+            self.len += 1;
+        }
+        Ok(())
+    }
+
+    // The transaction and the amount refunded so far.
+    pub fn read(&self, txid: TransactionID) -> io::Result<Option<(Transaction, Amount)>> {
+        let offset = match self.offset(txid)? {
+            None => return Ok(None),
+            Some(value) => value,
+        };
+        let mut file = &self.records.file;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut line = String::new();
+        BufReader::new(file).read_line(&mut line)?;
+        let fields: Vec<&str> = line.trim_end().split(',').collect();
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid spilled transaction");
+        if fields.len() != 8 {
+            return Err(invalid());
+        }
+        let mut transaction = Transaction::from_record(StringRecord::from(fields[..6].to_vec()))
+            .map_err(|_| invalid())?;
+        transaction.fee = match fields[6] {
+            "" => None,
+            value => Some(value.parse::<Amount>().map_err(|_| invalid())?),
+        };
+        let refunded = fields[7].parse::<Amount>().map_err(|_| invalid())?;
+        Ok(Some((transaction, refunded)))
+    }
+
+    // Appends the transaction again with the new refunded amount, answering whether it was spilled.
+    pub fn add_refund(&mut self, txid: TransactionID, amount: Amount) -> io::Result<bool> {
+        match self.read(txid)? {
+            None => Ok(false),
+            Some((transaction, refunded)) => {
+                self.write(&transaction, refunded + amount)?;
+                Ok(true)
+            }
+        }
+    }
+
+    // The line stays in the file, only the index forgets it.
+    pub fn remove(&mut self, txid: TransactionID) -> io::Result<bool> {
+        if self.offset(txid)?.is_none() {
+            return Ok(false);
+        }
+        self.unshare()?;
+        self.set_offset(txid, None)?;
+        self.len -= 1;
+        Ok(true)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) -> io::Result<()> {
+        // Nothing to copy, a clone keeps the files and this goes on with new ones
+        if self.is_shared() {
+            let (records, index) = Self::temporary_paths();
+            let records = SpillFile::open(records, true)?;
+            let index = SpillFile::open(index, true)?;
+            self.records = Arc::new(records);
+            self.index = Arc::new(index);
+            self.len = 0;
+            return Ok(());
+        }
+        self.records.file.set_len(0)?;
+        self.index.file.set_len(0)?;
+        self.len = 0;
+        Ok(())
+    }
+}
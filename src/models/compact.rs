@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+use fraction::{Decimal, ToPrimitive};
+
+use super::transaction::{Amount, ClientID, Currency, Transaction, TransactionID, TransactionType};
+
+// Compact amounts are whole numbers of ten-thousandths, the precision of the input.
+pub const AMOUNT_PRECISION: u8 = 4;
+const AMOUNT_SCALE: i64 = 10_000;
+
+const WITHDRAWAL: u8 = 0b01;
+
+// The currency codes of the compact records, each one stored once and referred to by its index.
+#[derive(Debug, Clone, Default)]
+pub struct CurrencyCodes {
+    codes: Vec<Currency>,
+    indexes: HashMap<Currency, u16>,
+}
+
+impl CurrencyCodes {
+    // None once there are more currencies than a compact record can refer to.
+    pub fn index(&mut self, code: &str) -> Option<u16> {
+        if let Some(index) = self.indexes.get(code) {
+            return Some(*index);
+        }
+        let index = u16::try_from(self.codes.len()).ok()?;
+        self.codes.push(code.to_string());
+        self.indexes.insert(code.to_string(), index);
+        Some(index)
+    }
+
+    pub fn code(&self, index: u16) -> &str {
+        &self.codes[index as usize]
+    }
+}
+
+// What needs to be kept of a stored deposit or withdrawal to dispute or refund it, in 16 bytes.
+// The id is the key it is stored under. Whether it is disputed is kept by `Disputes`, which
+// tracks it for every stored transaction, compact or not.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompactTransaction {
+    amount: i64,
+    client_id: ClientID,
+    currency: u16,
+    // Whether it is a withdrawal.
+    flags: u8,
+}
+
+impl CompactTransaction {
+    // None for transactions a compact record can't hold exactly: other than deposits and withdrawals,
+    // without an amount, or with an amount out of range or with more than four decimals.
+    pub fn new(transaction: &Transaction, currencies: &mut CurrencyCodes) -> Option<Self> {
+        let flags = match transaction.kind {
+            TransactionType::Deposit => 0,
+            TransactionType::Withdrawal => WITHDRAWAL,
+            _ => return None,
+        };
+        let amount = (transaction.amount? * Decimal::from(AMOUNT_SCALE))
+            .apply_ref(|scaled, _| scaled.to_i64())?;
+        Some(Self {
+            amount,
+            client_id: transaction.client_id,
+            currency: currencies.index(&transaction.currency)?,
+            flags,
+        })
+    }
+
+    pub fn kind(&self) -> TransactionType {
+        if self.flags & WITHDRAWAL != 0 {
+            TransactionType::Withdrawal
+        } else {
+            TransactionType::Deposit
+        }
+    }
+
+    pub fn amount(&self) -> Amount {
+        (Decimal::from(self.amount) / Decimal::from(AMOUNT_SCALE)).set_precision(AMOUNT_PRECISION)
+    }
+
+    // The transaction back, without the fee and timestamp it was processed with.
+    pub fn expand(&self, id: TransactionID, currencies: &CurrencyCodes) -> Transaction {
+        let mut transaction = Transaction::new_synthetic(self.kind(), self.client_id, id);
+        transaction.amount = Some(self.amount());
+        transaction.currency = currencies.code(self.currency).to_string();
+        transaction
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    mem,
+};
+
+use super::{
+    account::{RejectedTransaction, Result},
+    bookkeeping::Bookkeeping,
+    transaction::{Arrival, Timestamp, TransactionID, TransactionType},
+};
+
+// How long a stored transaction can be disputed.
+// Either limit, or both, can be set. Without any, transactions can be disputed forever.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DisputeWindow {
+    pub transactions: Option<u64>,
+    pub seconds: Option<Timestamp>,
+}
+
+impl DisputeWindow {
+    pub fn is_expired(&self, arrival: &Arrival, sequence: u64, now: Timestamp) -> bool {
+        arrival.is_older_than(self.transactions, self.seconds, sequence, now)
+    }
+}
+
+// What to do with a dispute nobody resolved nor charged back in time.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AgedDisputeAction {
+    #[default]
+    Resolve,
+    // Escalates it to a chargeback.
+    Chargeback,
+}
+
+impl AgedDisputeAction {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim() {
+            "resolve" => Some(Self::Resolve),
+            "chargeback" => Some(Self::Chargeback),
+            _ => None,
+        }
+    }
+
+    pub fn kind(&self) -> TransactionType {
+        match self {
+            Self::Resolve => TransactionType::Resolve,
+            Self::Chargeback => TransactionType::Chargeback,
+        }
+    }
+}
+
+// How long a dispute can stay open before it gets closed on behalf of the client.
+// Either limit, or both, can be set. Without any, disputes stay open until a resolve or chargeback arrives.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DisputeAging {
+    pub transactions: Option<u64>,
+    pub seconds: Option<Timestamp>,
+    pub action: AgedDisputeAction,
+}
+
+impl DisputeAging {
+    pub fn is_aged(&self, opened: &Arrival, sequence: u64, now: Timestamp) -> bool {
+        opened.is_older_than(self.transactions, self.seconds, sequence, now)
+    }
+}
+
+// An open dispute as persistent stores keep it: when it was opened and whether it still ages.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpenDispute {
+    pub opened: Arrival,
+    pub aging: bool,
+}
+
+// Keeps track of the open disputes and of how long ago the stored transactions arrived,
+// telling which ones can't be disputed anymore and which disputes went stale.
+#[derive(Debug, Clone)]
+pub struct Disputes {
+    window: DisputeWindow,
+    aging: DisputeAging,
+    // Only tracked when there is a dispute window.
+    arrivals: HashMap<TransactionID, Arrival>,
+    // The stored transactions, oldest first, to evict them as their window expires.
+    order: VecDeque<(TransactionID, u64)>,
+    // Transactions with an open dispute, and when it was opened.
+    // They are kept until the dispute is resolved or charged back.
+    open: HashMap<TransactionID, Arrival>,
+    // The open disputes still aging by when they were opened, which orders their deadlines too.
+    aging_queue: BTreeMap<u64, TransactionID>,
+    // The ids evicted so far, as ranges of consecutive ids from the first one to the last one.
+    // Ids are expected to grow with time, so they take few ranges however many get evicted.
+    evicted: BTreeMap<TransactionID, TransactionID>,
+    // The ids whose arrival or dispute changed since persistent stores were last told.
+    changed: HashSet<TransactionID>,
+    // The first ids of the evicted ranges changed since persistent stores were last told.
+    changed_evicted: HashSet<TransactionID>,
+}
+
+impl Disputes {
+    pub fn new() -> Self {
+        Self {
+            window: Default::default(),
+            aging: Default::default(),
+            arrivals: Default::default(),
+            order: Default::default(),
+            open: Default::default(),
+            aging_queue: Default::default(),
+            evicted: Default::default(),
+            changed: Default::default(),
+            changed_evicted: Default::default(),
+        }
+    }
+
+    pub fn set_window(&mut self, window: DisputeWindow) {
+        self.window = window;
+    }
+
+    pub fn set_aging(&mut self, aging: DisputeAging) {
+        self.aging = aging;
+    }
+
+    pub fn aging_action(&self) -> AgedDisputeAction {
+        self.aging.action
+    }
+
+    // Starts the dispute window of a stored transaction.
+    pub fn arrived(&mut self, txid: TransactionID, sequence: u64, now: Timestamp) {
+        if self.window == DisputeWindow::default() {
+            return;
+        }
+        let arrival = Arrival {
+            sequence,
+            timestamp: now,
+        };
+        self.arrivals.insert(txid, arrival);
+        self.order.push_back((txid, sequence));
+        self.changed.insert(txid);
+    }
+
+    // Rejects disputing a transaction stored longer ago than the dispute window.
+    pub fn check_window(&self, txid: TransactionID, sequence: u64, now: Timestamp) -> Result<()> {
+        let is_expired = match self.arrivals.get(&txid) {
+            Some(arrival) => self.window.is_expired(arrival, sequence, now),
+            None => self.is_evicted(txid),
+        };
+        if is_expired {
+            Err(RejectedTransaction::DisputeWindowExpired)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn open(&mut self, txid: TransactionID, sequence: u64, now: Timestamp) {
+        let opened = Arrival {
+            sequence,
+            timestamp: now,
+        };
+        if let Some(previous) = self.open.insert(txid, opened) {
+            self.aging_queue.remove(&previous.sequence);
+        }
+        self.aging_queue.insert(sequence, txid);
+        self.changed.insert(txid);
+    }
+
+    // A transaction that went out of its window meanwhile is forgotten too, to be evicted now.
+    pub fn close(&mut self, txid: TransactionID, sequence: u64, now: Timestamp) {
+        self.stop_aging(txid);
+        self.open.remove(&txid);
+        self.changed.insert(txid);
+        if self.check_window(txid, sequence, now).is_err() {
+            self.expire(txid);
+        }
+    }
+
+    // An estimate of the memory taken by the bookkeeping, in bytes.
+    pub fn footprint(&self) -> usize {
+        let entry = mem::size_of::<TransactionID>() + mem::size_of::<Arrival>();
+        (self.arrivals.len() + self.open.len()) * entry
+            + self.order.len() * mem::size_of::<(TransactionID, u64)>()
+            + self.aging_queue.len() * (mem::size_of::<u64>() + mem::size_of::<TransactionID>())
+            + self.evicted.len() * 2 * mem::size_of::<TransactionID>()
+    }
+
+    pub fn is_open(&self, txid: TransactionID) -> bool {
+        self.open.contains_key(&txid)
+    }
+
+    // The ids of the open disputes gone stale, oldest first.
+    pub fn aged(&self, sequence: u64, now: Timestamp) -> Vec<TransactionID> {
+        if self.aging == DisputeAging::default() {
+            return vec![];
+        }
+        self.aging_queue
+            .values()
+            .take_while(|txid| {
+                self.open
+                    .get(txid)
+                    .is_some_and(|opened| self.aging.is_aged(opened, sequence, now))
+            })
+            .copied()
+            .collect()
+    }
+
+    // Keeps the dispute open without closing it on behalf of the client anymore.
+    pub fn stop_aging(&mut self, txid: TransactionID) {
+        if let Some(opened) = self.open.get(&txid) {
+            self.aging_queue.remove(&opened.sequence);
+            self.changed.insert(txid);
+        }
+    }
+
+    // Answers the transactions that can't be disputed anymore, to be evicted from the store.
+    // Those with an open dispute are kept until it gets closed.
+    pub fn take_expired(&mut self, sequence: u64, now: Timestamp) -> Vec<TransactionID> {
+        let mut expired = vec![];
+        while let Some((txid, stored)) = self.order.front().copied() {
+            match self.arrivals.get(&txid) {
+                // Stored again later with the same id, the newer entry is further in the queue
+                Some(arrival) if arrival.sequence != stored => {}
+                Some(arrival) if !self.window.is_expired(arrival, sequence, now) => break,
+                Some(_) if self.open.contains_key(&txid) => {}
+                Some(_) => {
+                    self.expire(txid);
+                    expired.push(txid);
+                }
+                None => {}
+            }
+            self.order.pop_front();
+        }
+        expired
+    }
+
+    fn expire(&mut self, txid: TransactionID) {
+        self.arrivals.remove(&txid);
+        self.changed.insert(txid);
+        self.add_evicted(txid);
+    }
+
+    fn is_evicted(&self, txid: TransactionID) -> bool {
+        self.evicted
+            .range(..=txid)
+            .next_back()
+            .is_some_and(|(_, last)| txid <= *last)
+    }
+
+    // Extends the ranges next to the id, merging them when it fills the gap between two.
+    fn add_evicted(&mut self, txid: TransactionID) {
+        if self.is_evicted(txid) {
+            return;
+        }
+        let first = match self.evicted.range(..txid).next_back() {
+            Some((first, last)) if *last + 1 == txid => *first,
+            _ => txid,
+        };
+        let next = txid.checked_add(1);
+        let last = match next.and_then(|next| self.evicted.remove(&next)) {
+            Some(last) => {
+                self.changed_evicted.extend(next);
+                last
+            }
+            None => txid,
+        };
+        self.evicted.insert(first, last);
+        self.changed_evicted.insert(first);
+    }
+
+    // Tells what changed since the last time, for persistent stores to keep it.
+    pub fn take_changes(&mut self, changes: &mut Bookkeeping) {
+        for first in self.changed_evicted.drain() {
+            changes
+                .evicted
+                .push((first, self.evicted.get(&first).copied()));
+        }
+        for txid in self.changed.drain() {
+            changes
+                .arrivals
+                .push((txid, self.arrivals.get(&txid).copied()));
+            let dispute = self.open.get(&txid).map(|opened| OpenDispute {
+                opened: *opened,
+                aging: self.aging_queue.get(&opened.sequence) == Some(&txid),
+            });
+            changes.disputes.push((txid, dispute));
+        }
+    }
+
+    // Picks up the arrivals and disputes kept by a persistent store, in place of the current ones.
+    pub fn restore(&mut self, bookkeeping: &Bookkeeping) {
+        self.arrivals = bookkeeping
+            .arrivals
+            .iter()
+            .filter_map(|(txid, arrival)| arrival.map(|arrival| (*txid, arrival)))
+            .collect();
+        let mut order: Vec<(TransactionID, u64)> = self
+            .arrivals
+            .iter()
+            .map(|(txid, arrival)| (*txid, arrival.sequence))
+            .collect();
+        order.sort_by_key(|(_, sequence)| *sequence);
+        self.order = order.into();
+        self.open.clear();
+        self.aging_queue.clear();
+        for (txid, dispute) in bookkeeping.disputes.iter() {
+            if let Some(dispute) = dispute {
+                self.open.insert(*txid, dispute.opened);
+                if dispute.aging {
+                    self.aging_queue.insert(dispute.opened.sequence, *txid);
+                }
+            }
+        }
+        self.evicted = bookkeeping
+            .evicted
+            .iter()
+            .filter_map(|(first, last)| last.map(|last| (*first, last)))
+            .collect();
+        self.changed.clear();
+        self.changed_evicted.clear();
+    }
+}
+
+impl Default for Disputes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
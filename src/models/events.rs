@@ -0,0 +1,147 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use serde_json::{json, Value};
+
+use super::{
+    account::RejectedTransaction,
+    json::transaction_json,
+    transaction::{ClientID, Transaction},
+};
+
+// What happened to the accounts while processing a transaction.
+// Events carry the transaction as processed, so the currency is the one of the balance that changed.
+#[derive(Debug, Clone)]
+pub enum Event {
+    TransactionAccepted(Transaction),
+    TransactionRejected {
+        transaction: Transaction,
+        reason: RejectedTransaction,
+    },
+    DisputeOpened(Transaction),
+    DisputeResolved(Transaction),
+    ChargebackApplied(Transaction),
+    AccountLocked {
+        client_id: ClientID,
+    },
+    // What remained of a stale authorization went back to the available funds, told as a void
+    // of the released amount before the transaction that made it expire.
+    HoldReleased(Transaction),
+}
+
+impl Event {
+    pub fn client_id(&self) -> ClientID {
+        match self {
+            Self::TransactionAccepted(transaction)
+            | Self::TransactionRejected { transaction, .. }
+            | Self::DisputeOpened(transaction)
+            | Self::DisputeResolved(transaction)
+            | Self::ChargebackApplied(transaction)
+            | Self::HoldReleased(transaction) => transaction.client_id,
+            Self::AccountLocked { client_id } => *client_id,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::TransactionAccepted(_) => "TransactionAccepted",
+            Self::TransactionRejected { .. } => "TransactionRejected",
+            Self::DisputeOpened(_) => "DisputeOpened",
+            Self::DisputeResolved(_) => "DisputeResolved",
+            Self::ChargebackApplied(_) => "ChargebackApplied",
+            Self::AccountLocked { .. } => "AccountLocked",
+            Self::HoldReleased(_) => "HoldReleased",
+        }
+    }
+
+    // The event as sent to other systems. Amounts are strings so they stay exact.
+    pub fn to_json(&self) -> Value {
+        match self {
+            Self::AccountLocked { client_id } => json!({
+                "event": self.name(),
+                "client": client_id,
+            }),
+            Self::TransactionRejected {
+                transaction,
+                reason,
+            } => {
+                let mut value = transaction_json(transaction);
+                value["event"] = json!(self.name());
+                value["reason"] = json!(reason.reason());
+                value
+            }
+            Self::TransactionAccepted(transaction)
+            | Self::DisputeOpened(transaction)
+            | Self::DisputeResolved(transaction)
+            | Self::ChargebackApplied(transaction)
+            | Self::HoldReleased(transaction) => {
+                let mut value = transaction_json(transaction);
+                value["event"] = json!(self.name());
+                value
+            }
+        }
+    }
+}
+
+// Gets the events of every processed transaction, once its changes are committed.
+// Observers are shared with the app, so they keep what they need behind their own lock.
+pub trait Observer: Debug + Send + Sync {
+    fn notify(&self, event: &Event);
+}
+
+// Keeps every event it gets, to look at them later.
+#[derive(Debug, Default)]
+pub struct EventLog {
+    events: Mutex<Vec<Event>>,
+}
+
+impl EventLog {
+    pub fn new() -> Self {
+        Self {
+            events: Default::default(),
+        }
+    }
+
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().expect("Event log poisoned").clone()
+    }
+}
+
+impl Observer for EventLog {
+    fn notify(&self, event: &Event) {
+        self.events
+            .lock()
+            .expect("Event log poisoned")
+            .push(event.clone());
+    }
+}
+
+// The subscribed observers, all getting every event in the order they subscribed.
+#[derive(Debug, Clone, Default)]
+pub struct Observers {
+    observers: Vec<Arc<dyn Observer>>,
+}
+
+impl Observers {
+    pub fn new() -> Self {
+        Self { observers: vec![] }
+    }
+
+    pub fn subscribe(&mut self, observer: Arc<dyn Observer>) {
+        self.observers.push(observer);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.observers.is_empty()
+    }
+
+    pub fn notify(&self, events: &[Event]) {
+        for event in events {
+            for observer in self.observers.iter() {
+                observer.notify(event);
+            }
+        }
+    }
+}
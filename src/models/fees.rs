@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+
+use csv::StringRecord;
+use fraction::Decimal;
+
+use super::transaction::{
+    parse_currency_code, Amount, ClientID, Currency, Transaction, TransactionID, TransactionType,
+    DEFAULT_CURRENCY,
+};
+
+// What the rows of the fee revenue show in place of a client id.
+pub const FEE_REVENUE: &str = "fees";
+
+// How the fee of a transaction type is computed: a flat amount plus a percentage
+// of the transaction amount, optionally capped by a minimum and a maximum.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeeRule {
+    pub flat: Amount,
+    pub percentage: Amount,
+    pub min: Option<Amount>,
+    pub max: Option<Amount>,
+}
+
+impl FeeRule {
+    pub fn new(flat: Amount, percentage: Amount, min: Option<Amount>, max: Option<Amount>) -> Self {
+        Self {
+            flat,
+            percentage,
+            min,
+            max,
+        }
+    }
+
+    // type, flat, percentage, min, max, currency
+    // The amounts are in the currency of the rule, the default one when not given.
+    pub fn from_record(record: &StringRecord) -> Option<(TransactionType, Currency, Self)> {
+        let kind = match record.get(0)?.trim() {
+            "withdrawal" => TransactionType::Withdrawal,
+            "chargeback" => TransactionType::Chargeback,
+            _ => return None,
+        };
+        let flat = Self::parse_optional(record.get(1))?.unwrap_or_default();
+        let percentage = Self::parse_optional(record.get(2))?.unwrap_or_default();
+        let min = Self::parse_optional(record.get(3))?;
+        let max = Self::parse_optional(record.get(4))?;
+        let currency = match record.get(5).map(|value| value.trim()) {
+            None | Some("") => DEFAULT_CURRENCY.to_string(),
+            Some(value) => parse_currency_code(value)?,
+        };
+        Some((kind, currency, Self::new(flat, percentage, min, max)))
+    }
+
+    // Answers None when the value is present but not a valid amount.
+    fn parse_optional(value: Option<&str>) -> Option<Option<Amount>> {
+        match value.map(|value| value.trim()) {
+            None | Some("") => Some(None),
+            Some(value) => value.parse::<Amount>().ok().map(Some),
+        }
+    }
+
+    pub fn fee_for(&self, amount: Amount) -> Amount {
+        let mut fee = self.flat + amount * self.percentage / Decimal::from(100);
+        if let Some(min) = self.min {
+            if fee < min {
+                fee = min;
+            }
+        }
+        if let Some(max) = self.max {
+            if fee > max {
+                fee = max;
+            }
+        }
+        fee
+    }
+}
+
+// The fee rules per transaction type and currency.
+// Transactions in a currency without a rule for their type are charged no fee.
+#[derive(Debug, Clone)]
+pub struct FeeSchedule {
+    rules: HashMap<(TransactionType, Currency), FeeRule>,
+}
+
+impl FeeSchedule {
+    pub fn new() -> Self {
+        Self {
+            rules: Default::default(),
+        }
+    }
+
+    // Sets the rule of the transaction type in the default currency.
+    pub fn set_rule(&mut self, kind: TransactionType, rule: FeeRule) {
+        self.set_rule_in(kind, DEFAULT_CURRENCY, rule);
+    }
+
+    pub fn set_rule_in(&mut self, kind: TransactionType, currency: &str, rule: FeeRule) {
+        self.rules.insert((kind, currency.to_string()), rule);
+    }
+
+    // Answers the fee to charge for the given transaction type and amount in a currency, if any.
+    pub fn fee_for(
+        &self,
+        kind: &TransactionType,
+        currency: &str,
+        amount: Amount,
+    ) -> Option<Amount> {
+        let fee = self
+            .rules
+            .get(&(kind.clone(), currency.to_string()))?
+            .fee_for(amount);
+        if fee > Decimal::from(0) {
+            Some(fee)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+impl Default for FeeSchedule {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// A fee entry posted from a client account to the fee revenue account, linked to the transaction that caused it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fee {
+    pub client_id: ClientID,
+    pub linked_id: TransactionID,
+    pub kind: TransactionType,
+    pub amount: Amount,
+    pub currency: Currency,
+}
+
+impl Fee {
+    pub fn new(transaction: &Transaction, amount: Amount) -> Self {
+        Self {
+            client_id: transaction.client_id,
+            linked_id: transaction.id,
+            kind: transaction.kind.clone(),
+            amount,
+            currency: transaction.currency.clone(),
+        }
+    }
+}
+
+// The fees collected from the clients, by currency. It is no client account, so it takes
+// no client id from the clients and its rows show `fees` in place of one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeeRevenue {
+    balances: HashMap<Currency, Amount>,
+}
+
+impl FeeRevenue {
+    pub fn new() -> Self {
+        Self {
+            balances: Default::default(),
+        }
+    }
+
+    pub fn collect(&mut self, fee: &Fee) {
+        *self.balances.entry(fee.currency.clone()).or_default() += fee.amount;
+    }
+
+    pub fn balance(&self, currency: &str) -> Amount {
+        self.balances.get(currency).copied().unwrap_or_default()
+    }
+
+    // The currencies fees were collected in, sorted for a stable output.
+    pub fn currencies(&self) -> Vec<Currency> {
+        let mut currencies: Vec<Currency> = self.balances.keys().cloned().collect();
+        currencies.sort();
+        currencies
+    }
+}
@@ -0,0 +1,62 @@
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+use csv::StringRecord;
+
+use super::{
+    account::{Account, Result},
+    transaction::{Transaction, TransactionType},
+};
+
+// A transaction type defined outside the crate, registered with `App::register_handler`.
+// Custom transactions go through the same limits, history and output as the built-in ones.
+pub trait TransactionHandler: Debug + Send + Sync {
+    // The value of the type column for this transaction type.
+    fn name(&self) -> &'static str;
+
+    // Builds the transaction from its record, by default from the usual columns with an optional amount.
+    fn parse(&self, record: StringRecord) -> Result<Transaction> {
+        Transaction::new_custom(self.name(), record)
+    }
+
+    // Rejects the transaction before the account changes. Locked accounts are already rejected.
+    fn validate(&self, _transaction: &Transaction, _account: &Account) -> Result<()> {
+        Ok(())
+    }
+
+    // Applies the transaction to the account of its client, answering it as processed.
+    fn apply(&self, transaction: &Transaction, account: &mut Account) -> Result<Transaction>;
+}
+
+// Registering a handler named as a built-in transaction type, those can't be replaced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReservedName(pub &'static str);
+
+// The registered custom transaction types, by name.
+#[derive(Debug, Clone, Default)]
+pub struct TransactionHandlers {
+    handlers: HashMap<&'static str, Arc<dyn TransactionHandler>>,
+}
+
+impl TransactionHandlers {
+    pub fn new() -> Self {
+        Self {
+            handlers: Default::default(),
+        }
+    }
+
+    // A handler registered again under the same name replaces the previous one.
+    pub fn register(
+        &mut self,
+        handler: Arc<dyn TransactionHandler>,
+    ) -> std::result::Result<(), ReservedName> {
+        if TransactionType::from_name(handler.name()).is_some() {
+            return Err(ReservedName(handler.name()));
+        }
+        self.handlers.insert(handler.name(), handler);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn TransactionHandler> {
+        self.handlers.get(name).map(|handler| handler.as_ref())
+    }
+}
@@ -0,0 +1,42 @@
+use serde_json::{json, Map, Value};
+
+use super::{
+    account::{Balance, BalanceView},
+    transaction::Transaction,
+};
+
+// The JSON other systems get. Amounts are strings so they stay exact.
+
+pub fn transaction_json(transaction: &Transaction) -> Value {
+    json!({
+        "client": transaction.client_id,
+        "tx": transaction.id,
+        "type": transaction.kind.name(),
+        "amount": transaction.amount.map(|amount| amount.to_string()),
+        "currency": transaction.currency,
+        "fee": transaction.fee.map(|fee| fee.to_string()),
+        "timestamp": transaction.timestamp,
+    })
+}
+
+pub fn balance_json(balance: &Balance) -> Value {
+    json!({
+        "available": balance.available().to_string(),
+        "held": balance.held().to_string(),
+        "total": balance.total().to_string(),
+    })
+}
+
+// The balances of every currency of the account, by currency.
+pub fn account_json(account: &BalanceView) -> Value {
+    let mut balances = Map::new();
+    for currency in account.currencies() {
+        let balance = balance_json(&account.balance(&currency));
+        balances.insert(currency, balance);
+    }
+    json!({
+        "client": account.client_id,
+        "locked": account.locked,
+        "balances": balances,
+    })
+}
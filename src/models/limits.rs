@@ -0,0 +1,248 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use csv::StringRecord;
+use fraction::Decimal;
+
+use super::{
+    account::{RejectedTransaction, Result},
+    bookkeeping::Bookkeeping,
+    transaction::{
+        parse_currency_code, Amount, ClientID, Currency, Timestamp, Transaction, TransactionType,
+    },
+};
+
+const SECONDS_PER_DAY: Timestamp = 24 * 60 * 60;
+
+// Risk controls evaluated before a transaction changes any balance.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LimitRule {
+    // No single withdrawal can be greater than the amount.
+    MaxWithdrawal { amount: Amount },
+    // No more than count withdrawals among the last transactions of the client.
+    MaxWithdrawalsPerTransactions { count: usize, transactions: usize },
+    // No more than count withdrawals within the last seconds.
+    MaxWithdrawalsPerWindow { count: usize, seconds: Timestamp },
+    // The withdrawals in the currency of a (UTC) day can't add up to more than the amount.
+    MaxDailyOutflow { currency: Currency, amount: Amount },
+}
+
+impl LimitRule {
+    // rule, limit, window (the currency for the daily outflow)
+    pub fn from_record(record: &StringRecord) -> Option<Self> {
+        let limit = record.get(1)?.trim();
+        let window = record.get(2).map(|value| value.trim());
+        match record.get(0)?.trim() {
+            "max_withdrawal" => Some(Self::MaxWithdrawal {
+                amount: limit.parse::<Amount>().ok()?,
+            }),
+            "max_withdrawals_per_transactions" => Some(Self::MaxWithdrawalsPerTransactions {
+                count: limit.parse::<usize>().ok()?,
+                transactions: window?.parse::<usize>().ok()?,
+            }),
+            "max_withdrawals_per_window" => Some(Self::MaxWithdrawalsPerWindow {
+                count: limit.parse::<usize>().ok()?,
+                seconds: window?.parse::<Timestamp>().ok()?,
+            }),
+            "max_daily_outflow" => Some(Self::MaxDailyOutflow {
+                currency: parse_currency_code(window?)?,
+                amount: limit.parse::<Amount>().ok()?,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Self::MaxWithdrawal { .. } => "max_withdrawal".to_string(),
+            Self::MaxWithdrawalsPerTransactions { .. } => {
+                "max_withdrawals_per_transactions".to_string()
+            }
+            Self::MaxWithdrawalsPerWindow { .. } => "max_withdrawals_per_window".to_string(),
+            Self::MaxDailyOutflow { .. } => "max_daily_outflow".to_string(),
+        }
+    }
+
+    fn allows(&self, transaction: &Transaction, activity: &Activity, now: Timestamp) -> bool {
+        let amount = transaction.amount.unwrap_or_default();
+        match self {
+            Self::MaxWithdrawal { amount: max } => amount <= *max,
+            Self::MaxWithdrawalsPerTransactions {
+                count,
+                transactions,
+            } => {
+                // The last transactions window includes the one being evaluated
+                let previous = transactions.saturating_sub(1);
+                let withdrawals = activity
+                    .recent
+                    .iter()
+                    .rev()
+                    .take(previous)
+                    .filter(|is_withdrawal| **is_withdrawal)
+                    .count();
+                withdrawals < *count
+            }
+            Self::MaxWithdrawalsPerWindow { count, seconds } => {
+                let since = now.saturating_sub(*seconds);
+                let withdrawals = activity
+                    .outflows
+                    .iter()
+                    .filter(|(timestamp, _, _)| *timestamp > since)
+                    .count();
+                withdrawals < *count
+            }
+            Self::MaxDailyOutflow {
+                currency,
+                amount: max,
+            } => {
+                if transaction.currency != *currency {
+                    return true;
+                }
+                let today = now / SECONDS_PER_DAY;
+                let outflow = activity
+                    .outflows
+                    .iter()
+                    .filter(|(timestamp, outflow_currency, _)| {
+                        *timestamp / SECONDS_PER_DAY == today && *outflow_currency == *currency
+                    })
+                    .fold(Decimal::from(0), |sum, (_, _, amount)| sum + *amount);
+                outflow + amount <= *max
+            }
+        }
+    }
+
+    // How many of the latest transactions this rule needs to look at.
+    fn transactions_window(&self) -> usize {
+        match self {
+            Self::MaxWithdrawalsPerTransactions { transactions, .. } => *transactions,
+            _ => 0,
+        }
+    }
+
+    // How long back in time this rule needs to look at.
+    fn time_window(&self) -> Timestamp {
+        match self {
+            Self::MaxWithdrawalsPerWindow { seconds, .. } => *seconds,
+            Self::MaxDailyOutflow { .. } => SECONDS_PER_DAY,
+            _ => 0,
+        }
+    }
+}
+
+// The recent activity of a client needed to evaluate the rules.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Activity {
+    // Whether each of the latest transactions was a withdrawal.
+    pub recent: VecDeque<bool>,
+    // The withdrawals within the longest time window.
+    pub outflows: VecDeque<(Timestamp, Currency, Amount)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LimitRules {
+    rules: Vec<LimitRule>,
+    activity: HashMap<ClientID, Activity>,
+    // The clients whose activity changed since persistent stores were last told.
+    changed: HashSet<ClientID>,
+}
+
+impl LimitRules {
+    pub fn new() -> Self {
+        Self {
+            rules: Default::default(),
+            activity: Default::default(),
+            changed: Default::default(),
+        }
+    }
+
+    pub fn add_rule(&mut self, rule: LimitRule) {
+        self.rules.push(rule);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // Answers the first rule the withdrawal would exceed as a rejection.
+    pub fn check(&self, transaction: &Transaction, now: Timestamp) -> Result<()> {
+        if transaction.kind != TransactionType::Withdrawal {
+            return Ok(());
+        }
+        let empty = Activity::default();
+        let activity = self.activity.get(&transaction.client_id).unwrap_or(&empty);
+        match self
+            .rules
+            .iter()
+            .find(|rule| !rule.allows(transaction, activity, now))
+        {
+            None => Ok(()),
+            Some(rule) => Err(RejectedTransaction::LimitExceeded { rule: rule.name() }),
+        }
+    }
+
+    // Keeps track of an accepted transaction, forgetting what falls out of every rule window.
+    pub fn record(&mut self, transaction: &Transaction, now: Timestamp) {
+        if self.rules.is_empty() {
+            return;
+        }
+        let transactions_window = self
+            .rules
+            .iter()
+            .map(|rule| rule.transactions_window())
+            .max()
+            .unwrap_or_default();
+        let time_window = self
+            .rules
+            .iter()
+            .map(|rule| rule.time_window())
+            .max()
+            .unwrap_or_default();
+        self.changed.insert(transaction.client_id);
+        let activity = self.activity.entry(transaction.client_id).or_default();
+        let is_withdrawal = transaction.kind == TransactionType::Withdrawal;
+        activity.recent.push_back(is_withdrawal);
+        while activity.recent.len() > transactions_window {
+            activity.recent.pop_front();
+        }
+        if is_withdrawal {
+            activity.outflows.push_back((
+                now,
+                transaction.currency.clone(),
+                transaction.amount.unwrap_or_default(),
+            ));
+        }
+        let since = now.saturating_sub(time_window);
+        while let Some((timestamp, _, _)) = activity.outflows.front() {
+            if *timestamp > since {
+                break;
+            }
+            activity.outflows.pop_front();
+        }
+    }
+
+    // Tells what changed since the last time, for persistent stores to keep it.
+    pub fn take_changes(&mut self, changes: &mut Bookkeeping) {
+        for client_id in self.changed.drain() {
+            changes
+                .activity
+                .push((client_id, self.activity.get(&client_id).cloned()));
+        }
+    }
+
+    // Picks up the activity kept by a persistent store, in place of the current one.
+    pub fn restore(&mut self, bookkeeping: &Bookkeeping) {
+        self.activity = bookkeeping
+            .activity
+            .iter()
+            .filter_map(|(client_id, activity)| {
+                activity.clone().map(|activity| (*client_id, activity))
+            })
+            .collect();
+        self.changed.clear();
+    }
+}
+
+impl Default for LimitRules {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,4 +1,18 @@
-pub mod transaction;
 pub mod account;
+pub mod authorizations;
+pub mod bookkeeping;
+pub mod clients;
+pub mod cold_storage;
+pub mod compact;
+pub mod disputes;
+pub mod events;
+pub mod fees;
+pub mod handlers;
+pub mod json;
+pub mod limits;
+pub mod output;
+pub mod rates;
+pub mod sqlite;
+pub mod stores;
+pub mod transaction;
 pub mod transactions;
-pub mod output;
\ No newline at end of file
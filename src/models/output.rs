@@ -1,12 +1,304 @@
-#[derive(Debug, Clone)]
-pub struct Output {}
+use std::{
+    fmt::Debug,
+    fs::File,
+    io::{self, BufWriter, Write},
+};
 
-impl Output {
+use super::{
+    account::{Account, Balance, Snapshot},
+    fees::{FeeRevenue, FEE_REVENUE},
+    transaction::{Amount, ClientID, Transaction, DEFAULT_CURRENCY},
+};
+
+// Where the app writes its rows: one per (client, currency) changed by an accepted transaction.
+// Sinks only need to take lines, the rows are formatted here unless a sink wants them otherwise.
+// A sink failing to write answers the error, the app goes on processing.
+pub trait OutputSink: Debug {
+    fn write_line(&mut self, line: &str) -> io::Result<()>;
+
+    // Sinks buffering lines write them out here.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // The rows of the client affected by an accepted transaction,
+    // followed by the fee revenue if it charged a fee.
+    fn write_transaction(
+        &mut self,
+        account: &Account,
+        revenue: &FeeRevenue,
+        transaction: &Transaction,
+    ) -> io::Result<()> {
+        for line in balance_lines(account, transaction) {
+            self.write_line(&line)?;
+        }
+        if transaction.fee.is_some() {
+            // The fee revenue row shows where the fee went
+            self.write_line(&revenue_line(revenue, &transaction.currency))?;
+        }
+        Ok(())
+    }
+
+    // The rows of every currency the client had at some point in time.
+    fn write_snapshot(&mut self, client_id: ClientID, snapshot: &Snapshot) -> io::Result<()> {
+        for line in snapshot_lines(client_id, snapshot) {
+            self.write_line(&line)?;
+        }
+        Ok(())
+    }
+}
+
+impl OutputSink for Box<dyn OutputSink> {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.as_mut().write_line(line)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.as_mut().flush()
+    }
+
+    fn write_transaction(
+        &mut self,
+        account: &Account,
+        revenue: &FeeRevenue,
+        transaction: &Transaction,
+    ) -> io::Result<()> {
+        self.as_mut()
+            .write_transaction(account, revenue, transaction)
+    }
+
+    fn write_snapshot(&mut self, client_id: ClientID, snapshot: &Snapshot) -> io::Result<()> {
+        self.as_mut().write_snapshot(client_id, snapshot)
+    }
+}
+
+// The rows for the balances affected by the given transaction.
+// Conversions affect two currencies and their rows include the currency and the applied rate.
+pub fn balance_lines(account: &Account, transaction: &Transaction) -> Vec<String> {
+    match (&transaction.counter_currency, transaction.rate) {
+        (Some(counter_currency), Some(rate)) => [&transaction.currency, counter_currency]
+            .iter()
+            .map(|currency| format!("{},{},{}", balance_line(account, currency), currency, rate))
+            .collect(),
+        _ => {
+            let message = balance_line(account, &transaction.currency);
+            if transaction.currency == DEFAULT_CURRENCY {
+                vec![message]
+            } else {
+                vec![format!("{},{}", message, transaction.currency)]
+            }
+        }
+    }
+}
+
+// The rows of every currency in the snapshot, sorted by currency.
+pub fn snapshot_lines(client_id: ClientID, snapshot: &Snapshot) -> Vec<String> {
+    let mut currencies: Vec<&String> = snapshot.balances.keys().collect();
+    currencies.sort();
+    currencies
+        .into_iter()
+        .map(|currency| {
+            let message = format_balance(client_id, &snapshot.balance(currency), snapshot.locked);
+            if currency == DEFAULT_CURRENCY {
+                message
+            } else {
+                format!("{},{}", message, currency)
+            }
+        })
+        .collect()
+}
+
+// One row per (client, currency) without the currency column.
+pub fn balance_line(account: &Account, currency: &str) -> String {
+    format_balance(
+        account.client_id,
+        &account.balance(currency),
+        account.is_locked(),
+    )
+}
+
+pub fn format_balance(client_id: ClientID, balance: &Balance, locked: bool) -> String {
+    format_row(&client_id.to_string(), balance, locked)
+}
+
+// The row of the fee revenue in a currency, shaped as the rows of the clients.
+pub fn revenue_line(revenue: &FeeRevenue, currency: &str) -> String {
+    let collected = revenue.balance(currency);
+    let balance = Balance::from_funds(collected, Amount::from(0), collected);
+    let message = format_row(FEE_REVENUE, &balance, false);
+    if currency == DEFAULT_CURRENCY {
+        message
+    } else {
+        format!("{},{}", message, currency)
+    }
+}
+
+fn format_row(owner: &str, balance: &Balance, locked: bool) -> String {
+    let available = format!("{:.4}", balance.available());
+    let held = format!("{:.4}", balance.held());
+    let total = format!("{:.4}", balance.total());
+    format!("{},{},{},{},{}", owner, available, held, total, locked)
+}
+
+// How many bytes the stdout sink gathers before writing them out.
+const STDOUT_BUFFER: usize = 8 * 1024;
+
+// Writes to stdout in chunks instead of line by line. Whatever is left gets written when dropped.
+#[derive(Debug)]
+pub struct StdoutSink {
+    buffer: String,
+}
+
+impl StdoutSink {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            buffer: String::with_capacity(STDOUT_BUFFER),
+        }
+    }
+
+    fn write_out(&mut self) -> io::Result<()> {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(self.buffer.as_bytes())?;
+        self.buffer.clear();
+        stdout.flush()
+    }
+}
+
+impl OutputSink for StdoutSink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.buffer.push_str(line);
+        self.buffer.push('\n');
+        if self.buffer.len() >= STDOUT_BUFFER {
+            return self.flush();
+        }
+        Ok(())
+    }
+
+    // The lines that failed to be written are dropped, they would fail again.
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.write_out();
+        self.buffer.clear();
+        result
+    }
+}
+
+// A copy starts with nothing pending, otherwise the pending lines would be written twice.
+impl Clone for StdoutSink {
+    fn clone(&self) -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for StdoutSink {
+    fn drop(&mut self) {
+        let _ = self.write_out();
+    }
+}
+
+impl Default for StdoutSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+pub struct FileSink {
+    writer: BufWriter<File>,
+}
+
+impl FileSink {
+    // The file is created, or truncated when it exists.
+    pub fn create(filename: &str) -> io::Result<Self> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(filename)?),
+        })
+    }
+}
+
+impl OutputSink for FileSink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.writer, "{}", line)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Keeps the lines in memory, to look at them in tests.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink {
+    lines: Vec<String>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self { lines: vec![] }
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+}
+
+impl OutputSink for MemorySink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.lines.push(line.to_string());
+        Ok(())
+    }
+}
+
+// Writes every line to each of its sinks. A failing sink doesn't keep the others from getting
+// the line, the first error is answered.
+#[derive(Debug, Default)]
+pub struct FanOut {
+    sinks: Vec<Box<dyn OutputSink>>,
+}
+
+impl FanOut {
+    pub fn new() -> Self {
+        Self { sinks: vec![] }
+    }
+
+    pub fn add(&mut self, sink: Box<dyn OutputSink>) {
+        self.sinks.push(sink);
+    }
+
+    fn each(
+        &mut self,
+        mut write: impl FnMut(&mut Box<dyn OutputSink>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut result = Ok(());
+        for sink in self.sinks.iter_mut() {
+            let written = write(sink);
+            if result.is_ok() {
+                result = written;
+            }
+        }
+        result
+    }
+}
+
+impl OutputSink for FanOut {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.each(|sink| sink.write_line(line))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.each(|sink| sink.flush())
+    }
+
+    // Each sink formats the rows its own way.
+    fn write_transaction(
+        &mut self,
+        account: &Account,
+        revenue: &FeeRevenue,
+        transaction: &Transaction,
+    ) -> io::Result<()> {
+        self.each(|sink| sink.write_transaction(account, revenue, transaction))
     }
 
-    pub fn write(&self, string: String) {
-        println!("{}", string);
+    fn write_snapshot(&mut self, client_id: ClientID, snapshot: &Snapshot) -> io::Result<()> {
+        self.each(|sink| sink.write_snapshot(client_id, snapshot))
     }
 }
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+
+use csv::StringRecord;
+use fraction::Decimal;
+
+use super::transaction::{parse_currency_code, parse_currency_pair, Amount, Currency, Timestamp};
+
+pub type Rate = Amount;
+
+// Scale used for currencies without an explicit rounding rule, it matches the output precision.
+pub const DEFAULT_SCALE: u8 = 4;
+
+// The largest scale allowed, amounts get multiplied by 10 to the scale and larger factors
+// overflow the 64 bits of the amounts being rounded.
+pub const MAX_SCALE: u8 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RoundingMode {
+    // Ties go away from zero.
+    HalfUp,
+    // Ties go to the nearest even digit (banker's rounding).
+    HalfEven,
+    // Truncates the extra digits.
+    Down,
+}
+
+impl RoundingMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim() {
+            "half-up" => Some(Self::HalfUp),
+            "half-even" => Some(Self::HalfEven),
+            "down" => Some(Self::Down),
+            _ => None,
+        }
+    }
+}
+
+// How converted amounts get rounded when credited in a given currency.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RoundingRule {
+    pub scale: u8,
+    pub mode: RoundingMode,
+}
+
+impl RoundingRule {
+    pub fn new(scale: u8, mode: RoundingMode) -> Self {
+        Self { scale, mode }
+    }
+
+    // currency, scale, mode
+    pub fn from_record(record: &StringRecord) -> Option<(Currency, Self)> {
+        let currency = parse_currency_code(record.get(0)?)?;
+        let scale = record.get(1)?.trim().parse::<u8>().ok()?;
+        if scale > MAX_SCALE {
+            return None;
+        }
+        let mode = RoundingMode::from_name(record.get(2)?)?;
+        Some((currency, Self::new(scale, mode)))
+    }
+
+    pub fn apply(&self, amount: Amount) -> Amount {
+        let scale = self.scale.min(MAX_SCALE);
+        let factor = Decimal::from(10u64.pow(scale as u32));
+        let scaled = amount * factor;
+        let rounded = match self.mode {
+            RoundingMode::HalfUp => scaled.round(),
+            RoundingMode::Down => scaled.trunc(),
+            RoundingMode::HalfEven => {
+                let floor = scaled.floor();
+                let half = Decimal::from(0.5);
+                let diff = scaled - floor;
+                let is_even = (floor / Decimal::from(2)).trunc() * Decimal::from(2) == floor;
+                if diff > half || (diff == half && !is_even) {
+                    floor + Decimal::from(1)
+                } else {
+                    floor
+                }
+            }
+        };
+        (rounded / factor).set_precision(scale)
+    }
+}
+
+impl Default for RoundingRule {
+    fn default() -> Self {
+        Self::new(DEFAULT_SCALE, RoundingMode::HalfEven)
+    }
+}
+
+// Exchange rates by currency pair, each pair keeps its rates sorted by effective timestamp.
+#[derive(Debug, Clone)]
+pub struct RateTable {
+    rates: HashMap<(Currency, Currency), Vec<(Timestamp, Rate)>>,
+    rounding: HashMap<Currency, RoundingRule>,
+}
+
+impl RateTable {
+    pub fn new() -> Self {
+        Self {
+            rates: Default::default(),
+            rounding: Default::default(),
+        }
+    }
+
+    // pair, rate, effective timestamp
+    pub fn add_record(&mut self, record: &StringRecord) -> Option<()> {
+        let (base, quote) = parse_currency_pair(record.get(0)?)?;
+        let rate = record.get(1)?.trim().parse::<Rate>().ok()?;
+        let effective = record.get(2)?.trim().parse::<Timestamp>().ok()?;
+        if rate <= Decimal::from(0) {
+            return None;
+        }
+        self.set_rate(base, quote, rate, effective);
+        Some(())
+    }
+
+    pub fn set_rate(&mut self, base: Currency, quote: Currency, rate: Rate, effective: Timestamp) {
+        let rates = self.rates.entry((base, quote)).or_default();
+        rates.retain(|(timestamp, _)| *timestamp != effective);
+        rates.push((effective, rate));
+        rates.sort_by_key(|(timestamp, _)| *timestamp);
+    }
+
+    pub fn set_rounding(&mut self, currency: Currency, rule: RoundingRule) {
+        self.rounding.insert(currency, rule);
+    }
+
+    // Answers the rate to convert from base to quote effective at the given time.
+    // Rates taking effect later aren't known yet.
+    pub fn rate(&self, base: &str, quote: &str, at: Timestamp) -> Option<Rate> {
+        let rates = self.rates.get(&(base.to_string(), quote.to_string()))?;
+        rates
+            .iter()
+            .rev()
+            .find(|(effective, _)| *effective <= at)
+            .map(|(_, rate)| *rate)
+    }
+
+    pub fn rounding_for(&self, currency: &str) -> RoundingRule {
+        self.rounding.get(currency).copied().unwrap_or_default()
+    }
+
+    pub fn round(&self, currency: &str, amount: Amount) -> Amount {
+        self.rounding_for(currency).apply(amount)
+    }
+
+    pub fn size(&self) -> usize {
+        self.rates.values().map(|rates| rates.len()).sum()
+    }
+}
+
+impl Default for RateTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
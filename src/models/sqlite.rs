@@ -0,0 +1,862 @@
+use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    io,
+    rc::Rc,
+};
+
+use rusqlite::{params, types::Type, Connection, OptionalExtension, Row};
+
+use super::{
+    account::{Account, Balance, Balances, Snapshot},
+    authorizations::Authorization,
+    bookkeeping::Bookkeeping,
+    disputes::OpenDispute,
+    events::Event,
+    fees::Fee,
+    limits::Activity,
+    stores::{AccountStore, TransactionStore},
+    transaction::{
+        Amount, Arrival, ClientID, Currency, Timestamp, Transaction, TransactionID, TransactionType,
+    },
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS accounts (
+        client INTEGER PRIMARY KEY,
+        locked INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS balances (
+        client INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        available TEXT NOT NULL,
+        held TEXT NOT NULL,
+        total TEXT NOT NULL,
+        PRIMARY KEY (client, currency)
+    );
+    CREATE TABLE IF NOT EXISTS credit_limits (
+        client INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        credit_limit TEXT NOT NULL,
+        PRIMARY KEY (client, currency)
+    );
+    CREATE TABLE IF NOT EXISTS balance_history (
+        client INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        available TEXT NOT NULL,
+        held TEXT NOT NULL,
+        total TEXT NOT NULL,
+        PRIMARY KEY (client, currency, timestamp)
+    );
+    CREATE TABLE IF NOT EXISTS lock_history (
+        client INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        locked INTEGER NOT NULL,
+        PRIMARY KEY (client, timestamp)
+    );
+    CREATE TABLE IF NOT EXISTS transactions (
+        tx INTEGER PRIMARY KEY,
+        type TEXT NOT NULL,
+        client INTEGER NOT NULL,
+        amount TEXT,
+        currency TEXT NOT NULL,
+        timestamp INTEGER,
+        fee TEXT,
+        refunded TEXT NOT NULL DEFAULT '0'
+    );
+    CREATE TABLE IF NOT EXISTS journal (
+        sequence INTEGER PRIMARY KEY AUTOINCREMENT,
+        type TEXT NOT NULL,
+        client INTEGER NOT NULL,
+        tx INTEGER NOT NULL,
+        amount TEXT,
+        currency TEXT NOT NULL,
+        timestamp INTEGER,
+        fee TEXT
+    );
+    CREATE TABLE IF NOT EXISTS outbox (
+        sequence INTEGER PRIMARY KEY AUTOINCREMENT,
+        event TEXT NOT NULL,
+        client INTEGER NOT NULL,
+        payload TEXT NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        delivered INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS authorizations (
+        tx INTEGER PRIMARY KEY,
+        client INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        amount TEXT NOT NULL,
+        captured TEXT NOT NULL,
+        sequence INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS arrivals (
+        tx INTEGER PRIMARY KEY,
+        sequence INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS disputes (
+        tx INTEGER PRIMARY KEY,
+        sequence INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        aging INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS evicted (
+        first INTEGER PRIMARY KEY,
+        last INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS fees (
+        sequence INTEGER PRIMARY KEY AUTOINCREMENT,
+        client INTEGER NOT NULL,
+        tx INTEGER NOT NULL,
+        type TEXT NOT NULL,
+        amount TEXT NOT NULL,
+        currency TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS recent_activity (
+        client INTEGER PRIMARY KEY,
+        withdrawals TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS outflows (
+        sequence INTEGER PRIMARY KEY AUTOINCREMENT,
+        client INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        amount TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS state (
+        name TEXT PRIMARY KEY,
+        value INTEGER
+    );
+";
+
+// Amounts are kept as text so they stay exact and can still be cast in SQL queries.
+fn to_text(amount: Amount) -> String {
+    amount.calc_precision(Some(18)).to_string()
+}
+
+// A value in the database that can't be read back as what it should be.
+fn invalid(column: usize, what: &str) -> rusqlite::Error {
+    let error = io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid {} in the database", what),
+    );
+    rusqlite::Error::FromSqlConversionFailure(column, Type::Text, Box::new(error))
+}
+
+fn amount(row: &Row, column: usize) -> rusqlite::Result<Amount> {
+    row.get::<_, String>(column)?
+        .parse::<Amount>()
+        .map_err(|_| invalid(column, "amount"))
+}
+
+fn optional_amount(row: &Row, column: usize) -> rusqlite::Result<Option<Amount>> {
+    match row.get::<_, Option<String>>(column)? {
+        None => Ok(None),
+        Some(_) => amount(row, column).map(Some),
+    }
+}
+
+fn kind(row: &Row, column: usize) -> rusqlite::Result<TransactionType> {
+    TransactionType::from_name(&row.get::<_, String>(column)?)
+        .ok_or_else(|| invalid(column, "transaction type"))
+}
+
+// Three columns in a row: available, held and total.
+fn balance(row: &Row, first: usize) -> rusqlite::Result<Balance> {
+    Ok(Balance::from_funds(
+        amount(row, first)?,
+        amount(row, first + 1)?,
+        amount(row, first + 2)?,
+    ))
+}
+
+fn io_error(error: rusqlite::Error) -> io::Error {
+    io::Error::other(error)
+}
+
+// Opens, or creates, a SQLite database and answers the account and transaction stores using it.
+// Both share the connection so the changes of each processed transaction get committed together.
+pub fn open(filename: &str) -> rusqlite::Result<(SqliteAccounts, SqliteTransactions)> {
+    let connection = Connection::open(filename)?;
+    connection.pragma_update(None, "journal_mode", "WAL")?;
+    connection.execute_batch(SCHEMA)?;
+    let connection = Rc::new(connection);
+    let accounts = SqliteAccounts {
+        connection: connection.clone(),
+        working: Default::default(),
+    };
+    let transactions = SqliteTransactions { connection };
+    Ok((accounts, transactions))
+}
+
+// Accounts are read as the transactions need them and written back on commit, only the ones
+// of the current database transaction stay in memory. Their history is kept in tables of its own.
+#[derive(Debug)]
+pub struct SqliteAccounts {
+    connection: Rc<Connection>,
+    // The accounts read or created since the last commit.
+    working: HashMap<ClientID, Account>,
+}
+
+impl SqliteAccounts {
+    // The account as last committed, if the client has one.
+    fn read(connection: &Connection, client_id: ClientID) -> rusqlite::Result<Option<Account>> {
+        let locked = connection
+            .query_row(
+                "SELECT locked FROM accounts WHERE client = ?1",
+                [client_id],
+                |row| row.get::<_, bool>(0),
+            )
+            .optional()?;
+        let locked = match locked {
+            None => return Ok(None),
+            Some(value) => value,
+        };
+        let mut statement = connection
+            .prepare("SELECT currency, available, held, total FROM balances WHERE client = ?1")?;
+        let balances = statement
+            .query_map([client_id], |row| {
+                Ok((row.get::<_, Currency>(0)?, balance(row, 1)?))
+            })?
+            .collect::<rusqlite::Result<Balances>>()?;
+        let mut statement = connection
+            .prepare("SELECT currency, credit_limit FROM credit_limits WHERE client = ?1")?;
+        let credit_limits = statement
+            .query_map([client_id], |row| {
+                Ok((row.get::<_, Currency>(0)?, amount(row, 1)?))
+            })?
+            .collect::<rusqlite::Result<HashMap<Currency, Amount>>>()?;
+        Ok(Some(Account::restore(
+            client_id,
+            balances,
+            credit_limits,
+            locked,
+        )))
+    }
+
+    fn save(&self, account: &Account) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO accounts (client, locked) VALUES (?1, ?2)",
+            params![account.client_id, account.is_locked()],
+        )?;
+        for currency in account.currencies() {
+            let balance = account.balance(&currency);
+            self.connection.execute(
+                "INSERT OR REPLACE INTO balances (client, currency, available, held, total)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    account.client_id,
+                    currency,
+                    to_text(balance.available()),
+                    to_text(balance.held()),
+                    to_text(balance.total()),
+                ],
+            )?;
+        }
+        for (currency, limit) in account.credit_limits() {
+            self.connection.execute(
+                "INSERT OR REPLACE INTO credit_limits (client, currency, credit_limit)
+                 VALUES (?1, ?2, ?3)",
+                params![account.client_id, currency, to_text(*limit)],
+            )?;
+        }
+        self.save_history(account)
+    }
+
+    // An account read from the database starts with no history, what it recorded since is added
+    // to the tables unless they already had it at that time, as the history in memory does.
+    fn save_history(&self, account: &Account) -> rusqlite::Result<()> {
+        for (currency, timestamp, recorded) in account.recorded_balances() {
+            let previous = self
+                .connection
+                .query_row(
+                    "SELECT available, held, total FROM balance_history
+                     WHERE client = ?1 AND currency = ?2 AND timestamp <= ?3
+                     ORDER BY timestamp DESC LIMIT 1",
+                    params![account.client_id, currency, timestamp],
+                    |row| balance(row, 0),
+                )
+                .optional()?;
+            if previous.as_ref() != Some(recorded) {
+                self.connection.execute(
+                    "INSERT OR REPLACE INTO balance_history
+                     (client, currency, timestamp, available, held, total)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![
+                        account.client_id,
+                        currency,
+                        timestamp,
+                        to_text(recorded.available()),
+                        to_text(recorded.held()),
+                        to_text(recorded.total()),
+                    ],
+                )?;
+            }
+        }
+        for (timestamp, locked) in account.recorded_locks() {
+            let previous = self
+                .connection
+                .query_row(
+                    "SELECT locked FROM lock_history WHERE client = ?1 AND timestamp <= ?2
+                     ORDER BY timestamp DESC LIMIT 1",
+                    params![account.client_id, timestamp],
+                    |row| row.get::<_, bool>(0),
+                )
+                .optional()?;
+            if previous.unwrap_or(false) != *locked {
+                self.connection.execute(
+                    "INSERT OR REPLACE INTO lock_history (client, timestamp, locked)
+                     VALUES (?1, ?2, ?3)",
+                    params![account.client_id, timestamp, locked],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_snapshot(
+        &self,
+        client_id: ClientID,
+        timestamp: Timestamp,
+    ) -> rusqlite::Result<Option<Snapshot>> {
+        let mut latest = None;
+        // SQLite takes the other columns from the row with the largest timestamp
+        let mut statement = self.connection.prepare(
+            "SELECT currency, available, held, total, MAX(timestamp) FROM balance_history
+             WHERE client = ?1 AND timestamp <= ?2 GROUP BY currency",
+        )?;
+        let rows = statement.query_map(params![client_id, timestamp], |row| {
+            Ok((
+                row.get::<_, Currency>(0)?,
+                balance(row, 1)?,
+                row.get::<_, Timestamp>(4)?,
+            ))
+        })?;
+        let mut balances = Balances::new();
+        for row in rows {
+            let (currency, balance, at) = row?;
+            balances.insert(currency, balance);
+            latest = latest.max(Some(at));
+        }
+        let lock = self
+            .connection
+            .query_row(
+                "SELECT timestamp, locked FROM lock_history WHERE client = ?1 AND timestamp <= ?2
+                 ORDER BY timestamp DESC LIMIT 1",
+                params![client_id, timestamp],
+                |row| Ok((row.get::<_, Timestamp>(0)?, row.get::<_, bool>(1)?)),
+            )
+            .optional()?;
+        let locked = match lock {
+            None => false,
+            Some((at, locked)) => {
+                latest = latest.max(Some(at));
+                locked
+            }
+        };
+        Ok(latest.map(|timestamp| Snapshot {
+            timestamp,
+            balances,
+            locked,
+        }))
+    }
+}
+
+impl AccountStore for SqliteAccounts {
+    fn get_account(&self, client_id: ClientID) -> io::Result<Option<Cow<'_, Account>>> {
+        if let Some(account) = self.working.get(&client_id) {
+            return Ok(Some(Cow::Borrowed(account)));
+        }
+        let account = Self::read(&self.connection, client_id).map_err(io_error)?;
+        Ok(account.map(Cow::Owned))
+    }
+
+    // Whoever asks for a mutable account might change it, it is written back on commit.
+    fn get_or_create_account(&mut self, client_id: ClientID) -> io::Result<&mut Account> {
+        match self.working.entry(client_id) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let account = Self::read(&self.connection, client_id)
+                    .map_err(io_error)?
+                    .unwrap_or_else(|| Account::new(client_id));
+                Ok(entry.insert(account))
+            }
+        }
+    }
+
+    fn client_ids(&self) -> io::Result<Vec<ClientID>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT client FROM accounts")
+            .map_err(io_error)?;
+        let mut client_ids = statement
+            .query_map([], |row| row.get::<_, ClientID>(0))
+            .and_then(|rows| rows.collect::<rusqlite::Result<HashSet<ClientID>>>())
+            .map_err(io_error)?;
+        client_ids.extend(self.working.keys());
+        Ok(client_ids.into_iter().collect())
+    }
+
+    // As last committed.
+    fn snapshot_at(
+        &self,
+        client_id: ClientID,
+        timestamp: Timestamp,
+    ) -> io::Result<Option<Snapshot>> {
+        self.read_snapshot(client_id, timestamp).map_err(io_error)
+    }
+
+    // The accounts savepoint is the outermost one, releasing it commits.
+    fn begin(&mut self) -> io::Result<()> {
+        self.connection
+            .execute_batch("SAVEPOINT accounts")
+            .map_err(io_error)
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        for account in self.working.values() {
+            self.save(account).map_err(io_error)?;
+        }
+        self.connection
+            .execute_batch("RELEASE accounts")
+            .map_err(io_error)?;
+        self.working.clear();
+        Ok(())
+    }
+
+    // The accounts get read again as they were committed.
+    fn rollback(&mut self) {
+        // Nothing to undo when the savepoint couldn't even begin
+        let _ = self
+            .connection
+            .execute_batch("ROLLBACK TO accounts; RELEASE accounts");
+        self.working.clear();
+    }
+}
+
+#[derive(Debug)]
+pub struct SqliteTransactions {
+    connection: Rc<Connection>,
+}
+
+impl SqliteTransactions {
+    fn from_row(row: &Row) -> rusqlite::Result<Transaction> {
+        let mut transaction = Transaction::new_synthetic(
+            kind(row, 0)?,
+            row.get::<_, ClientID>(1)?,
+            row.get::<_, TransactionID>(2)?,
+        );
+        transaction.amount = optional_amount(row, 3)?;
+        transaction.currency = row.get::<_, Currency>(4)?;
+        transaction.timestamp = row.get::<_, Option<Timestamp>>(5)?;
+        transaction.fee = optional_amount(row, 6)?;
+        Ok(transaction)
+    }
+
+    fn write_bookkeeping(&self, changes: &Bookkeeping) -> rusqlite::Result<()> {
+        let connection = &self.connection;
+        connection.execute(
+            "INSERT OR REPLACE INTO state (name, value) VALUES ('sequence', ?1)",
+            [changes.sequence],
+        )?;
+        for (txid, authorization) in changes.authorizations.iter() {
+            match authorization {
+                Some(authorization) => connection.execute(
+                    "INSERT OR REPLACE INTO authorizations
+                     (tx, client, currency, amount, captured, sequence, timestamp)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![
+                        txid,
+                        authorization.client_id,
+                        authorization.currency,
+                        to_text(authorization.amount),
+                        to_text(authorization.captured),
+                        authorization.authorized.sequence,
+                        authorization.authorized.timestamp,
+                    ],
+                )?,
+                None => connection.execute("DELETE FROM authorizations WHERE tx = ?1", [txid])?,
+            };
+        }
+        for (txid, arrival) in changes.arrivals.iter() {
+            match arrival {
+                Some(arrival) => connection.execute(
+                    "INSERT OR REPLACE INTO arrivals (tx, sequence, timestamp) VALUES (?1, ?2, ?3)",
+                    params![txid, arrival.sequence, arrival.timestamp],
+                )?,
+                None => connection.execute("DELETE FROM arrivals WHERE tx = ?1", [txid])?,
+            };
+        }
+        for (txid, dispute) in changes.disputes.iter() {
+            match dispute {
+                Some(dispute) => connection.execute(
+                    "INSERT OR REPLACE INTO disputes (tx, sequence, timestamp, aging)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        txid,
+                        dispute.opened.sequence,
+                        dispute.opened.timestamp,
+                        dispute.aging,
+                    ],
+                )?,
+                None => connection.execute("DELETE FROM disputes WHERE tx = ?1", [txid])?,
+            };
+        }
+        for (first, last) in changes.evicted.iter() {
+            match last {
+                Some(last) => connection.execute(
+                    "INSERT OR REPLACE INTO evicted (first, last) VALUES (?1, ?2)",
+                    params![first, last],
+                )?,
+                None => connection.execute("DELETE FROM evicted WHERE first = ?1", [first])?,
+            };
+        }
+        for fee in changes.fees.iter() {
+            connection.execute(
+                "INSERT INTO fees (client, tx, type, amount, currency) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    fee.client_id,
+                    fee.linked_id,
+                    fee.kind.name(),
+                    to_text(fee.amount),
+                    fee.currency,
+                ],
+            )?;
+        }
+        // The activity of a client is small, it gets written again as a whole
+        for (client_id, activity) in changes.activity.iter() {
+            connection.execute("DELETE FROM recent_activity WHERE client = ?1", [client_id])?;
+            connection.execute("DELETE FROM outflows WHERE client = ?1", [client_id])?;
+            let activity = match activity {
+                None => continue,
+                Some(value) => value,
+            };
+            // A character per transaction, `1` for the withdrawals
+            let withdrawals: String = activity
+                .recent
+                .iter()
+                .map(|is_withdrawal| if *is_withdrawal { '1' } else { '0' })
+                .collect();
+            connection.execute(
+                "INSERT INTO recent_activity (client, withdrawals) VALUES (?1, ?2)",
+                params![client_id, withdrawals],
+            )?;
+            for (timestamp, currency, amount) in activity.outflows.iter() {
+                connection.execute(
+                    "INSERT INTO outflows (client, timestamp, currency, amount)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![client_id, timestamp, currency, to_text(*amount)],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_bookkeeping(&self) -> rusqlite::Result<Bookkeeping> {
+        let connection = &self.connection;
+        let state = |name: &str| {
+            connection
+                .query_row("SELECT value FROM state WHERE name = ?1", [name], |row| {
+                    row.get::<_, Option<u64>>(0)
+                })
+                .optional()
+                .map(Option::flatten)
+        };
+        let sequence = state("sequence")?.unwrap_or_default();
+        let mut statement = connection.prepare("SELECT first, last FROM evicted")?;
+        let evicted = statement
+            .query_map([], |row| {
+                Ok((row.get::<_, TransactionID>(0)?, Some(row.get(1)?)))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut statement = connection.prepare(
+            "SELECT tx, client, currency, amount, captured, sequence, timestamp FROM authorizations",
+        )?;
+        let authorizations = statement
+            .query_map([], |row| {
+                let authorization = Authorization {
+                    client_id: row.get(1)?,
+                    id: row.get(0)?,
+                    currency: row.get(2)?,
+                    amount: amount(row, 3)?,
+                    captured: amount(row, 4)?,
+                    authorized: Arrival {
+                        sequence: row.get(5)?,
+                        timestamp: row.get(6)?,
+                    },
+                };
+                Ok((authorization.id, Some(authorization)))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut statement = connection.prepare("SELECT tx, sequence, timestamp FROM arrivals")?;
+        let arrivals = statement
+            .query_map([], |row| {
+                let arrival = Arrival {
+                    sequence: row.get(1)?,
+                    timestamp: row.get(2)?,
+                };
+                Ok((row.get::<_, TransactionID>(0)?, Some(arrival)))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut statement =
+            connection.prepare("SELECT tx, sequence, timestamp, aging FROM disputes")?;
+        let disputes = statement
+            .query_map([], |row| {
+                let dispute = OpenDispute {
+                    opened: Arrival {
+                        sequence: row.get(1)?,
+                        timestamp: row.get(2)?,
+                    },
+                    aging: row.get(3)?,
+                };
+                Ok((row.get::<_, TransactionID>(0)?, Some(dispute)))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut statement = connection
+            .prepare("SELECT client, tx, type, amount, currency FROM fees ORDER BY sequence")?;
+        let fees = statement
+            .query_map([], |row| {
+                Ok(Fee {
+                    client_id: row.get(0)?,
+                    linked_id: row.get(1)?,
+                    kind: kind(row, 2)?,
+                    amount: amount(row, 3)?,
+                    currency: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut activity: HashMap<ClientID, Activity> = HashMap::new();
+        let mut statement =
+            connection.prepare("SELECT client, withdrawals FROM recent_activity")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let recent = row
+                .get::<_, String>(1)?
+                .chars()
+                .map(|flag| match flag {
+                    '0' => Ok(false),
+                    '1' => Ok(true),
+                    _ => Err(invalid(1, "recent activity")),
+                })
+                .collect::<rusqlite::Result<_>>()?;
+            activity.entry(row.get(0)?).or_default().recent = recent;
+        }
+        let mut statement = connection.prepare(
+            "SELECT client, timestamp, currency, amount FROM outflows ORDER BY sequence",
+        )?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let outflow = (row.get(1)?, row.get(2)?, amount(row, 3)?);
+            activity
+                .entry(row.get(0)?)
+                .or_default()
+                .outflows
+                .push_back(outflow);
+        }
+        Ok(Bookkeeping {
+            sequence,
+            evicted,
+            authorizations,
+            arrivals,
+            disputes,
+            fees,
+            activity: activity
+                .into_iter()
+                .map(|(client_id, activity)| (client_id, Some(activity)))
+                .collect(),
+        })
+    }
+}
+
+impl TransactionStore for SqliteTransactions {
+    fn set(&mut self, transaction: Transaction) -> io::Result<Option<TransactionID>> {
+        self.connection
+            .execute(
+                "INSERT OR REPLACE INTO transactions (tx, type, client, amount, currency, timestamp, fee)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    transaction.id,
+                    transaction.kind.name(),
+                    transaction.client_id,
+                    transaction.amount.map(to_text),
+                    transaction.currency,
+                    transaction.timestamp,
+                    transaction.fee.map(to_text),
+                ],
+            )
+            .map_err(io_error)?;
+        Ok(Some(transaction.id))
+    }
+
+    fn get(&self, txid: TransactionID) -> io::Result<Option<Cow<'_, Transaction>>> {
+        let transaction = self
+            .connection
+            .query_row(
+                "SELECT type, client, tx, amount, currency, timestamp, fee FROM transactions WHERE tx = ?1",
+                [txid],
+                Self::from_row,
+            )
+            .optional()
+            .map_err(io_error)?;
+        Ok(transaction.map(Cow::Owned))
+    }
+
+    fn remove(&mut self, txid: TransactionID) -> io::Result<bool> {
+        let removed = self
+            .connection
+            .execute("DELETE FROM transactions WHERE tx = ?1", [txid])
+            .map_err(io_error)?;
+        Ok(removed > 0)
+    }
+
+    fn size(&self) -> io::Result<usize> {
+        self.connection
+            .query_row("SELECT COUNT(*) FROM transactions", [], |row| row.get(0))
+            .map_err(io_error)
+    }
+
+    fn add_refund(&mut self, txid: TransactionID, amount: Amount) -> io::Result<()> {
+        let refunded = self.refunded(txid)? + amount;
+        self.connection
+            .execute(
+                "UPDATE transactions SET refunded = ?2 WHERE tx = ?1",
+                params![txid, to_text(refunded)],
+            )
+            .map_err(io_error)?;
+        Ok(())
+    }
+
+    fn refunded(&self, txid: TransactionID) -> io::Result<Amount> {
+        let refunded = self
+            .connection
+            .query_row(
+                "SELECT refunded FROM transactions WHERE tx = ?1",
+                [txid],
+                |row| amount(row, 0),
+            )
+            .optional()
+            .map_err(io_error)?;
+        Ok(refunded.unwrap_or_default())
+    }
+
+    fn journal(&mut self, transaction: &Transaction) -> io::Result<()> {
+        self.connection
+            .execute(
+                "INSERT INTO journal (type, client, tx, amount, currency, timestamp, fee)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    transaction.kind.name(),
+                    transaction.client_id,
+                    transaction.id,
+                    transaction.amount.map(to_text),
+                    transaction.currency,
+                    transaction.timestamp,
+                    transaction.fee.map(to_text),
+                ],
+            )
+            .map_err(io_error)?;
+        Ok(())
+    }
+
+    fn outbox(&mut self, events: &[Event]) -> io::Result<()> {
+        for event in events {
+            self.connection
+                .execute(
+                    "INSERT INTO outbox (event, client, payload) VALUES (?1, ?2, ?3)",
+                    params![event.name(), event.client_id(), event.to_json().to_string()],
+                )
+                .map_err(io_error)?;
+        }
+        Ok(())
+    }
+
+    fn save_bookkeeping(&mut self, changes: &Bookkeeping) -> io::Result<()> {
+        self.write_bookkeeping(changes).map_err(io_error)
+    }
+
+    fn load_bookkeeping(&self) -> io::Result<Option<Bookkeeping>> {
+        self.read_bookkeeping().map(Some).map_err(io_error)
+    }
+
+    // Nested within the accounts savepoint.
+    fn begin(&mut self) -> io::Result<()> {
+        self.connection
+            .execute_batch("SAVEPOINT transactions")
+            .map_err(io_error)
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        self.connection
+            .execute_batch("RELEASE transactions")
+            .map_err(io_error)
+    }
+
+    fn rollback(&mut self) {
+        // Nothing to undo when the savepoint couldn't even begin, or was already released
+        let _ = self
+            .connection
+            .execute_batch("ROLLBACK TO transactions; RELEASE transactions");
+    }
+}
+
+// An event waiting in the outbox to be delivered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutboxEntry {
+    // Increases with every event, downstream systems can use it to drop repeated deliveries.
+    pub sequence: i64,
+    pub event: String,
+    pub payload: String,
+    // Deliveries that failed so far.
+    pub attempts: u32,
+}
+
+// The events written along with the journal, read by the relay delivering them downstream.
+#[derive(Debug)]
+pub struct Outbox {
+    connection: Connection,
+}
+
+impl Outbox {
+    pub fn open(filename: &str) -> rusqlite::Result<Self> {
+        let connection = Connection::open(filename)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self { connection })
+    }
+
+    // The undelivered events, oldest first.
+    pub fn pending(&self) -> rusqlite::Result<Vec<OutboxEntry>> {
+        let mut statement = self.connection.prepare(
+            "SELECT sequence, event, payload, attempts FROM outbox
+             WHERE delivered IS NULL ORDER BY sequence",
+        )?;
+        let entries = statement.query_map([], |row| {
+            Ok(OutboxEntry {
+                sequence: row.get(0)?,
+                event: row.get(1)?,
+                payload: row.get(2)?,
+                attempts: row.get(3)?,
+            })
+        })?;
+        entries.collect()
+    }
+
+    pub fn mark_delivered(&self, sequence: i64, at: Timestamp) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "UPDATE outbox SET delivered = ?2 WHERE sequence = ?1",
+            params![sequence, at],
+        )?;
+        Ok(())
+    }
+
+    pub fn record_attempt(&self, sequence: i64) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "UPDATE outbox SET attempts = attempts + 1 WHERE sequence = ?1",
+            [sequence],
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap},
+    fmt::Debug,
+    io,
+};
+
+use super::{
+    account::{Account, Checkpoint, Snapshot},
+    bookkeeping::Bookkeeping,
+    events::Event,
+    transaction::{Amount, ClientID, Timestamp, Transaction, TransactionID},
+};
+
+// Where the app keeps the accounts, created on demand as transactions for new clients arrive.
+// Stores keeping them out of memory answer the errors of reading or writing them, the app
+// rejects the transaction that needed them.
+pub trait AccountStore: Debug {
+    // Stores not keeping the accounts in memory answer an owned copy.
+    fn get_account(&self, client_id: ClientID) -> io::Result<Option<Cow<'_, Account>>>;
+
+    fn get_or_create_account(&mut self, client_id: ClientID) -> io::Result<&mut Account>;
+
+    // The clients having an account, in no particular order.
+    fn client_ids(&self) -> io::Result<Vec<ClientID>>;
+
+    // The balances the client had at the given time, for stores keeping the history elsewhere.
+    fn snapshot_at(
+        &self,
+        client_id: ClientID,
+        timestamp: Timestamp,
+    ) -> io::Result<Option<Snapshot>> {
+        Ok(self
+            .get_account(client_id)?
+            .and_then(|account| account.snapshot_at(timestamp)))
+    }
+
+    // Called before processing each transaction, persistent stores open a database transaction here.
+    fn begin(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Called once the transaction got processed, accepted or not, to persist what changed.
+    fn commit(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Called instead of commit when a store failed meanwhile, to undo what changed.
+    fn rollback(&mut self) {}
+}
+
+// Where the app keeps the accepted deposits and withdrawals, to be disputed or refunded later.
+// Stores keeping them out of memory answer the errors of reading or writing them, the app
+// rejects the transaction that needed them.
+pub trait TransactionStore: Debug {
+    fn set(&mut self, transaction: Transaction) -> io::Result<Option<TransactionID>>;
+
+    // Stores not keeping the transactions in memory answer an owned copy.
+    fn get(&self, txid: TransactionID) -> io::Result<Option<Cow<'_, Transaction>>>;
+
+    // Forgets the transaction and its refunds, answering whether it was stored.
+    fn remove(&mut self, txid: TransactionID) -> io::Result<bool>;
+
+    fn size(&self) -> io::Result<usize>;
+
+    // Links a refunded amount to the original withdrawal.
+    fn add_refund(&mut self, txid: TransactionID, amount: Amount) -> io::Result<()>;
+
+    fn refunded(&self, txid: TransactionID) -> io::Result<Amount>;
+
+    // Called with every accepted transaction, for stores keeping a journal of them.
+    fn journal(&mut self, _transaction: &Transaction) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Called with the events of every processed transaction when the app keeps an outbox,
+    // for stores able to write them along with the journal entry.
+    fn outbox(&mut self, _events: &[Event]) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Called with what processing each transaction changed in the bookkeeping of the app,
+    // for persistent stores to keep it along with the rest.
+    fn save_bookkeeping(&mut self, _changes: &Bookkeeping) -> io::Result<()> {
+        Ok(())
+    }
+
+    // The bookkeeping kept by a persistent store, as last committed. Stores not keeping it answer none.
+    fn load_bookkeeping(&self) -> io::Result<Option<Bookkeeping>> {
+        Ok(None)
+    }
+
+    // Called before processing each transaction with an estimate of the memory the app keeps
+    // about the stored transactions elsewhere, for stores bounding their memory to count it.
+    fn reserve_memory(&mut self, _bytes: usize) {}
+
+    // Called before processing each transaction, persistent stores open a database transaction here.
+    fn begin(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Called once the transaction got processed, accepted or not, to persist what changed.
+    fn commit(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Called instead of commit when a store failed meanwhile, persistent stores undo what changed.
+    fn rollback(&mut self) {}
+}
+
+// The default in-memory account store. The accounts a transaction touched are rolled back
+// to how they were when first asked for, when a store failed while processing it.
+#[derive(Debug, Clone, Default)]
+pub struct Accounts {
+    accounts: HashMap<ClientID, Account>,
+    // The accounts touched since the last begin, none for the ones created meanwhile.
+    touched: HashMap<ClientID, Option<Checkpoint>>,
+}
+
+impl Accounts {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // Looks at an account, creating it as needed, out of any transaction to roll back.
+    pub fn account(&mut self, client_id: ClientID) -> &mut Account {
+        self.accounts
+            .entry(client_id)
+            .or_insert_with(|| Account::new(client_id))
+    }
+}
+
+impl AccountStore for Accounts {
+    fn get_account(&self, client_id: ClientID) -> io::Result<Option<Cow<'_, Account>>> {
+        Ok(self.accounts.get(&client_id).map(Cow::Borrowed))
+    }
+
+    // Whoever asks for a mutable account might change it, it is checkpointed the first time.
+    fn get_or_create_account(&mut self, client_id: ClientID) -> io::Result<&mut Account> {
+        match self.accounts.entry(client_id) {
+            Entry::Occupied(entry) => {
+                let account = entry.into_mut();
+                if let Entry::Vacant(touched) = self.touched.entry(client_id) {
+                    touched.insert(Some(account.checkpoint()));
+                }
+                Ok(account)
+            }
+            Entry::Vacant(entry) => {
+                self.touched.entry(client_id).or_insert(None);
+                Ok(entry.insert(Account::new(client_id)))
+            }
+        }
+    }
+
+    fn client_ids(&self) -> io::Result<Vec<ClientID>> {
+        Ok(self.accounts.keys().copied().collect())
+    }
+
+    fn begin(&mut self) -> io::Result<()> {
+        self.touched.clear();
+        Ok(())
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        self.touched.clear();
+        Ok(())
+    }
+
+    fn rollback(&mut self) {
+        for (client_id, checkpoint) in self.touched.drain() {
+            match checkpoint {
+                None => {
+                    self.accounts.remove(&client_id);
+                }
+                Some(checkpoint) => {
+                    if let Some(account) = self.accounts.get_mut(&client_id) {
+                        account.roll_back(checkpoint);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -7,23 +7,80 @@ pub struct InvalidTransactionType;
 pub type ClientID = u16;
 pub type TransactionID = u32;
 pub type Amount = fraction::Decimal;
+pub type Currency = String;
+pub type Timestamp = u64;
 
-// type, client, tx, amount
+// Currency used when a record doesn't bring the optional currency column.
+pub const DEFAULT_CURRENCY: &str = "USD";
+
+// type, client, tx, amount, currency, timestamp
 #[derive(Debug, Clone, PartialEq)]
 pub struct Transaction {
     pub kind: TransactionType,
     pub client_id: ClientID,
     pub id: TransactionID,
     pub amount: Option<Amount>,
+    pub currency: Currency,
+    // The currency credited by a conversion.
+    pub counter_currency: Option<Currency>,
+    // The exchange rate applied when a conversion gets processed.
+    pub rate: Option<Amount>,
+    // The fee charged when the transaction gets processed.
+    pub fee: Option<Amount>,
+    // When the transaction happened, if the record brings it.
+    pub timestamp: Option<Timestamp>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum TransactionType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    Convert,
+    Authorize,
+    Capture,
+    Void,
+    Refund,
+    // A type registered with a `TransactionHandler`, by its name.
+    Custom(&'static str),
+}
+
+impl TransactionType {
+    // The name used for the type in the input records.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Deposit => "deposit",
+            Self::Withdrawal => "withdrawal",
+            Self::Dispute => "dispute",
+            Self::Resolve => "resolve",
+            Self::Chargeback => "chargeback",
+            Self::Convert => "convert",
+            Self::Authorize => "authorize",
+            Self::Capture => "capture",
+            Self::Void => "void",
+            Self::Refund => "refund",
+            Self::Custom(name) => name,
+        }
+    }
+
+    // Only the built-in types, custom ones are known by their handlers.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "deposit" => Some(Self::Deposit),
+            "withdrawal" => Some(Self::Withdrawal),
+            "dispute" => Some(Self::Dispute),
+            "resolve" => Some(Self::Resolve),
+            "chargeback" => Some(Self::Chargeback),
+            "convert" => Some(Self::Convert),
+            "authorize" => Some(Self::Authorize),
+            "capture" => Some(Self::Capture),
+            "void" => Some(Self::Void),
+            "refund" => Some(Self::Refund),
+            _ => None,
+        }
+    }
 }
 
 impl Transaction {
@@ -36,7 +93,12 @@ impl Transaction {
                 "dispute" => Self::new_dispute(record),
                 "resolve" => Self::new_resolve(record),
                 "chargeback" => Self::new_chargeback(record),
-                _ => return Err(RejectedTransaction::InvalidInput),
+                "convert" => Self::new_convert(record),
+                "authorize" => Self::new_authorize(record),
+                "capture" => Self::new_capture(record),
+                "void" => Self::new_void(record),
+                "refund" => Self::new_refund(record),
+                _ => Err(RejectedTransaction::InvalidInput),
             },
         }
     }
@@ -45,47 +107,110 @@ impl Transaction {
         record: StringRecord,
         kind: TransactionType,
         amount: Option<Amount>,
+    ) -> Result<Self> {
+        let currency = Self::parse_currency(&record)?;
+        Self::basic_new_in(record, kind, amount, currency)
+    }
+
+    fn basic_new_in(
+        record: StringRecord,
+        kind: TransactionType,
+        amount: Option<Amount>,
+        currency: Currency,
     ) -> Result<Self> {
         Ok(Self {
             kind,
-            client_id: record.get(1).unwrap().trim().parse::<ClientID>().unwrap(),
-            id: record
-                .get(2)
-                .unwrap()
-                .trim()
-                .parse::<TransactionID>()
-                .unwrap(),
+            client_id: Self::parse_id(&record, 1)?,
+            id: Self::parse_id(&record, 2)?,
             amount,
+            currency,
+            counter_currency: None,
+            rate: None,
+            fee: None,
+            timestamp: Self::parse_timestamp(&record)?,
         })
     }
 
-    pub fn new_deposit(record: StringRecord) -> Result<Self> {
-        let amount;
+    // Ill-formed ids reject the transaction, records can come from anywhere, like a socket.
+    fn parse_id<I: std::str::FromStr>(record: &StringRecord, index: usize) -> Result<I> {
+        record
+            .get(index)
+            .and_then(|value| value.trim().parse::<I>().ok())
+            .ok_or(RejectedTransaction::InvalidInput)
+    }
+
+    fn parse_amount(record: &StringRecord) -> Result<Option<Amount>> {
         match record.get(3) {
-            None => return Err(RejectedTransaction::InvalidInput),
+            None => Err(RejectedTransaction::InvalidInput),
             Some(value) => {
                 let a = value.trim().parse::<Amount>();
                 match a {
-                    Err(_err) => return Err(RejectedTransaction::InvalidInput),
-                    Ok(value) => amount = Some(value),
+                    Err(_err) => Err(RejectedTransaction::InvalidInput),
+                    Ok(value) => Ok(Some(value)),
                 }
             }
-        };
+        }
+    }
+
+    // An empty or missing amount is fine but when present it should be valid.
+    fn parse_optional_amount(record: &StringRecord) -> Result<Option<Amount>> {
+        match record.get(3).map(|value| value.trim()) {
+            None | Some("") => Ok(None),
+            Some(value) => match value.parse::<Amount>() {
+                Err(_err) => Err(RejectedTransaction::InvalidInput),
+                Ok(value) => Ok(Some(value)),
+            },
+        }
+    }
+
+    // The timestamp column is optional, it can be seconds since the Unix epoch or a UTC date and time.
+    fn parse_timestamp(record: &StringRecord) -> Result<Option<Timestamp>> {
+        match record.get(5).map(|value| value.trim()) {
+            None | Some("") => Ok(None),
+            Some(value) => parse_timestamp(value)
+                .map(Some)
+                .ok_or(RejectedTransaction::InvalidInput),
+        }
+    }
+
+    // The currency column is optional, when missing or empty the default currency is assumed.
+    fn parse_currency(record: &StringRecord) -> Result<Currency> {
+        match record.get(4).map(|value| value.trim()) {
+            None | Some("") => Ok(DEFAULT_CURRENCY.to_string()),
+            Some(value) => parse_currency_code(value).ok_or(RejectedTransaction::InvalidInput),
+        }
+    }
+
+    // Answers a copy of this transaction bound to the given currency.
+    pub fn in_currency(&self, currency: &str) -> Self {
+        Self {
+            currency: currency.to_string(),
+            ..self.clone()
+        }
+    }
+
+    // A transaction the system applies on its own, like closing an aged dispute.
+    pub fn new_synthetic(kind: TransactionType, client_id: ClientID, id: TransactionID) -> Self {
+        Self {
+            kind,
+            client_id,
+            id,
+            amount: None,
+            currency: DEFAULT_CURRENCY.to_string(),
+            counter_currency: None,
+            rate: None,
+            fee: None,
+            timestamp: None,
+        }
+    }
+
+    pub fn new_deposit(record: StringRecord) -> Result<Self> {
+        let amount = Self::parse_amount(&record)?;
         Self::basic_new(record, TransactionType::Deposit, amount)
     }
 
     pub fn new_withdrawal(record: StringRecord) -> Result<Self> {
-        let amount;
-        match record.get(3) {
-            None => return Err(RejectedTransaction::InvalidInput),
-            Some(value) => {
-                let a = value.trim().parse::<Amount>();
-                match a {
-                    Err(_err) => return Err(RejectedTransaction::InvalidInput),
-                    Ok(value) => amount = Some(value),
-                }
-            }
-        };
+        let amount = Self::parse_amount(&record)?;
         Self::basic_new(record, TransactionType::Withdrawal, amount)
     }
 
@@ -100,4 +225,155 @@ impl Transaction {
     pub fn new_chargeback(record: StringRecord) -> Result<Self> {
         Self::basic_new(record, TransactionType::Chargeback, None)
     }
+
+    pub fn new_authorize(record: StringRecord) -> Result<Self> {
+        let amount = Self::parse_amount(&record)?;
+        Self::basic_new(record, TransactionType::Authorize, amount)
+    }
+
+    // A capture refers to the authorization by its tx id, without an amount it captures all that remains.
+    pub fn new_capture(record: StringRecord) -> Result<Self> {
+        let amount = Self::parse_optional_amount(&record)?;
+        Self::basic_new(record, TransactionType::Capture, amount)
+    }
+
+    pub fn new_void(record: StringRecord) -> Result<Self> {
+        Self::basic_new(record, TransactionType::Void, None)
+    }
+
+    // A refund refers to the original withdrawal by its tx id.
+    pub fn new_refund(record: StringRecord) -> Result<Self> {
+        let amount = Self::parse_amount(&record)?;
+        Self::basic_new(record, TransactionType::Refund, amount)
+    }
+
+    // The usual columns with an optional amount, for custom transaction types.
+    pub fn new_custom(name: &'static str, record: StringRecord) -> Result<Self> {
+        let amount = Self::parse_optional_amount(&record)?;
+        Self::basic_new(record, TransactionType::Custom(name), amount)
+    }
+
+    // A conversion brings the currency pair in the currency column, i.e. EUR/USD debits EUR and credits USD.
+    pub fn new_convert(record: StringRecord) -> Result<Self> {
+        let amount = Self::parse_amount(&record)?;
+        let pair = record
+            .get(4)
+            .and_then(parse_currency_pair)
+            .ok_or(RejectedTransaction::InvalidInput)?;
+        let (currency, counter_currency) = pair;
+        let mut transaction =
+            Self::basic_new_in(record, TransactionType::Convert, amount, currency)?;
+        transaction.counter_currency = Some(counter_currency);
+        Ok(transaction)
+    }
+}
+
+// When a transaction was stored, a dispute opened or funds got authorized.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Arrival {
+    // The number of transactions processed by then.
+    pub sequence: u64,
+    pub timestamp: Timestamp,
+}
+
+impl Arrival {
+    // Answers whether more transactions or seconds than either limit went by since the arrival.
+    // Without any limit, nothing gets old.
+    pub fn is_older_than(
+        &self,
+        transactions: Option<u64>,
+        seconds: Option<Timestamp>,
+        sequence: u64,
+        now: Timestamp,
+    ) -> bool {
+        let by_transactions = transactions
+            .map(|limit| sequence.saturating_sub(self.sequence) > limit)
+            .unwrap_or(false);
+        let by_time = seconds
+            .map(|limit| now.saturating_sub(self.timestamp) > limit)
+            .unwrap_or(false);
+        by_transactions || by_time
+    }
+}
+
+// Answers the normalized currency for a three letters code like `eur`.
+pub fn parse_currency_code(value: &str) -> Option<Currency> {
+    let value = value.trim();
+    if value.len() == 3 && value.chars().all(|c| c.is_ascii_alphabetic()) {
+        Some(value.to_ascii_uppercase())
+    } else {
+        None
+    }
+}
+
+// Answers the (base, quote) currencies for a pair like `EUR/USD`.
+pub fn parse_currency_pair(value: &str) -> Option<(Currency, Currency)> {
+    let (base, quote) = value.trim().split_once('/')?;
+    let base = parse_currency_code(base)?;
+    let quote = parse_currency_code(quote)?;
+    if base == quote {
+        None
+    } else {
+        Some((base, quote))
+    }
+}
+
+// Dates are written with four digits years at most.
+const MAX_YEAR: i64 = 9999;
+
+// Answers the seconds since the Unix epoch for either a number of seconds or
+// a UTC date and time like `2022-08-30T17:00:00Z` or `2022-08-30 17:00:00`.
+pub fn parse_timestamp(value: &str) -> Option<Timestamp> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<Timestamp>() {
+        return Some(seconds);
+    }
+    let value = value.strip_suffix('Z').unwrap_or(value);
+    let (date, time) = value.split_once(['T', ' '])?;
+    let mut date_parts = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (
+        date_parts.next()??,
+        date_parts.next()??,
+        date_parts.next()??,
+    );
+    let mut time_parts = time.splitn(3, ':').map(|part| part.parse::<i64>().ok());
+    let (hour, minute, second) = (
+        time_parts.next()??,
+        time_parts.next()??,
+        time_parts.next()??,
+    );
+    if !(0..=MAX_YEAR).contains(&year)
+        || !(1..=12).contains(&month)
+        || !(1..=days_in_month(year, month)).contains(&day)
+        || !(0..24).contains(&hour)
+        || !(0..60).contains(&minute)
+        || !(0..60).contains(&second)
+    {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    let seconds = days
+        .checked_mul(86400)?
+        .checked_add(hour * 3600 + minute * 60 + second)?;
+    Timestamp::try_from(seconds).ok()
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        4 | 6 | 9 | 11 => 30,
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01 of a proleptic Gregorian calendar date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
 }
@@ -1,40 +1,238 @@
-use std::collections::HashMap;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    io, mem,
+};
 
-use super::transaction::{Transaction, TransactionID};
+use super::{
+    cold_storage::ColdStorage,
+    compact::{CompactTransaction, CurrencyCodes},
+    stores::TransactionStore,
+    transaction::{Amount, Transaction, TransactionID},
+};
 
+// The in-memory transaction store, optionally spilling the oldest transactions to disk.
+// Transactions are kept as compact records, sized for tens of millions of them.
 #[derive(Debug, Clone)]
 pub struct Transactions {
-    pub store: HashMap<TransactionID, Transaction>,
+    // The hot transactions, kept in memory.
+    pub store: HashMap<TransactionID, CompactTransaction>,
+    // The few hot transactions a compact record can't hold exactly, kept as they are.
+    wide: HashMap<TransactionID, Transaction>,
+    currencies: CurrencyCodes,
+    // An estimate of the memory used by the hot transactions, in bytes.
+    hot_bytes: usize,
+    // An estimate of the memory the app keeps about the stored transactions elsewhere,
+    // counted in the budget too.
+    reserved_bytes: usize,
+    // Without a budget every transaction stays in memory.
+    memory_budget: Option<usize>,
+    // The hot transactions, oldest first, to spill them when going over the budget.
+    recent: VecDeque<TransactionID>,
+    cold: Option<ColdStorage>,
+    // The amount refunded so far for each refunded hot withdrawal, cold ones keep it in their record.
+    refunds: HashMap<TransactionID, Amount>,
 }
 
 impl Transactions {
     pub fn new() -> Self {
         Self {
             store: Default::default(),
+            wide: Default::default(),
+            currencies: Default::default(),
+            hot_bytes: 0,
+            reserved_bytes: 0,
+            memory_budget: None,
+            recent: Default::default(),
+            cold: None,
+            refunds: Default::default(),
         }
     }
 
-    pub fn set(&mut self, transaction: Transaction) -> Option<TransactionID> {
-        let txid = transaction.id;
-        self.store.insert(txid, transaction);
-        Some(txid)
+    // Keeps at most the budget worth of transactions in memory, spilling the oldest ones to a file
+    // before processing each transaction. Without a filename they go to a temporary file.
+    pub fn set_memory_budget(&mut self, budget: usize, filename: Option<String>) -> io::Result<()> {
+        self.cold = Some(ColdStorage::create(filename)?);
+        self.memory_budget = Some(budget);
+        self.recent = self.store.keys().chain(self.wide.keys()).copied().collect();
+        self.spill()
+    }
+
+    // An estimate of the memory a compact record takes.
+    fn compact_footprint() -> usize {
+        mem::size_of::<TransactionID>() + mem::size_of::<CompactTransaction>()
     }
 
-    pub fn get(&self, txid: TransactionID) -> Option<&Transaction> {
-        self.store.get(&txid)
+    // An estimate of the memory a transaction kept as it is takes.
+    fn footprint(transaction: &Transaction) -> usize {
+        mem::size_of::<TransactionID>()
+            + mem::size_of::<Transaction>()
+            + transaction.currency.capacity()
     }
 
-    pub fn get_mut(&mut self, txid: TransactionID) -> Option<&mut Transaction> {
-        self.store.get_mut(&txid)
+    // An estimate of the memory used in all, the hot transactions along with what it takes
+    // to find them, refund them and spill them.
+    pub fn memory_used(&self) -> usize {
+        self.hot_bytes
+            + self.reserved_bytes
+            + self.recent.len() * mem::size_of::<TransactionID>()
+            + self.refunds.len() * (mem::size_of::<TransactionID>() + mem::size_of::<Amount>())
     }
 
-    pub fn size(&self) -> usize {
-        self.store.len()
+    // How many transactions a compact record couldn't hold.
+    pub fn wide_size(&self) -> usize {
+        self.wide.len()
     }
 
-    pub fn reset(&mut self) {
+    // How many transactions were spilled to disk.
+    pub fn cold_size(&self) -> usize {
+        self.cold
+            .as_ref()
+            .map(|cold| cold.len())
+            .unwrap_or_default()
+    }
+
+    // Forgets a hot transaction, answering whether it was one.
+    fn remove_hot(&mut self, txid: TransactionID) -> bool {
+        if self.store.remove(&txid).is_some() {
+            self.hot_bytes -= Self::compact_footprint();
+            return true;
+        }
+        match self.wide.remove(&txid) {
+            Some(transaction) => {
+                self.hot_bytes -= Self::footprint(&transaction);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Moves the oldest hot transactions to the cold storage until back within the budget.
+    // A transaction only leaves memory once written, a failing write loses nothing.
+    fn spill(&mut self) -> io::Result<()> {
+        let budget = match (self.memory_budget, &self.cold) {
+            (Some(budget), Some(_)) => budget,
+            _ => return Ok(()),
+        };
+        while self.memory_used() > budget {
+            let txid = match self.recent.front() {
+                None => break,
+                Some(value) => *value,
+            };
+            let transaction = match self.store.get(&txid) {
+                Some(compact) => Some(compact.expand(txid, &self.currencies)),
+                None => self.wide.get(&txid).cloned(),
+            };
+            // Ids already spilled or removed are skipped
+            if let (Some(transaction), Some(cold)) = (transaction, self.cold.as_mut()) {
+                let refunded = self.refunds.get(&txid).copied().unwrap_or_default();
+                cold.write(&transaction, refunded)?;
+                self.remove_hot(txid);
+                self.refunds.remove(&txid);
+            }
+            self.recent.pop_front();
+        }
+        Ok(())
+    }
+
+    pub fn reset(&mut self) -> io::Result<()> {
         self.store.clear();
+        self.wide.clear();
+        self.hot_bytes = 0;
+        self.recent.clear();
+        self.refunds.clear();
+        match self.cold.as_mut() {
+            Some(cold) => cold.clear(),
+            None => Ok(()),
+        }
     }
 }
 
+impl TransactionStore for Transactions {
+    fn set(&mut self, transaction: Transaction) -> io::Result<Option<TransactionID>> {
+        let txid = transaction.id;
+        if let Some(cold) = self.cold.as_mut() {
+            cold.remove(txid)?;
+        }
+        self.remove_hot(txid);
+        match CompactTransaction::new(&transaction, &mut self.currencies) {
+            Some(compact) => {
+                self.hot_bytes += Self::compact_footprint();
+                self.store.insert(txid, compact);
+            }
+            None => {
+                self.hot_bytes += Self::footprint(&transaction);
+                self.wide.insert(txid, transaction);
+            }
+        }
+        if self.memory_budget.is_some() {
+            self.recent.push_back(txid);
+        }
+        Ok(Some(txid))
+    }
 
+    // Compact records and cold transactions answer an owned copy.
+    fn get(&self, txid: TransactionID) -> io::Result<Option<Cow<'_, Transaction>>> {
+        if let Some(compact) = self.store.get(&txid) {
+            return Ok(Some(Cow::Owned(compact.expand(txid, &self.currencies))));
+        }
+        if let Some(transaction) = self.wide.get(&txid) {
+            return Ok(Some(Cow::Borrowed(transaction)));
+        }
+        match self.cold.as_ref() {
+            Some(cold) => Ok(cold
+                .read(txid)?
+                .map(|(transaction, _)| Cow::Owned(transaction))),
+            None => Ok(None),
+        }
+    }
+
+    fn remove(&mut self, txid: TransactionID) -> io::Result<bool> {
+        let cold = match self.cold.as_mut() {
+            Some(cold) => cold.remove(txid)?,
+            None => false,
+        };
+        self.refunds.remove(&txid);
+        Ok(self.remove_hot(txid) || cold)
+    }
+
+    fn size(&self) -> io::Result<usize> {
+        Ok(self.store.len() + self.wide.len() + self.cold_size())
+    }
+
+    // Links a refunded amount to the original withdrawal.
+    fn add_refund(&mut self, txid: TransactionID, amount: Amount) -> io::Result<()> {
+        if let Some(cold) = self.cold.as_mut() {
+            if cold.add_refund(txid, amount)? {
+                return Ok(());
+            }
+        }
+        *self.refunds.entry(txid).or_default() += amount;
+        Ok(())
+    }
+
+    fn refunded(&self, txid: TransactionID) -> io::Result<Amount> {
+        if let Some(refunded) = self.refunds.get(&txid) {
+            return Ok(*refunded);
+        }
+        match self.cold.as_ref().map(|cold| cold.read(txid)).transpose()? {
+            Some(Some((_, refunded))) => Ok(refunded),
+            _ => Ok(Default::default()),
+        }
+    }
+
+    fn reserve_memory(&mut self, bytes: usize) {
+        self.reserved_bytes = bytes;
+    }
+
+    // Spills before anything changes, so a failing spill file only rejects the transaction.
+    fn begin(&mut self) -> io::Result<()> {
+        self.spill()
+    }
+}
+
+impl Default for Transactions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
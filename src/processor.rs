@@ -0,0 +1,177 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, Read},
+};
+
+use csv::StringRecord;
+
+use crate::{
+    app::App,
+    clock::Clock,
+    csv::get_transactions_reader,
+    models::{
+        account::Result,
+        output::OutputSink,
+        stores::{AccountStore, TransactionStore},
+        transaction::{ClientID, Transaction},
+    },
+};
+
+// What happened to the records of a run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Summary {
+    // The records given to the app, accepted or not. The header row isn't one.
+    pub processed: usize,
+    pub accepted: usize,
+    // How many records were rejected for each reason.
+    pub rejected: BTreeMap<String, usize>,
+    // Records that couldn't even be read as CSV.
+    pub unreadable: usize,
+    // Disputes closed on behalf of the client for going stale.
+    pub aged_disputes: usize,
+    // The clients of the accepted transactions.
+    pub accounts_touched: BTreeSet<ClientID>,
+    // Writes to the output sink that failed, each losing the rows of a transaction
+    // or the ones the sink had pending. Processing goes on regardless.
+    pub output_failures: usize,
+}
+
+impl Summary {
+    pub fn rejected_total(&self) -> usize {
+        self.rejected.values().sum()
+    }
+}
+
+// Feeds CSV records from any source to an app, the loop every embedding would otherwise write.
+#[derive(Debug)]
+pub struct Processor<C: Clock, A: AccountStore, T: TransactionStore, O: OutputSink> {
+    app: App<C, A, T, O>,
+    // Writes to the output sink that failed since the processor was created.
+    output_failures: usize,
+}
+
+impl<C: Clock, A: AccountStore, T: TransactionStore, O: OutputSink> Processor<C, A, T, O> {
+    pub fn new(app: App<C, A, T, O>) -> Self {
+        Self {
+            app,
+            output_failures: 0,
+        }
+    }
+
+    // Processes every record, writing the rows of the accepted transactions to the output sink.
+    pub fn run<R: Read>(&mut self, reader: R) -> Summary {
+        self.process(reader, true)
+    }
+
+    // Processes every record without writing any row, to look at the state of the app afterwards.
+    pub fn replay<R: Read>(&mut self, reader: R) -> Summary {
+        self.process(reader, false)
+    }
+
+    fn process<R: Read>(&mut self, reader: R, write: bool) -> Summary {
+        let mut summary = Summary::default();
+        let mut reader = get_transactions_reader(reader);
+        for (index, record) in reader.records().enumerate() {
+            let record = match record {
+                Err(_) => {
+                    summary.unreadable += 1;
+                    continue;
+                }
+                Ok(value) => value,
+            };
+            if index == 0 && record.get(0) == Some("type") {
+                continue;
+            }
+            // The summary counts the outcome
+            let _ = self.record(record, &mut summary, write);
+        }
+        if write {
+            self.output(&mut summary, |app| app.flush_output());
+        }
+        summary
+    }
+
+    // Processes a single record as `run` does, for records coming one at a time like from a server.
+    // Answers its outcome along with the disputes it made age, which changed balances too.
+    pub fn process_record(
+        &mut self,
+        record: StringRecord,
+    ) -> (Result<Transaction>, Vec<Transaction>) {
+        let mut summary = Summary::default();
+        let processed = self.record(record, &mut summary, true);
+        self.output(&mut summary, |app| app.flush_output());
+        processed
+    }
+
+    // Counts the failed writes, a sink that can't write isn't a reason to stop processing.
+    fn output(
+        &mut self,
+        summary: &mut Summary,
+        write: impl FnOnce(&mut App<C, A, T, O>) -> io::Result<()>,
+    ) {
+        if write(&mut self.app).is_err() {
+            summary.output_failures += 1;
+            self.output_failures += 1;
+        }
+    }
+
+    fn record(
+        &mut self,
+        record: StringRecord,
+        summary: &mut Summary,
+        write: bool,
+    ) -> (Result<Transaction>, Vec<Transaction>) {
+        summary.processed += 1;
+        let result = self.app.process_record(record);
+        match &result {
+            Ok(transaction) => {
+                summary.accepted += 1;
+                summary.accounts_touched.insert(transaction.client_id);
+                if write {
+                    self.output(summary, |app| app.output_transaction(transaction));
+                }
+            }
+            Err(rejection) => {
+                *summary
+                    .rejected
+                    .entry(rejection.reason().to_string())
+                    .or_default() += 1;
+            }
+        }
+        // Disputes closed by aging show up right after the transaction that made them age
+        let aged = self.app.age_disputes();
+        for transaction in aged.iter() {
+            summary.aged_disputes += 1;
+            summary.accounts_touched.insert(transaction.client_id);
+            if write {
+                self.output(summary, |app| app.output_transaction(transaction));
+            }
+        }
+        (result, aged)
+    }
+
+    // Writes to the output sink that failed so far, records given one at a time included.
+    pub fn output_failures(&self) -> usize {
+        self.output_failures
+    }
+
+    pub fn app(&self) -> &App<C, A, T, O> {
+        &self.app
+    }
+
+    pub fn app_mut(&mut self) -> &mut App<C, A, T, O> {
+        &mut self.app
+    }
+
+    pub fn into_app(self) -> App<C, A, T, O> {
+        self.app
+    }
+}
+
+impl<C: Clock, A: AccountStore, T: TransactionStore, O: OutputSink> From<App<C, A, T, O>>
+    for Processor<C, A, T, O>
+{
+    fn from(app: App<C, A, T, O>) -> Self {
+        Self::new(app)
+    }
+}
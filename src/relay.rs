@@ -0,0 +1,128 @@
+use std::{thread, time::Duration};
+
+use ureq::{Agent, AgentBuilder};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    models::sqlite::{Outbox, OutboxEntry},
+};
+
+pub const DEFAULT_RETRIES: u32 = 5;
+
+// The wait before the first retry, doubled for every retry after it.
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(500);
+
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+// How deliveries to other systems are retried.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    pub retries: u32,
+    // The wait before the first retry, doubled for every retry after it.
+    pub initial: Duration,
+}
+
+impl Backoff {
+    // Makes attempts, numbered from zero, until one succeeds or there are no retries left.
+    pub fn retry(&self, mut attempt: impl FnMut(u32) -> bool) -> bool {
+        let mut wait = self.initial;
+        for number in 0..=self.retries {
+            if number > 0 {
+                thread::sleep(wait);
+                wait *= 2;
+            }
+            if attempt(number) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            retries: DEFAULT_RETRIES,
+            initial: DEFAULT_BACKOFF,
+        }
+    }
+}
+
+// What a relay run did with the outbox.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RelaySummary {
+    pub delivered: usize,
+    // Events left for the next run, the first of them couldn't be delivered.
+    pub pending: usize,
+}
+
+// Delivers the events of an outbox to an HTTP endpoint, one POST per event and in order.
+// An event is marked delivered only once the endpoint accepted it, so a relay stopped halfway
+// sends it again on the next run. Its `Idempotency-Key` header lets the endpoint drop the repeat.
+#[derive(Debug)]
+pub struct OutboxRelay {
+    outbox: Outbox,
+    endpoint: String,
+    agent: Agent,
+    backoff: Backoff,
+}
+
+impl OutboxRelay {
+    pub fn new(outbox: Outbox, endpoint: &str) -> Self {
+        Self {
+            outbox,
+            endpoint: endpoint.to_string(),
+            agent: AgentBuilder::new().timeout(TIMEOUT).build(),
+            backoff: Backoff::default(),
+        }
+    }
+
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.backoff.retries = retries;
+        self
+    }
+
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff.initial = backoff;
+        self
+    }
+
+    // Delivers the pending events, stopping at the first one the endpoint keeps refusing
+    // so that later events never overtake it. Stops as well when the outbox can't be read
+    // or written, a delivered event not marked as such gets sent again on the next run.
+    pub fn run(&self) -> rusqlite::Result<RelaySummary> {
+        let pending = self.outbox.pending()?;
+        let mut summary = RelaySummary {
+            delivered: 0,
+            pending: pending.len(),
+        };
+        for entry in pending {
+            if !self.deliver(&entry)? {
+                break;
+            }
+            self.outbox
+                .mark_delivered(entry.sequence, SystemClock.now())?;
+            summary.delivered += 1;
+            summary.pending -= 1;
+        }
+        Ok(summary)
+    }
+
+    fn deliver(&self, entry: &OutboxEntry) -> rusqlite::Result<bool> {
+        let mut recorded = Ok(());
+        let delivered = self.backoff.retry(|_| {
+            let response = self
+                .agent
+                .post(&self.endpoint)
+                .set("Content-Type", "application/json")
+                .set("Idempotency-Key", &entry.sequence.to_string())
+                .set("X-Event", &entry.event)
+                .send_string(&entry.payload);
+            if response.is_err() && recorded.is_ok() {
+                recorded = self.outbox.record_attempt(entry.sequence);
+            }
+            response.is_ok()
+        });
+        recorded.map(|_| delivered)
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    collections::HashSet,
+    io,
+    net::{SocketAddr, TcpListener},
+    thread::{self, JoinHandle},
+};
+
+use csv::StringRecord;
+use tokio::{
+    runtime::Builder,
+    sync::{mpsc, oneshot},
+    task,
+};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{transport::Server, Request, Response, Status, Streaming};
+
+use super::{AppHandle, Outcome, Update};
+use crate::models::{
+    account::{BalanceView, RejectedTransaction},
+    transaction::{ClientID, Transaction},
+};
+
+pub mod proto {
+    tonic::include_proto!("integrator");
+}
+
+use proto::integrator_server::{Integrator, IntegratorServer};
+
+// How many balance updates a watcher can fall behind before it's dropped.
+const WATCH_BUFFER: usize = 256;
+
+pub fn rejection_reason(rejection: &RejectedTransaction) -> proto::RejectionReason {
+    match rejection {
+        RejectedTransaction::InvalidType => proto::RejectionReason::InvalidType,
+        RejectedTransaction::InsufficientFunds => proto::RejectionReason::InsufficientFunds,
+        RejectedTransaction::IDNotFound => proto::RejectionReason::IdNotFound,
+        RejectedTransaction::InconsistentWithValueHeld => {
+            proto::RejectionReason::InconsistentWithValueHeld
+        }
+        RejectedTransaction::InvalidInput => proto::RejectionReason::InvalidInput,
+        RejectedTransaction::TargetTransactionAmountMissing => {
+            proto::RejectionReason::TargetTransactionAmountMissing
+        }
+        RejectedTransaction::AccountLocked => proto::RejectionReason::AccountLocked,
+        RejectedTransaction::RateNotFound => proto::RejectionReason::RateNotFound,
+        RejectedTransaction::CreditLimitExceeded => proto::RejectionReason::CreditLimitExceeded,
+        RejectedTransaction::LimitExceeded { .. } => proto::RejectionReason::LimitExceeded,
+        RejectedTransaction::ClientMismatch => proto::RejectionReason::ClientMismatch,
+        RejectedTransaction::RefundExceedsWithdrawal => {
+            proto::RejectionReason::RefundExceedsWithdrawal
+        }
+        RejectedTransaction::DisputeWindowExpired => proto::RejectionReason::DisputeWindowExpired,
+        RejectedTransaction::StorageFailed => proto::RejectionReason::StorageFailed,
+    }
+}
+
+// The record the CSV row of the transaction would make, so it gets parsed as any other.
+fn record(transaction: &proto::Transaction) -> StringRecord {
+    let timestamp = match transaction.timestamp {
+        0 => String::new(),
+        value => value.to_string(),
+    };
+    StringRecord::from(vec![
+        transaction.r#type.clone(),
+        transaction.client.to_string(),
+        transaction.tx.to_string(),
+        transaction.amount.clone(),
+        transaction.currency.clone(),
+        timestamp,
+    ])
+}
+
+fn transaction_message(transaction: &Transaction) -> proto::Transaction {
+    proto::Transaction {
+        r#type: transaction.kind.name().to_string(),
+        client: transaction.client_id.into(),
+        tx: transaction.id,
+        amount: transaction
+            .amount
+            .map(|amount| amount.to_string())
+            .unwrap_or_default(),
+        currency: transaction.currency.clone(),
+        timestamp: transaction.timestamp.unwrap_or_default(),
+    }
+}
+
+fn account_message(account: &BalanceView) -> proto::Account {
+    proto::Account {
+        client: account.client_id.into(),
+        locked: account.locked,
+        balances: account
+            .currencies()
+            .into_iter()
+            .map(|currency| {
+                let balance = account.balance(&currency);
+                proto::Balance {
+                    currency,
+                    available: balance.available().to_string(),
+                    held: balance.held().to_string(),
+                    total: balance.total().to_string(),
+                }
+            })
+            .collect(),
+    }
+}
+
+fn outcome_message(tx: u32, outcome: Outcome) -> proto::Outcome {
+    match outcome {
+        Ok((_, account)) => proto::Outcome {
+            tx,
+            accepted: true,
+            reason: proto::RejectionReason::Unspecified.into(),
+            account: Some(account_message(&account)),
+        },
+        Err(rejection) => proto::Outcome {
+            tx,
+            accepted: false,
+            reason: rejection_reason(&rejection).into(),
+            account: None,
+        },
+    }
+}
+
+// The app handle asks and waits for the answer, which is no work for the async runtime.
+async fn ask<R: Send + 'static>(
+    app: &AppHandle,
+    question: impl FnOnce(AppHandle) -> R + Send + 'static,
+) -> Result<R, Status> {
+    let app = app.clone();
+    task::spawn_blocking(move || question(app))
+        .await
+        .map_err(|_| Status::internal("The app stopped serving"))
+}
+
+#[derive(Debug, Clone)]
+pub struct GrpcService {
+    app: AppHandle,
+}
+
+impl GrpcService {
+    pub fn new(app: AppHandle) -> Self {
+        Self { app }
+    }
+}
+
+#[tonic::async_trait]
+impl Integrator for GrpcService {
+    async fn submit_transactions(
+        &self,
+        request: Request<Streaming<proto::Transaction>>,
+    ) -> Result<Response<proto::Submission>, Status> {
+        let mut transactions = request.into_inner();
+        let mut submission = proto::Submission::default();
+        while let Some(transaction) = transactions.message().await? {
+            let record = record(&transaction);
+            let outcome = ask(&self.app, move |app| app.process_record(record)).await?;
+            let outcome = outcome_message(transaction.tx, outcome);
+            if outcome.accepted {
+                submission.accepted += 1;
+            } else {
+                submission.rejected += 1;
+            }
+            submission.outcomes.push(outcome);
+        }
+        Ok(Response::new(submission))
+    }
+
+    async fn get_balance(
+        &self,
+        request: Request<proto::BalanceRequest>,
+    ) -> Result<Response<proto::Account>, Status> {
+        let client_id = ClientID::try_from(request.into_inner().client)
+            .map_err(|_| Status::invalid_argument("Invalid client"))?;
+        match ask(&self.app, move |app| app.account(client_id)).await? {
+            None => Err(Status::not_found("No account for the client")),
+            Some(account) => Ok(Response::new(account_message(&account))),
+        }
+    }
+
+    type WatchBalancesStream = ReceiverStream<Result<proto::BalanceUpdate, Status>>;
+
+    // Updates are forwarded from a thread of their own, which ends with the first update
+    // after the watcher went away or fell behind, closing its stream.
+    async fn watch_balances(
+        &self,
+        request: Request<proto::WatchRequest>,
+    ) -> Result<Response<Self::WatchBalancesStream>, Status> {
+        let clients: HashSet<ClientID> = request
+            .into_inner()
+            .clients
+            .into_iter()
+            .filter_map(|client| ClientID::try_from(client).ok())
+            .collect();
+        let updates = self.app.subscribe();
+        let (sender, receiver) = mpsc::channel(WATCH_BUFFER);
+        thread::spawn(move || {
+            for update in updates {
+                let (transaction, account) = match update {
+                    Update::Balance {
+                        transaction,
+                        account,
+                    } => (transaction, account),
+                    Update::Rejected { .. } => continue,
+                };
+                if !clients.is_empty() && !clients.contains(&account.client_id) {
+                    continue;
+                }
+                let update = proto::BalanceUpdate {
+                    transaction: Some(transaction_message(&transaction)),
+                    account: Some(account_message(&account)),
+                };
+                if sender.try_send(Ok(update)).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+// Serves the gRPC service from a runtime on a thread of its own, until stopped or dropped.
+#[derive(Debug)]
+pub struct GrpcServer {
+    address: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    worker: JoinHandle<()>,
+}
+
+impl GrpcServer {
+    // Listens on the address, an `:0` port picks any free one.
+    pub fn start(address: &str, app: AppHandle) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?;
+        let runtime = Builder::new_multi_thread().enable_all().build()?;
+        let (shutdown, signal) = oneshot::channel::<()>();
+        let worker = thread::spawn(move || {
+            runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener)
+                    .expect("Failed to listen for gRPC connections");
+                Server::builder()
+                    .add_service(IntegratorServer::new(GrpcService::new(app)))
+                    .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                        let _ = signal.await;
+                    })
+                    .await
+                    .expect("The gRPC server failed");
+            })
+        });
+        Ok(Self {
+            address,
+            shutdown,
+            worker,
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    // Stops taking calls and waits for the ones being served.
+    pub fn stop(self) {
+        let _ = self.shutdown.send(());
+        let _ = self.worker.join();
+    }
+}
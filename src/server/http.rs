@@ -0,0 +1,374 @@
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use csv::StringRecord;
+use serde_json::{json, Value};
+
+use super::{websocket, AppHandle, Outcome};
+use crate::{
+    csv::get_transactions_reader,
+    models::{
+        account::RejectedTransaction,
+        json::{account_json, transaction_json},
+        transaction::ClientID,
+    },
+};
+
+// The columns of a transaction record, named as in the header of the CSV input.
+const COLUMNS: [&str; 6] = ["type", "client", "tx", "amount", "currency", "timestamp"];
+
+// How long the request line and the headers can be, in bytes.
+const MAX_HEAD: u64 = 64 * 1024;
+
+// The HTTP status telling why a transaction got rejected.
+pub fn status_code(rejection: &RejectedTransaction) -> u16 {
+    match rejection {
+        RejectedTransaction::InvalidType | RejectedTransaction::InvalidInput => 400,
+        RejectedTransaction::IDNotFound => 404,
+        RejectedTransaction::AccountLocked => 423,
+        _ => 422,
+    }
+}
+
+// A local HTTP API over the app:
+//
+//     POST /transactions      one transaction or a batch, as CSV or JSON
+//     GET  /accounts          the balances of every account
+//     GET  /accounts/{client} the balances of an account
+//     GET  /updates           a WebSocket pushing balance updates and rejections
+//
+// Each connection gets one answer and is closed, but for the WebSockets which stay open on a
+// thread of their own. Connections are served by as many threads as given.
+pub struct HttpServer {
+    address: SocketAddr,
+    stopping: Arc<AtomicBool>,
+    acceptor: JoinHandle<()>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl HttpServer {
+    // Listens on the address, an `:0` port picks any free one, with as many threads serving requests.
+    pub fn start(address: &str, app: AppHandle, threads: usize) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let stopping = Arc::new(AtomicBool::new(false));
+        let (connections, queue) = mpsc::channel::<TcpStream>();
+        let queue = Arc::new(Mutex::new(queue));
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let queue = queue.clone();
+                let app = app.clone();
+                thread::spawn(move || loop {
+                    let stream = match queue.lock().expect("Connection queue poisoned").recv() {
+                        Ok(stream) => stream,
+                        Err(_) => break,
+                    };
+                    // The client may hang up any time, there is no one to tell
+                    let _ = handle(stream, &app);
+                })
+            })
+            .collect();
+        let acceptor = {
+            let stopping = stopping.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopping.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    // A connection that failed to open has no one to answer
+                    if let Ok(stream) = stream {
+                        let _ = connections.send(stream);
+                    }
+                }
+            })
+        };
+        Ok(Self {
+            address,
+            stopping,
+            acceptor,
+            workers,
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    // Stops taking requests and waits for the ones being served.
+    pub fn stop(self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        // The listener only looks at the flag once a connection wakes it up
+        let _ = TcpStream::connect(self.address);
+        let _ = self.acceptor.join();
+        for worker in self.workers {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl fmt::Debug for HttpServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HttpServer")
+            .field("address", &self.address)
+            .finish()
+    }
+}
+
+// A request as far as the API looks at it.
+pub struct Request {
+    pub method: String,
+    // The path along with the query.
+    pub target: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+}
+
+// Reads the request line, the headers and a body of the given `Content-Length`.
+// Ill-formed requests answer `None`, as do chunked bodies which aren't taken.
+fn read_request(stream: &mut TcpStream) -> io::Result<Option<Request>> {
+    let mut reader = BufReader::new(stream.try_clone()?).take(MAX_HEAD);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method.to_string(), target.to_string())
+        }
+        _ => return Ok(None),
+    };
+    let mut headers = vec![];
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        match line.split_once(':') {
+            Some((field, value)) => {
+                headers.push((field.trim().to_string(), value.trim().to_string()))
+            }
+            None => return Ok(None),
+        }
+    }
+    let mut request = Request {
+        method,
+        target,
+        headers,
+        body: vec![],
+    };
+    if request.header("Transfer-Encoding").is_some() {
+        return Ok(None);
+    }
+    let length = match request.header("Content-Length").map(str::parse::<u64>) {
+        None => 0,
+        Some(Ok(length)) => length,
+        Some(Err(_)) => return Ok(None),
+    };
+    if length > 0 {
+        if request
+            .header("Expect")
+            .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+        {
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        }
+        let mut reader = reader.into_inner();
+        reader
+            .by_ref()
+            .take(length)
+            .read_to_end(&mut request.body)?;
+        if request.body.len() as u64 != length {
+            return Ok(None);
+        }
+    }
+    Ok(Some(request))
+}
+
+// Writes a response with the JSON body and closes the connection.
+pub fn respond(mut stream: TcpStream, status: u16, body: &Value) -> io::Result<()> {
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason_phrase(status),
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+// The error answered, as `{"error": ...}`.
+pub fn respond_error(stream: TcpStream, status: u16, error: &str) -> io::Result<()> {
+    respond(stream, status, &error_json(error))
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        422 => "Unprocessable Entity",
+        423 => "Locked",
+        426 => "Upgrade Required",
+        _ => "",
+    }
+}
+
+fn handle(mut stream: TcpStream, app: &AppHandle) -> io::Result<()> {
+    let request = match read_request(&mut stream)? {
+        Some(request) => request,
+        None => return respond_error(stream, 400, "InvalidRequest"),
+    };
+    let path = request.path().to_string();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let (status, body) = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["updates"]) => return websocket::watch(request, stream, app),
+        ("POST", ["transactions"]) => post_transactions(&request, app),
+        ("GET", ["accounts"]) => {
+            let accounts: Vec<Value> = app.accounts().iter().map(account_json).collect();
+            (200, json!(accounts))
+        }
+        ("GET", ["accounts", client]) => match client.parse::<ClientID>() {
+            Err(_) => (400, error_json("InvalidClient")),
+            Ok(client_id) => match app.account(client_id) {
+                None => (404, error_json("AccountNotFound")),
+                Some(account) => (200, account_json(&account)),
+            },
+        },
+        (_, ["transactions"]) | (_, ["accounts", ..]) | (_, ["updates"]) => {
+            (405, error_json("MethodNotAllowed"))
+        }
+        _ => (404, error_json("NotFound")),
+    };
+    respond(stream, status, &body)
+}
+
+// A single transaction answers with the status of its outcome,
+// a batch with 200 and the outcome of each transaction in order.
+fn post_transactions(request: &Request, app: &AppHandle) -> (u16, Value) {
+    let is_json = request
+        .header("Content-Type")
+        .is_some_and(|content_type| content_type.contains("json"));
+    let body = match std::str::from_utf8(&request.body) {
+        Ok(body) => body,
+        Err(_) => return (400, error_json("UnreadableBody")),
+    };
+    let parsed = if is_json {
+        json_records(body)
+    } else {
+        csv_records(body)
+    };
+    let (records, batch) = match parsed {
+        None => return (400, error_json("InvalidBody")),
+        Some((records, _)) if records.is_empty() => return (400, error_json("NoTransactions")),
+        Some(value) => value,
+    };
+    let outcomes: Vec<(u16, Value)> = records
+        .into_iter()
+        .map(|record| outcome_json(app.process_record(record)))
+        .collect();
+    if !batch {
+        return outcomes.into_iter().next().expect("A single outcome");
+    }
+    let results: Vec<Value> = outcomes
+        .into_iter()
+        .map(|(status, mut value)| {
+            value["code"] = json!(status);
+            value
+        })
+        .collect();
+    (200, json!(results))
+}
+
+fn outcome_json(outcome: Outcome) -> (u16, Value) {
+    match outcome {
+        Ok((transaction, account)) => (
+            200,
+            json!({
+                "status": "accepted",
+                "transaction": transaction_json(&transaction),
+                "account": account_json(&account),
+            }),
+        ),
+        Err(rejection) => (
+            status_code(&rejection),
+            json!({
+                "status": "rejected",
+                "reason": rejection.reason(),
+            }),
+        ),
+    }
+}
+
+fn error_json(error: &str) -> Value {
+    json!({ "error": error })
+}
+
+// An object is a single transaction and an array a batch of them.
+fn json_records(body: &str) -> Option<(Vec<StringRecord>, bool)> {
+    match serde_json::from_str::<Value>(body).ok()? {
+        Value::Array(values) => {
+            let records = values.iter().map(json_record).collect::<Option<_>>()?;
+            Some((records, true))
+        }
+        value => Some((vec![json_record(&value)?], false)),
+    }
+}
+
+// The record the CSV row of the transaction would make, numbers and strings alike.
+// Amounts must be strings, as a JSON number could have lost digits going through a float.
+fn json_record(value: &Value) -> Option<StringRecord> {
+    let object = value.as_object()?;
+    let fields = COLUMNS
+        .iter()
+        .map(|column| match object.get(*column) {
+            None | Some(Value::Null) => Some(String::new()),
+            Some(Value::String(text)) => Some(text.clone()),
+            Some(Value::Number(_)) if *column == "amount" => None,
+            Some(Value::Number(number)) => Some(number.to_string()),
+            Some(_) => None,
+        })
+        .collect::<Option<Vec<String>>>()?;
+    Some(StringRecord::from(fields))
+}
+
+// Rows as in the CSV input, the header being optional. More than one row is a batch.
+fn csv_records(body: &str) -> Option<(Vec<StringRecord>, bool)> {
+    let mut reader = get_transactions_reader(body.as_bytes());
+    let mut records = vec![];
+    for (index, record) in reader.records().enumerate() {
+        let record = record.ok()?;
+        if index == 0 && record.get(0) == Some("type") {
+            continue;
+        }
+        records.push(record);
+    }
+    let batch = records.len() > 1;
+    Some((records, batch))
+}
@@ -0,0 +1,185 @@
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+
+use csv::StringRecord;
+
+use crate::{
+    clock::Clock,
+    models::{
+        account::{BalanceView, RejectedTransaction, Result},
+        output::OutputSink,
+        stores::{AccountStore, TransactionStore},
+        transaction::{ClientID, Transaction, TransactionID},
+    },
+    processor::Processor,
+};
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod http;
+pub mod tcp;
+pub mod websocket;
+
+// How many updates a subscriber can fall behind before the app drops it.
+pub const SUBSCRIBER_BUFFER: usize = 256;
+
+// What processing a record gave: the transaction and the balances it left, or why it was rejected.
+pub type Outcome = Result<(Transaction, BalanceView)>;
+
+// What the subscribers of the app learn as records get processed.
+#[derive(Debug, Clone)]
+pub enum Update {
+    // An accepted transaction, or a dispute it made age, and the balances it left.
+    Balance {
+        transaction: Transaction,
+        account: Box<BalanceView>,
+    },
+    // The ids come from the record as far as they could be read.
+    Rejected {
+        client_id: Option<ClientID>,
+        id: Option<TransactionID>,
+        reason: RejectedTransaction,
+    },
+}
+
+impl Update {
+    pub fn client_id(&self) -> Option<ClientID> {
+        match self {
+            Self::Balance { account, .. } => Some(account.client_id),
+            Self::Rejected { client_id, .. } => *client_id,
+        }
+    }
+}
+
+// What connections ask the app, along with where to send the answer.
+#[derive(Debug)]
+pub enum Command {
+    Process(StringRecord, Sender<Outcome>),
+    Account(ClientID, Sender<Option<BalanceView>>),
+    Accounts(Sender<Vec<BalanceView>>),
+    Subscribe(SyncSender<Update>),
+}
+
+// Lets any thread use the app, which stays on the thread serving it so its stores don't need to be shared.
+// Commands are answered one at a time in the order they arrive.
+#[derive(Debug, Clone)]
+pub struct AppHandle {
+    commands: Sender<Command>,
+}
+
+impl AppHandle {
+    pub fn process_record(&self, record: StringRecord) -> Outcome {
+        self.ask(|reply| Command::Process(record, reply))
+    }
+
+    pub fn account(&self, client_id: ClientID) -> Option<BalanceView> {
+        self.ask(|reply| Command::Account(client_id, reply))
+    }
+
+    // Every account, sorted by client.
+    pub fn accounts(&self) -> Vec<BalanceView> {
+        self.ask(Command::Accounts)
+    }
+
+    // The updates of every record processed from now on, until the receiver is dropped or falls
+    // more than `SUBSCRIBER_BUFFER` updates behind.
+    pub fn subscribe(&self) -> Receiver<Update> {
+        let (updates, receiver) = mpsc::sync_channel(SUBSCRIBER_BUFFER);
+        self.commands
+            .send(Command::Subscribe(updates))
+            .expect("The app stopped serving");
+        receiver
+    }
+
+    fn ask<R>(&self, command: impl FnOnce(Sender<R>) -> Command) -> R {
+        let (reply, answer) = mpsc::channel();
+        self.commands
+            .send(command(reply))
+            .expect("The app stopped serving");
+        answer.recv().expect("The app stopped serving")
+    }
+}
+
+// A handle to give the connections, and the commands they send to pass to `serve`.
+pub fn channel() -> (AppHandle, Receiver<Command>) {
+    let (commands, receiver) = mpsc::channel();
+    (AppHandle { commands }, receiver)
+}
+
+// Answers the commands of the handles until all of them are dropped.
+// Records are processed as in a batch run, their rows going to the output sink of the app.
+pub fn serve<C: Clock, A: AccountStore, T: TransactionStore, O: OutputSink>(
+    processor: &mut Processor<C, A, T, O>,
+    commands: Receiver<Command>,
+) {
+    let mut subscribers: Vec<SyncSender<Update>> = vec![];
+    for command in commands {
+        // A connection gone before its answer doesn't need it
+        match command {
+            Command::Process(record, reply) => {
+                let ids = (
+                    record.get(1).and_then(|value| value.trim().parse().ok()),
+                    record.get(2).and_then(|value| value.trim().parse().ok()),
+                );
+                let (result, aged) = processor.process_record(record);
+                let app = processor.app();
+                let outcome = result.and_then(|transaction| {
+                    let account = app.get_account(transaction.client_id)?;
+                    Ok((transaction, account.view()))
+                });
+                if !subscribers.is_empty() {
+                    let mut updates = vec![];
+                    match &outcome {
+                        Ok((transaction, account)) => updates.push(Update::Balance {
+                            transaction: transaction.clone(),
+                            account: Box::new(account.clone()),
+                        }),
+                        Err(reason) => updates.push(Update::Rejected {
+                            client_id: ids.0,
+                            id: ids.1,
+                            reason: reason.clone(),
+                        }),
+                    }
+                    for transaction in aged {
+                        if let Ok(account) = app.get_account(transaction.client_id) {
+                            updates.push(Update::Balance {
+                                transaction,
+                                account: Box::new(account.view()),
+                            });
+                        }
+                    }
+                    // Subscribers gone, or too slow to keep up, are forgotten rather than waited for
+                    subscribers.retain(|subscriber| {
+                        updates
+                            .iter()
+                            .all(|update| subscriber.try_send(update.clone()).is_ok())
+                    });
+                }
+                let _ = reply.send(outcome);
+            }
+            Command::Account(client_id, reply) => {
+                let account = processor
+                    .app()
+                    .get_account(client_id)
+                    .ok()
+                    .map(|account| account.view());
+                let _ = reply.send(account);
+            }
+            Command::Accounts(reply) => {
+                let app = processor.app();
+                // Accounts that can't be read are left out
+                let mut client_ids = app.accounts.client_ids().unwrap_or_default();
+                client_ids.sort();
+                let accounts = client_ids
+                    .into_iter()
+                    .filter_map(|client_id| {
+                        app.get_account(client_id)
+                            .ok()
+                            .map(|account| account.view())
+                    })
+                    .collect();
+                let _ = reply.send(accounts);
+            }
+            Command::Subscribe(updates) => subscribers.push(updates),
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    mem,
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use csv::StringRecord;
+
+use super::{AppHandle, Outcome};
+use crate::{csv::get_transactions_reader, models::transaction::TransactionID};
+
+// The connections open, to close them when the server stops.
+type Connections = Arc<Mutex<Vec<(TcpStream, JoinHandle<()>)>>>;
+
+// A line-oriented protocol for feeders: each CSV row sent, as in the input file, gets an ACK line.
+//
+//     ok,<tx>,<client>,<currency>,<available>,<held>,<total>,<locked>
+//     rejected,<tx>,<reason>
+//
+// Accepted rows answer the balance of the account in the currency of the transaction. The tx of
+// a rejection is empty when the row doesn't bring a valid one. Blank lines and the header row
+// get no ACK. Every connection is served from a thread of its own, all of them into the same app.
+pub struct TcpServer {
+    address: SocketAddr,
+    stopping: Arc<AtomicBool>,
+    connections: Connections,
+    worker: JoinHandle<()>,
+}
+
+impl TcpServer {
+    // Listens on the address, an `:0` port picks any free one.
+    pub fn start(address: &str, app: AppHandle) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let stopping = Arc::new(AtomicBool::new(false));
+        let connections: Connections = Arc::default();
+        let worker = {
+            let stopping = stopping.clone();
+            let connections = connections.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopping.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    // A connection that failed to open has no one to answer
+                    let (stream, peer) = match stream.and_then(|stream| {
+                        let peer = stream.try_clone()?;
+                        Ok((stream, peer))
+                    }) {
+                        Ok(streams) => streams,
+                        Err(_) => continue,
+                    };
+                    let app = app.clone();
+                    let connection = thread::spawn(move || {
+                        // The feeder may hang up any time, there is no one to tell
+                        let _ = feed(stream, &app);
+                    });
+                    let mut connections = connections.lock().expect("Connections poisoned");
+                    connections.retain(|(_, connection)| !connection.is_finished());
+                    connections.push((peer, connection));
+                }
+            })
+        };
+        Ok(Self {
+            address,
+            stopping,
+            connections,
+            worker,
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    // Stops taking connections and closes the open ones, waiting for the rows being processed.
+    pub fn stop(self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        // The listener only looks at the flag once a connection wakes it up
+        let _ = TcpStream::connect(self.address);
+        let _ = self.worker.join();
+        let connections = mem::take(&mut *self.connections.lock().expect("Connections poisoned"));
+        for (stream, connection) in connections {
+            let _ = stream.shutdown(Shutdown::Both);
+            let _ = connection.join();
+        }
+    }
+}
+
+impl fmt::Debug for TcpServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TcpServer")
+            .field("address", &self.address)
+            .finish()
+    }
+}
+
+// Answers every row of the connection until the feeder closes it.
+fn feed(stream: TcpStream, app: &AppHandle) -> io::Result<()> {
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    for line in BufReader::new(stream).lines() {
+        let record = match line_record(&line?) {
+            Some(record) => record,
+            None => continue,
+        };
+        let id = record
+            .get(2)
+            .and_then(|value| value.trim().parse::<TransactionID>().ok());
+        writeln!(writer, "{}", ack(id, app.process_record(record)))?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+// The record of a row, parsed as the rows of the input file are.
+fn line_record(line: &str) -> Option<StringRecord> {
+    let mut reader = get_transactions_reader(line.as_bytes());
+    let record = match reader.records().next()? {
+        Ok(record) => record,
+        // What the CSV reader can't make sense of still gets an ACK
+        Err(_) => StringRecord::from(vec![line]),
+    };
+    if record.get(0) == Some("type") {
+        return None;
+    }
+    Some(record)
+}
+
+fn ack(id: Option<TransactionID>, outcome: Outcome) -> String {
+    match outcome {
+        Ok((transaction, account)) => {
+            let balance = account.balance(&transaction.currency);
+            format!(
+                "ok,{},{},{},{},{},{},{}",
+                transaction.id,
+                account.client_id,
+                transaction.currency,
+                balance.available(),
+                balance.held(),
+                balance.total(),
+                account.locked
+            )
+        }
+        Err(rejection) => format!(
+            "rejected,{},{}",
+            id.map(|id| id.to_string()).unwrap_or_default(),
+            rejection.reason()
+        ),
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    collections::HashSet,
+    io::{self, Write},
+    net::TcpStream,
+    sync::mpsc::{Receiver, TryRecvError},
+    thread,
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+use tungstenite::{handshake::derive_accept_key, protocol::Role, Error, Message, WebSocket};
+
+use super::{
+    http::{respond_error, Request},
+    AppHandle, Update,
+};
+use crate::models::{
+    json::{account_json, transaction_json},
+    transaction::ClientID,
+};
+
+// How long the dashboard is waited for between the updates being pushed.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// A dashboard not taking what is pushed for this long is let go.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The JSON pushed to dashboards for an update.
+pub fn update_json(update: &Update) -> Value {
+    match update {
+        Update::Balance {
+            transaction,
+            account,
+        } => json!({
+            "event": "balance",
+            "transaction": transaction_json(transaction),
+            "account": account_json(account),
+        }),
+        Update::Rejected {
+            client_id,
+            id,
+            reason,
+        } => json!({
+            "event": "rejected",
+            "client": client_id,
+            "tx": id,
+            "reason": reason.reason(),
+        }),
+    }
+}
+
+// The clients of a `clients=1,2` query, none meaning every client.
+fn watched_clients(url: &str) -> Option<HashSet<ClientID>> {
+    let query = match url.split_once('?') {
+        Some((_, query)) => query,
+        None => return Some(HashSet::new()),
+    };
+    let mut clients = HashSet::new();
+    for (name, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        if name != "clients" {
+            continue;
+        }
+        for client in value.split(',').filter(|client| !client.is_empty()) {
+            clients.insert(client.parse().ok()?);
+        }
+    }
+    Some(clients)
+}
+
+fn is_watched(clients: &HashSet<ClientID>, update: &Update) -> bool {
+    clients.is_empty()
+        || update
+            .client_id()
+            .is_some_and(|client_id| clients.contains(&client_id))
+}
+
+// Upgrades the request to a WebSocket pushing the updates of the watched clients from then on.
+// Updates are pushed from a thread of their own, which also answers the pings and the close of
+// the dashboard. It ends once the dashboard goes away, or falls too far behind.
+pub fn watch(request: Request, mut stream: TcpStream, app: &AppHandle) -> io::Result<()> {
+    let clients = match watched_clients(&request.target) {
+        Some(clients) => clients,
+        None => return respond_error(stream, 400, "InvalidClient"),
+    };
+    let is_websocket = request
+        .header("Upgrade")
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) if is_websocket => key,
+        _ => return respond_error(stream, 426, "WebSocketExpected"),
+    };
+    // Subscribing before answering, so the dashboard misses nothing processed once connected
+    let updates = app.subscribe();
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes())
+    )?;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    thread::spawn(move || {
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+        // The dashboard may be gone already, there is no one to tell
+        if push(&mut socket, &updates, &clients) {
+            let _ = socket.close(None);
+        }
+        // Sends the close, ours or the answer to the one of the dashboard
+        let _ = socket.flush();
+    });
+    Ok(())
+}
+
+// Pushes the updates as they come, looking at what the dashboard sent in between.
+// Answers whether the WebSocket is still open once done: the app dropped the subscription,
+// the dashboard closed the WebSocket or it went away.
+fn push(
+    socket: &mut WebSocket<TcpStream>,
+    updates: &Receiver<Update>,
+    clients: &HashSet<ClientID>,
+) -> bool {
+    loop {
+        loop {
+            let update = match updates.try_recv() {
+                Ok(update) => update,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return true,
+            };
+            if !is_watched(clients, &update) {
+                continue;
+            }
+            let message = Message::Text(update_json(&update).to_string());
+            if socket.send(message).is_err() {
+                return false;
+            }
+        }
+        // Waits up to the read timeout for a frame. Pings get their pong queued, and a close
+        // its answer, both sent by the next flush.
+        match socket.read() {
+            Ok(Message::Close(_)) => return false,
+            Ok(_) => {}
+            Err(Error::Io(error))
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(_) => return false,
+        }
+        if socket.flush().is_err() {
+            return false;
+        }
+    }
+}
@@ -1,12 +1,39 @@
+// The baseline tests are kept as they were first written
+#![allow(clippy::assertions_on_constants, clippy::single_match)]
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::TcpStream,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
 use csv::StringRecord;
 use fraction::Decimal;
 
 use crate::{
+    builder::AppBuilder,
     cli::get_command,
     csv::get_transactions_iter,
     models::{
-        transaction::{Transaction, TransactionType},
+        account::RejectedTransaction,
+        clients::CreditLimit,
+        cold_storage::ColdStorage,
+        compact::{CompactTransaction, CurrencyCodes},
+        fees::FeeRule,
+        limits::{LimitRule, LimitRules},
+        output::{MemorySink, OutputSink},
+        rates::{RoundingMode, RoundingRule},
+        sqlite::{self, Outbox},
+        stores::TransactionStore,
+        transaction::{Arrival, Timestamp, Transaction, TransactionType, DEFAULT_CURRENCY},
+        transactions::Transactions,
     },
+    processor::Processor,
+    relay::{Backoff, OutboxRelay},
+    server::{self, http::HttpServer, tcp::TcpServer},
+    webhooks::{sign, DeliveryLog, Webhook, WebhookDispatcher},
 };
 
 #[test]
@@ -71,3 +98,893 @@ fn can_parse_a_withdrawal_command() {
     }
 }
 
+#[test]
+fn can_parse_an_optional_currency_column() {
+    let record = StringRecord::from(vec!["deposit", "1", "1", "1.0", " eur "]);
+    let tx = Transaction::from_record(record).unwrap();
+    assert_eq!(tx.currency, "EUR");
+    let record = StringRecord::from(vec!["deposit", "1", "2", "1.0"]);
+    let tx = Transaction::from_record(record).unwrap();
+    assert_eq!(tx.currency, DEFAULT_CURRENCY);
+    let record = StringRecord::from(vec!["deposit", "1", "3", "1.0", "EURO"]);
+    let tx = Transaction::from_record(record);
+    assert!(matches!(tx, Err(RejectedTransaction::InvalidInput)));
+}
+
+#[test]
+fn can_parse_a_convert_command() {
+    let record = StringRecord::from(vec!["convert", "1", "7", "2.5", "eur/usd"]);
+    let tx = Transaction::from_record(record).unwrap();
+    assert_eq!(tx.kind, TransactionType::Convert);
+    assert_eq!(tx.currency, "EUR");
+    assert_eq!(tx.counter_currency, Some("USD".to_string()));
+    assert_eq!(tx.amount.unwrap(), Decimal::from(2.5));
+    let record = StringRecord::from(vec!["convert", "1", "8", "2.5", "EUR"]);
+    let tx = Transaction::from_record(record);
+    assert!(matches!(tx, Err(RejectedTransaction::InvalidInput)));
+}
+
+#[test]
+fn rounding_rules_round_converted_amounts_per_mode() {
+    let half_even = RoundingRule::new(2, RoundingMode::HalfEven);
+    assert_eq!(half_even.apply(Decimal::from(1.125)), Decimal::from(1.12));
+    assert_eq!(half_even.apply(Decimal::from(1.135)), Decimal::from(1.14));
+    let half_up = RoundingRule::new(2, RoundingMode::HalfUp);
+    assert_eq!(half_up.apply(Decimal::from(1.125)), Decimal::from(1.13));
+    let down = RoundingRule::new(0, RoundingMode::Down);
+    assert_eq!(down.apply(Decimal::from(1577.9)), Decimal::from(1577));
+}
+
+#[test]
+fn rounding_rules_reject_scales_too_large_for_amounts() {
+    let record = StringRecord::from(vec!["EUR", "9", "half-even"]);
+    let (_, rule) = RoundingRule::from_record(&record).unwrap();
+    assert_eq!(
+        rule.apply(Decimal::from(1_000_000.125)),
+        Decimal::from(1_000_000.125)
+    );
+    let record = StringRecord::from(vec!["EUR", "20", "half-even"]);
+    assert!(RoundingRule::from_record(&record).is_none());
+}
+
+#[test]
+fn can_parse_a_fee_rule_and_apply_its_caps() {
+    let record = StringRecord::from(vec!["withdrawal", "0.5", "1.0", "1.0", "10"]);
+    let (kind, currency, rule) = FeeRule::from_record(&record).unwrap();
+    assert_eq!(currency, DEFAULT_CURRENCY);
+    assert_eq!(kind, TransactionType::Withdrawal);
+    assert_eq!(rule.fee_for(Decimal::from(10)), Decimal::from(1));
+    assert_eq!(rule.fee_for(Decimal::from(100)), Decimal::from(1.5));
+    assert_eq!(rule.fee_for(Decimal::from(5000)), Decimal::from(10));
+    let record = StringRecord::from(vec!["chargeback", "15", "", "", "", "eur"]);
+    let (_, currency, rule) = FeeRule::from_record(&record).unwrap();
+    assert_eq!(currency, "EUR");
+    assert_eq!(rule.fee_for(Decimal::from(10)), Decimal::from(15));
+    let record = StringRecord::from(vec!["deposit", "1", "", "", ""]);
+    assert!(FeeRule::from_record(&record).is_none());
+}
+
+#[test]
+fn can_parse_a_client_credit_limit() {
+    let record = StringRecord::from(vec!["1", "100.0"]);
+    let credit_limit = CreditLimit::from_record(&record).unwrap();
+    assert_eq!(credit_limit.client_id, 1u16);
+    assert_eq!(credit_limit.limit, Decimal::from(100));
+    assert_eq!(credit_limit.currency, DEFAULT_CURRENCY);
+    let record = StringRecord::from(vec!["2", "50", "eur"]);
+    let credit_limit = CreditLimit::from_record(&record).unwrap();
+    assert_eq!(credit_limit.currency, "EUR");
+    let record = StringRecord::from(vec!["2", "-50"]);
+    assert!(CreditLimit::from_record(&record).is_none());
+}
+
+#[test]
+fn daily_outflow_and_time_window_limits_depend_on_when_withdrawals_happen() {
+    let mut limits = LimitRules::new();
+    limits.add_rule(LimitRule::MaxDailyOutflow {
+        currency: "USD".to_string(),
+        amount: Decimal::from(100),
+    });
+    limits.add_rule(LimitRule::MaxWithdrawalsPerWindow {
+        count: 2,
+        seconds: 60,
+    });
+    let day = 24 * 60 * 60;
+    let withdrawal = |tx: &str, amount: &str| {
+        Transaction::from_record(StringRecord::from(vec!["withdrawal", "1", tx, amount])).unwrap()
+    };
+    let tx1 = withdrawal("1", "60");
+    assert!(limits.check(&tx1, day).is_ok());
+    limits.record(&tx1, day);
+    let tx2 = withdrawal("2", "50");
+    match limits.check(&tx2, day + 10) {
+        Err(RejectedTransaction::LimitExceeded { rule }) => assert_eq!(rule, "max_daily_outflow"),
+        _ => unreachable!(),
+    }
+    let tx3 = withdrawal("3", "10");
+    limits.record(&tx3, day + 20);
+    match limits.check(&tx3, day + 30) {
+        Err(RejectedTransaction::LimitExceeded { rule }) => {
+            assert_eq!(rule, "max_withdrawals_per_window")
+        }
+        _ => unreachable!(),
+    }
+    // The next day starts a fresh outflow and the window has passed
+    assert!(limits.check(&tx2, 2 * day).is_ok());
+    // Withdrawals in other currencies don't count toward the outflow of the rule
+    let euros = Transaction::from_record(StringRecord::from(vec![
+        "withdrawal",
+        "1",
+        "4",
+        "500",
+        "EUR",
+    ]))
+    .unwrap();
+    assert!(limits.check(&euros, 2 * day + 100).is_ok());
+    let record = StringRecord::from(vec!["max_daily_outflow", "100", "eur"]);
+    assert_eq!(
+        LimitRule::from_record(&record),
+        Some(LimitRule::MaxDailyOutflow {
+            currency: "EUR".to_string(),
+            amount: Decimal::from(100)
+        })
+    );
+    let record = StringRecord::from(vec!["max_daily_outflow", "100"]);
+    assert_eq!(LimitRule::from_record(&record), None);
+    let record = StringRecord::from(vec!["max_withdrawals_per_window", "2", "60"]);
+    assert_eq!(
+        LimitRule::from_record(&record),
+        Some(LimitRule::MaxWithdrawalsPerWindow {
+            count: 2,
+            seconds: 60
+        })
+    );
+}
+
+#[test]
+fn can_parse_an_optional_timestamp_column() {
+    let record = StringRecord::from(vec!["deposit", "1", "1", "1.0", "", "1661878800"]);
+    let tx = Transaction::from_record(record).unwrap();
+    assert_eq!(tx.timestamp, Some(1661878800));
+    let record = StringRecord::from(vec!["deposit", "1", "1", "1.0", "", "2022-08-30T17:00:00Z"]);
+    let tx = Transaction::from_record(record).unwrap();
+    assert_eq!(tx.timestamp, Some(1661878800));
+    let record = StringRecord::from(vec!["deposit", "1", "1", "1.0", "", "2022-08-30 17:00:00"]);
+    let tx = Transaction::from_record(record).unwrap();
+    assert_eq!(tx.timestamp, Some(1661878800));
+    let record = StringRecord::from(vec!["deposit", "1", "1", "1.0"]);
+    let tx = Transaction::from_record(record).unwrap();
+    assert_eq!(tx.timestamp, None);
+    let record = StringRecord::from(vec!["deposit", "1", "1", "1.0", "", "2022-13-30T17:00:00Z"]);
+    let tx = Transaction::from_record(record);
+    assert!(matches!(tx, Err(RejectedTransaction::InvalidInput)));
+}
+
+#[test]
+fn arrivals_get_old_past_either_limit() {
+    let arrival = Arrival {
+        sequence: 10,
+        timestamp: 1000,
+    };
+    assert!(!arrival.is_older_than(None, None, u64::MAX, Timestamp::MAX));
+    assert!(!arrival.is_older_than(Some(5), None, 15, 5000));
+    assert!(arrival.is_older_than(Some(5), None, 16, 1000));
+    assert!(!arrival.is_older_than(None, Some(60), 100, 1060));
+    assert!(arrival.is_older_than(Some(5), Some(60), 11, 1061));
+    // Earlier times than the arrival don't make it any older
+    assert!(!arrival.is_older_than(Some(5), Some(60), 0, 0));
+}
+
+#[test]
+fn timestamps_out_of_the_calendar_range_are_invalid_input() {
+    for timestamp in [
+        "99999999999999-01-01T00:00:00Z",
+        "10000-01-01T00:00:00Z",
+        "1969-12-31T23:59:59Z",
+    ] {
+        let record = StringRecord::from(vec!["deposit", "1", "1", "10", "", timestamp]);
+        let tx = Transaction::from_record(record);
+        assert!(matches!(tx, Err(RejectedTransaction::InvalidInput)));
+    }
+    let record = StringRecord::from(vec!["deposit", "1", "1", "10", "", "9999-12-31T23:59:59Z"]);
+    assert_eq!(
+        Transaction::from_record(record).unwrap().timestamp,
+        Some(253402300799)
+    );
+}
+
+#[test]
+fn days_past_the_end_of_the_month_are_invalid_input() {
+    for timestamp in [
+        "2022-02-31T00:00:00Z",
+        "2022-04-31T00:00:00Z",
+        "2022-02-29T00:00:00Z",
+        "1900-02-29T00:00:00Z",
+    ] {
+        let record = StringRecord::from(vec!["deposit", "1", "1", "10", "", timestamp]);
+        let tx = Transaction::from_record(record);
+        assert!(matches!(tx, Err(RejectedTransaction::InvalidInput)));
+    }
+    // Leap years have a February 29th
+    for (timestamp, seconds) in [
+        ("2024-02-29T00:00:00Z", 1709164800),
+        ("2000-02-29T00:00:00Z", 951782400),
+    ] {
+        let record = StringRecord::from(vec!["deposit", "1", "1", "10", "", timestamp]);
+        assert_eq!(
+            Transaction::from_record(record).unwrap().timestamp,
+            Some(seconds)
+        );
+    }
+}
+
+#[test]
+fn can_parse_a_query_subcommand_from_command_line() {
+    let matches = get_command()
+        .try_get_matches_from([
+            "integrator",
+            "query",
+            "input_file.csv",
+            "--client",
+            "5",
+            "--at",
+            "2022-08-30T17:00:00Z",
+        ])
+        .unwrap();
+    let query = matches.subcommand_matches("query").unwrap();
+    assert_eq!(
+        query.get_one::<String>("input_filename").unwrap(),
+        "input_file.csv"
+    );
+    assert_eq!(*query.get_one::<u16>("client").unwrap(), 5u16);
+    assert_eq!(*query.get_one::<u64>("at").unwrap(), 1661878800u64);
+}
+
+#[test]
+fn can_read_back_a_transaction_spilled_to_cold_storage() {
+    let mut cold = ColdStorage::create(None).unwrap();
+    let mut withdrawal = Transaction::from_record(StringRecord::from(vec![
+        "withdrawal",
+        "3",
+        "7",
+        "12.3456",
+        "EUR",
+        "1661882400",
+    ]))
+    .unwrap();
+    withdrawal.fee = Some(Decimal::from(0.25));
+    let deposit =
+        Transaction::from_record(StringRecord::from(vec!["deposit", "3", "8", "1"])).unwrap();
+    cold.write(&withdrawal, Decimal::from(0)).unwrap();
+    cold.write(&deposit, Decimal::from(0)).unwrap();
+    assert_eq!(cold.len(), 2);
+    assert!(cold.add_refund(7, Decimal::from(2)).unwrap());
+    assert_eq!(cold.len(), 2);
+    assert_eq!(cold.read(7).unwrap(), Some((withdrawal, Decimal::from(2))));
+    assert_eq!(cold.read(8).unwrap(), Some((deposit, Decimal::from(0))));
+    assert_eq!(cold.read(100000).unwrap(), None);
+    // A clone shares the files until one changes, clearing it leaves the original alone
+    let mut copy = cold.clone();
+    copy.clear().unwrap();
+    assert_eq!(copy.read(8).unwrap(), None);
+    assert_eq!(cold.read(8).unwrap().map(|(tx, _)| tx.id), Some(8));
+    // and changing the original leaves the clone alone
+    let copy = cold.clone();
+    assert!(cold.add_refund(7, Decimal::from(1)).unwrap());
+    assert_eq!(
+        copy.read(7).unwrap().map(|(_, refunded)| refunded),
+        Some(Decimal::from(2))
+    );
+    assert_eq!(
+        cold.read(7).unwrap().map(|(_, refunded)| refunded),
+        Some(Decimal::from(3))
+    );
+    drop(copy);
+    assert!(cold.remove(7).unwrap());
+    assert!(!cold.remove(7).unwrap());
+    assert_eq!(cold.read(7).unwrap(), None);
+    assert_eq!(cold.len(), 1);
+}
+
+#[test]
+fn cold_storage_refuses_a_spill_file_that_isnt_empty() {
+    let path = std::env::temp_dir().join(format!("integrator-{}-taken.spill", std::process::id()));
+    std::fs::write(&path, "something else\n").unwrap();
+    let filename = path.to_str().unwrap().to_string();
+    let result = ColdStorage::create(Some(filename));
+    assert_eq!(
+        result.unwrap_err().kind(),
+        std::io::ErrorKind::AlreadyExists
+    );
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "something else\n");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn compact_records_keep_what_disputes_and_refunds_need_in_16_bytes() {
+    assert_eq!(std::mem::size_of::<CompactTransaction>(), 16);
+    let mut currencies = CurrencyCodes::default();
+    let withdrawal = Transaction::from_record(StringRecord::from(vec![
+        "withdrawal",
+        "3",
+        "7",
+        "12.3456",
+        "EUR",
+    ]))
+    .unwrap();
+    let compact = CompactTransaction::new(&withdrawal, &mut currencies).unwrap();
+    assert_eq!(compact.expand(7, &currencies), withdrawal);
+    assert_eq!(compact.kind(), TransactionType::Withdrawal);
+    // More decimals than the input precision can't be held exactly
+    let deposit =
+        Transaction::from_record(StringRecord::from(vec!["deposit", "3", "8", "0.00001"])).unwrap();
+    assert_eq!(CompactTransaction::new(&deposit, &mut currencies), None);
+}
+
+#[test]
+fn transactions_not_fitting_a_compact_record_are_kept_as_they_are() {
+    let mut transactions = Transactions::new();
+    for (txid, amount) in [("1", "10.5"), ("2", "0.00001")] {
+        let deposit =
+            Transaction::from_record(StringRecord::from(vec!["deposit", "4", txid, amount]))
+                .unwrap();
+        transactions.set(deposit).unwrap();
+    }
+    assert_eq!(transactions.size().unwrap(), 2);
+    assert_eq!(transactions.wide_size(), 1);
+    assert_eq!(
+        transactions.get(2).unwrap().unwrap().amount,
+        Some(Decimal::from(0.00001))
+    );
+    transactions.set_memory_budget(0, None).unwrap();
+    assert_eq!(transactions.cold_size(), 2);
+    assert_eq!(
+        transactions.get(1).unwrap().unwrap().amount,
+        Some(Decimal::from(10.5))
+    );
+}
+
+#[test]
+fn processor_runs_any_reader_and_summarizes_the_outcome() {
+    let app = AppBuilder::new().output(MemorySink::new()).build().unwrap();
+    let mut processor = Processor::new(app);
+    let input = "type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,5
+withdrawal,1,3,50
+dispute,2,9,
+withdrawal,1,4,2.5
+";
+    let summary = processor.run(input.as_bytes());
+    assert_eq!(summary.processed, 5);
+    assert_eq!(summary.accepted, 3);
+    assert_eq!(summary.rejected_total(), 2);
+    assert_eq!(summary.rejected.get("InsufficientFunds"), Some(&1));
+    assert_eq!(summary.rejected.get("IDNotFound"), Some(&1));
+    assert_eq!(summary.accounts_touched.len(), 2);
+    assert_eq!(
+        processor.app().output().lines(),
+        ["1,10,0,10,false", "2,5,0,5,false", "1,7.5,0,7.5,false"]
+    );
+}
+
+// An output sink whose writes all fail, like stdout once its reader is gone.
+#[derive(Debug)]
+struct FailingSink;
+
+impl OutputSink for FailingSink {
+    fn write_line(&mut self, _line: &str) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+#[test]
+fn processor_goes_on_when_the_output_sink_fails() {
+    let app = AppBuilder::new().output(FailingSink).build().unwrap();
+    let mut processor = Processor::new(app);
+    let input = "type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,4
+";
+    let summary = processor.run(input.as_bytes());
+    assert_eq!(summary.accepted, 2);
+    assert_eq!(summary.output_failures, 2);
+    let record = StringRecord::from(vec!["deposit", "1", "3", "1"]);
+    assert!(processor.process_record(record).0.is_ok());
+    assert_eq!(processor.output_failures(), 3);
+    assert_eq!(
+        processor.app().get_account(1).unwrap().total_balance(),
+        Decimal::from(7)
+    );
+}
+
+// The headers and body of a request.
+type Request = (Vec<tiny_http::Header>, String);
+
+// An endpoint failing the first request it gets, keeping the headers and body of the others.
+struct StubEndpoint {
+    url: String,
+    server: Arc<tiny_http::Server>,
+    received: Arc<Mutex<Vec<Request>>>,
+    worker: thread::JoinHandle<()>,
+}
+
+impl StubEndpoint {
+    fn start(path: &str, first_status: u16) -> Self {
+        let server = Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
+        let url = format!("http://{}{}", server.server_addr(), path);
+        let received = Arc::new(Mutex::new(vec![]));
+        let worker = {
+            let server = server.clone();
+            let received = received.clone();
+            thread::spawn(move || {
+                for (index, mut request) in server.incoming_requests().enumerate() {
+                    let mut body = String::new();
+                    request.as_reader().read_to_string(&mut body).unwrap();
+                    let status = if index == 0 { first_status } else { 200 };
+                    if status == 200 {
+                        let headers = request.headers().to_vec();
+                        received.lock().unwrap().push((headers, body));
+                    }
+                    request.respond(tiny_http::Response::empty(status)).unwrap();
+                }
+            })
+        };
+        Self {
+            url,
+            server,
+            received,
+            worker,
+        }
+    }
+
+    // The requests accepted, in the order they came.
+    fn stop(self) -> Vec<Request> {
+        self.server.unblock();
+        self.worker.join().unwrap();
+        let received = self.received.lock().unwrap();
+        received.clone()
+    }
+}
+
+fn header(headers: &[tiny_http::Header], name: &'static str) -> String {
+    headers
+        .iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.to_string())
+        .unwrap()
+}
+
+#[test]
+fn outbox_relay_delivers_every_event_in_order_retrying_refusals() {
+    let path = std::env::temp_dir().join(format!("integrator-outbox-{}.db", std::process::id()));
+    let filename = path.to_str().unwrap();
+    let _ = std::fs::remove_file(filename);
+    let (accounts, transactions) = sqlite::open(filename).unwrap();
+    let app = AppBuilder::new()
+        .output(MemorySink::new())
+        .stores(accounts, transactions)
+        .outbox()
+        .build()
+        .unwrap();
+    let input = "deposit,1,1,100
+deposit,1,2,10
+withdrawal,1,3,500
+dispute,1,1,
+chargeback,1,1,
+";
+    Processor::new(app).run(input.as_bytes());
+
+    let endpoint = StubEndpoint::start("/events", 500);
+    let relay = OutboxRelay::new(Outbox::open(filename).unwrap(), &endpoint.url)
+        .backoff(Duration::from_millis(1));
+    let summary = relay.run().unwrap();
+    assert_eq!(summary.delivered, 8);
+    assert_eq!(summary.pending, 0);
+    // Delivered events aren't sent again
+    assert_eq!(relay.run().unwrap().delivered, 0);
+
+    let received = endpoint.stop();
+    let keys: Vec<i64> = received
+        .iter()
+        .map(|(headers, _)| header(headers, "Idempotency-Key").parse().unwrap())
+        .collect();
+    assert_eq!(keys, (1..=8).collect::<Vec<i64>>());
+    let events: Vec<serde_json::Value> = received
+        .iter()
+        .map(|(_, body)| serde_json::from_str(body).unwrap())
+        .collect();
+    assert_eq!(events[0]["event"], "TransactionAccepted");
+    assert_eq!(events[0]["amount"], "100");
+    assert_eq!(events[2]["event"], "TransactionRejected");
+    assert_eq!(events[2]["reason"], "InsufficientFunds");
+    assert_eq!(events[4]["event"], "DisputeOpened");
+    assert_eq!(events[7]["event"], "AccountLocked");
+    let _ = std::fs::remove_file(filename);
+}
+
+#[test]
+fn webhooks_get_signed_account_events_retrying_failed_deliveries() {
+    let endpoint = StubEndpoint::start("/hooks", 503);
+    let log_path =
+        std::env::temp_dir().join(format!("integrator-webhooks-{}.log", std::process::id()));
+    let log_filename = log_path.to_str().unwrap();
+    let _ = std::fs::remove_file(log_filename);
+    let run = |records: Vec<Vec<&str>>| {
+        let record = StringRecord::from(vec![endpoint.url.as_str(), "s3cret"]);
+        let webhook = Webhook::from_record(&record).unwrap();
+        let backoff = Backoff {
+            retries: 2,
+            initial: Duration::from_millis(1),
+        };
+        let log = DeliveryLog::open(log_filename).unwrap();
+        let dispatcher = WebhookDispatcher::new(vec![webhook], backoff, log);
+        let mut app = AppBuilder::new()
+            .output(MemorySink::new())
+            .observer(Arc::new(dispatcher))
+            .build()
+            .unwrap();
+        for record in records {
+            app.process_record(StringRecord::from(record)).unwrap();
+        }
+        // Dropping the app lets the dispatcher finish its deliveries
+    };
+    run(vec![
+        vec!["deposit", "4", "1", "100"],
+        vec!["deposit", "4", "2", "10"],
+        vec!["dispute", "4", "1", ""],
+        vec!["chargeback", "4", "1", ""],
+    ]);
+    // A second run with the same log numbers its deliveries on
+    run(vec![
+        vec!["deposit", "5", "1", "100"],
+        vec!["deposit", "5", "2", "10"],
+        vec!["dispute", "5", "1", ""],
+    ]);
+
+    let received = endpoint.stop();
+    let deliveries: Vec<String> = received
+        .iter()
+        .map(|(headers, _)| header(headers, "X-Delivery"))
+        .collect();
+    assert_eq!(deliveries, ["1", "2", "3", "4"]);
+    let events: Vec<serde_json::Value> = received
+        .iter()
+        .map(|(_, body)| serde_json::from_str(body).unwrap())
+        .collect();
+    let names: Vec<&str> = events
+        .iter()
+        .map(|event| event["event"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "DisputeOpened",
+            "ChargebackApplied",
+            "AccountLocked",
+            "DisputeOpened"
+        ]
+    );
+    assert_eq!(
+        sign("key", "The quick brown fox jumps over the lazy dog"),
+        "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+    );
+    for (headers, body) in received.iter() {
+        let signature = header(headers, "X-Signature");
+        assert_eq!(signature, format!("sha256={}", sign("s3cret", body)));
+    }
+    let log = std::fs::read_to_string(log_filename).unwrap();
+    let outcomes: Vec<&str> = log
+        .lines()
+        .map(|line| line.rsplit(',').next().unwrap())
+        .collect();
+    assert_eq!(outcomes, ["503", "200", "200", "200", "200"]);
+    let _ = std::fs::remove_file(log_filename);
+}
+
+#[test]
+fn subscribers_falling_behind_are_dropped_instead_of_waited_for() {
+    let mut processor =
+        Processor::new(AppBuilder::new().output(MemorySink::new()).build().unwrap());
+    let (handle, commands) = server::channel();
+    // Never read while the records get processed
+    let updates = handle.subscribe();
+    let feeder = thread::spawn(move || {
+        for tx in 0..=server::SUBSCRIBER_BUFFER {
+            let tx = tx.to_string();
+            let record = StringRecord::from(vec!["deposit", "1", tx.as_str(), "1"]);
+            handle.process_record(record).unwrap();
+        }
+    });
+    server::serve(&mut processor, commands);
+    feeder.join().unwrap();
+    assert_eq!(updates.iter().count(), server::SUBSCRIBER_BUFFER);
+}
+
+#[test]
+fn http_server_processes_posted_transactions_and_answers_balances() {
+    let mut processor =
+        Processor::new(AppBuilder::new().output(MemorySink::new()).build().unwrap());
+    let (handle, commands) = server::channel();
+    let http = HttpServer::start("127.0.0.1:0", handle, 2).unwrap();
+    let url = format!("http://{}", http.address());
+    // Requests come from another thread while this one serves the app
+    let client = thread::spawn(move || {
+        let answer = |response: Result<ureq::Response, ureq::Error>| {
+            let (status, response) = match response {
+                Ok(response) => (response.status(), response),
+                Err(ureq::Error::Status(status, response)) => (status, response),
+                Err(error) => panic!("{}", error),
+            };
+            let body = response.into_string().unwrap();
+            (
+                status,
+                serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            )
+        };
+        let post = |body: &str, content_type: &str| {
+            answer(
+                ureq::post(&format!("{}/transactions", url))
+                    .set("Content-Type", content_type)
+                    .send_string(body),
+            )
+        };
+        let get = |path: &str| answer(ureq::get(&format!("{}{}", url, path)).call());
+        let responses: Vec<(u16, serde_json::Value)> = vec![
+            post("type,client,tx,amount\ndeposit,7,1,10\n", "text/csv"),
+            post(
+                r#"[{"type": "withdrawal", "client": 7, "tx": 2, "amount": "50"},
+                    {"type": "deposit", "client": 8, "tx": 3, "amount": "1.5", "currency": "EUR"}]"#,
+                "application/json",
+            ),
+            post(
+                r#"{"type": "dispute", "client": 7, "tx": 99}"#,
+                "application/json",
+            ),
+            post("deposit,x,4,1", "text/csv"),
+            get("/accounts/7"),
+            get("/accounts/9"),
+            get("/accounts"),
+            post(
+                r#"{"type": "deposit", "client": 7, "tx": 5, "amount": 0.1}"#,
+                "application/json",
+            ),
+        ];
+        http.stop();
+        responses
+    });
+    server::serve(&mut processor, commands);
+    let responses = client.join().unwrap();
+
+    let (status, body) = &responses[0];
+    assert_eq!(*status, 200);
+    assert_eq!(body["status"], "accepted");
+    assert_eq!(body["account"]["balances"]["USD"]["available"], "10");
+    let (status, body) = &responses[1];
+    assert_eq!(*status, 200);
+    assert_eq!(body[0]["code"], 422);
+    assert_eq!(body[0]["reason"], "InsufficientFunds");
+    assert_eq!(body[1]["code"], 200);
+    assert_eq!(responses[2].0, 404);
+    assert_eq!(responses[2].1["reason"], "IDNotFound");
+    assert_eq!(responses[3].0, 400);
+    assert_eq!(responses[4].0, 200);
+    assert_eq!(responses[4].1["balances"]["USD"]["total"], "10");
+    assert_eq!(responses[5].0, 404);
+    let clients: Vec<u64> = responses[6]
+        .1
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|account| account["client"].as_u64().unwrap())
+        .collect();
+    assert_eq!(clients, [7, 8]);
+    // Amounts given as JSON numbers could have lost digits already
+    assert_eq!(responses[7].0, 400);
+    assert_eq!(responses[7].1["error"], "InvalidBody");
+    assert_eq!(
+        processor.app().output().lines(),
+        ["7,10,0,10,false", "8,1.5,0,1.5,false,EUR"]
+    );
+}
+
+#[cfg(feature = "grpc")]
+#[test]
+fn grpc_service_takes_transaction_streams_and_answers_balances() {
+    use crate::server::grpc::{
+        proto::{self, integrator_client::IntegratorClient},
+        GrpcServer,
+    };
+
+    let mut processor =
+        Processor::new(AppBuilder::new().output(MemorySink::new()).build().unwrap());
+    let (handle, commands) = server::channel();
+    let grpc = GrpcServer::start("127.0.0.1:0", handle).unwrap();
+    let url = format!("http://{}", grpc.address());
+    // An in-process client calls from another thread while this one serves the app
+    let client = thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let answers = runtime.block_on(async {
+            let mut client = IntegratorClient::connect(url).await.unwrap();
+            let request = proto::WatchRequest { clients: vec![21] };
+            let mut updates = client.watch_balances(request).await.unwrap().into_inner();
+            let transaction = |kind: &str, client: u32, tx: u32, amount: &str| proto::Transaction {
+                r#type: kind.to_string(),
+                client,
+                tx,
+                amount: amount.to_string(),
+                ..Default::default()
+            };
+            let transactions = vec![
+                transaction("deposit", 21, 1, "10"),
+                transaction("deposit", 22, 2, "3"),
+                transaction("withdrawal", 21, 3, "50"),
+                transaction("withdrawal", 21, 4, "2.5"),
+            ];
+            let submission = client
+                .submit_transactions(tokio_stream::iter(transactions))
+                .await
+                .unwrap()
+                .into_inner();
+            let balance = client
+                .get_balance(proto::BalanceRequest { client: 22 })
+                .await
+                .unwrap()
+                .into_inner();
+            let missing = client
+                .get_balance(proto::BalanceRequest { client: 99 })
+                .await
+                .unwrap_err();
+            let mut watched = vec![];
+            for _ in 0..2 {
+                watched.push(updates.message().await.unwrap().unwrap());
+            }
+            (submission, balance, missing, watched)
+        });
+        grpc.stop();
+        answers
+    });
+    server::serve(&mut processor, commands);
+    let (submission, balance, missing, watched) = client.join().unwrap();
+
+    assert_eq!(submission.accepted, 3);
+    assert_eq!(submission.rejected, 1);
+    let rejected = &submission.outcomes[2];
+    assert_eq!(rejected.tx, 3);
+    assert!(!rejected.accepted);
+    assert_eq!(rejected.reason(), proto::RejectionReason::InsufficientFunds);
+    let account = submission.outcomes[3].account.as_ref().unwrap();
+    assert_eq!(account.balances[0].available, "7.5");
+    assert_eq!(balance.client, 22);
+    assert_eq!(balance.balances[0].total, "3");
+    assert_eq!(missing.code(), tonic::Code::NotFound);
+    // Only the watched client, leaving the rejected withdrawal out
+    let watched: Vec<(u32, String)> = watched
+        .iter()
+        .map(|update| {
+            let account = update.account.as_ref().unwrap();
+            (
+                update.transaction.as_ref().unwrap().tx,
+                account.balances[0].available.clone(),
+            )
+        })
+        .collect();
+    assert_eq!(watched, [(1, "10".to_string()), (4, "7.5".to_string())]);
+}
+
+#[test]
+fn tcp_feeders_get_an_ack_line_per_row_from_the_same_app() {
+    let mut processor =
+        Processor::new(AppBuilder::new().output(MemorySink::new()).build().unwrap());
+    let (handle, commands) = server::channel();
+    let tcp = TcpServer::start("127.0.0.1:0", handle).unwrap();
+    let address = tcp.address();
+    // Each feeder sends its rows over a connection of its own, at the same time
+    let feed = move |rows: &'static str| {
+        thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(rows.as_bytes()).unwrap();
+            let expected = rows
+                .lines()
+                .filter(|row| !row.is_empty() && !row.starts_with("type"))
+                .count();
+            let mut lines = BufReader::new(stream).lines();
+            (0..expected)
+                .map(|_| lines.next().unwrap().unwrap())
+                .collect::<Vec<String>>()
+        })
+    };
+    let first =
+        feed("type,client,tx,amount\ndeposit,31,1,10\nwithdrawal,31,2,50\n\nwithdrawal,31,3,2.5\n");
+    let second = feed("deposit,32,4,3\ndeposit,x,5,1\ndispute,32,99,\n");
+    let feeders = thread::spawn(move || {
+        let acks = (first.join().unwrap(), second.join().unwrap());
+        tcp.stop();
+        acks
+    });
+    server::serve(&mut processor, commands);
+    let (first, second) = feeders.join().unwrap();
+
+    assert_eq!(
+        first,
+        [
+            format!("ok,1,31,{},10,0,10,false", DEFAULT_CURRENCY),
+            "rejected,2,InsufficientFunds".to_string(),
+            format!("ok,3,31,{},7.5,0,7.5,false", DEFAULT_CURRENCY),
+        ]
+    );
+    assert_eq!(
+        second,
+        [
+            format!("ok,4,32,{},3,0,3,false", DEFAULT_CURRENCY),
+            "rejected,5,InvalidInput".to_string(),
+            "rejected,99,IDNotFound".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn websocket_pushes_the_balance_updates_and_rejections_of_the_watched_clients() {
+    let mut processor =
+        Processor::new(AppBuilder::new().output(MemorySink::new()).build().unwrap());
+    let (handle, commands) = server::channel();
+    let http = HttpServer::start("127.0.0.1:0", handle, 2).unwrap();
+    let address = http.address();
+    // The dashboard and the feeder are other threads while this one serves the app
+    let dashboard = thread::spawn(move || {
+        let url = format!("ws://{}/updates?clients=41", address);
+        let (mut socket, _) = tungstenite::connect(url).unwrap();
+        let _ = ureq::post(&format!("http://{}/transactions", address))
+            .set("Content-Type", "text/csv")
+            .send_string(
+                "deposit,41,1,10\ndeposit,42,2,3\nwithdrawal,41,3,50\nwithdrawal,41,4,2.5\n",
+            );
+        let without_upgrade = ureq::get(&format!("http://{}/updates", address)).call();
+        let pushed: Vec<serde_json::Value> = (0..3)
+            .map(|_| {
+                let message = socket.read().unwrap();
+                serde_json::from_str(message.to_text().unwrap()).unwrap()
+            })
+            .collect();
+        // Pings and the close get answered
+        socket
+            .send(tungstenite::Message::Ping(b"dashboard".to_vec()))
+            .unwrap();
+        let pong = socket.read().unwrap();
+        socket.close(None).unwrap();
+        let closed = loop {
+            match socket.read() {
+                Ok(_) => continue,
+                Err(error) => break error,
+            }
+        };
+        http.stop();
+        let status = match without_upgrade {
+            Err(ureq::Error::Status(status, _)) => status,
+            _ => 0,
+        };
+        (pushed, status, pong, closed)
+    });
+    server::serve(&mut processor, commands);
+    let (pushed, status, pong, closed) = dashboard.join().unwrap();
+
+    assert_eq!(status, 426);
+    assert_eq!(pong, tungstenite::Message::Pong(b"dashboard".to_vec()));
+    assert!(matches!(closed, tungstenite::Error::ConnectionClosed));
+    assert_eq!(pushed[0]["event"], "balance");
+    assert_eq!(pushed[0]["transaction"]["tx"], 1);
+    assert_eq!(
+        pushed[0]["account"]["balances"][DEFAULT_CURRENCY]["available"],
+        "10"
+    );
+    // Client 42 isn't watched
+    assert_eq!(pushed[1]["event"], "rejected");
+    assert_eq!(pushed[1]["client"], 41);
+    assert_eq!(pushed[1]["tx"], 3);
+    assert_eq!(pushed[1]["reason"], "InsufficientFunds");
+    assert_eq!(pushed[2]["transaction"]["tx"], 4);
+    assert_eq!(
+        pushed[2]["account"]["balances"][DEFAULT_CURRENCY]["available"],
+        "7.5"
+    );
+}
@@ -0,0 +1,222 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use csv::StringRecord;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use ureq::{Agent, AgentBuilder};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    models::events::{Event, Observer},
+    relay::{Backoff, TIMEOUT},
+};
+
+// Where account events get POSTed, and the secret their signature is made with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Webhook {
+    pub url: String,
+    pub secret: String,
+}
+
+impl Webhook {
+    // A webhook from its configuration record (url, secret).
+    pub fn from_record(record: &StringRecord) -> Option<Self> {
+        let url = record.get(0)?.trim();
+        let secret = record.get(1)?.trim();
+        let is_http = url.starts_with("http://") || url.starts_with("https://");
+        if !is_http || secret.is_empty() {
+            return None;
+        }
+        Some(Self {
+            url: url.to_string(),
+            secret: secret.to_string(),
+        })
+    }
+}
+
+// The hex HMAC-SHA256 of the body, receivers compute it with their copy of the secret to trust the event.
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// Only what happens to the account itself goes to the webhooks, not every transaction.
+fn is_account_event(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::DisputeOpened(_)
+            | Event::DisputeResolved(_)
+            | Event::ChargebackApplied(_)
+            | Event::AccountLocked { .. }
+            | Event::HoldReleased(_)
+    )
+}
+
+// Posts account events to every webhook from a thread of its own, so slow or failing webhooks
+// don't hold the processing back. Dropping the dispatcher waits for the pending deliveries.
+#[derive(Debug)]
+pub struct WebhookDispatcher {
+    sender: Mutex<Option<Sender<Event>>>,
+    worker: Option<JoinHandle<()>>,
+    failed_log_writes: Arc<AtomicU64>,
+}
+
+impl WebhookDispatcher {
+    // The delivery log numbers the events on from the last one it has, across runs.
+    pub fn new(webhooks: Vec<Webhook>, backoff: Backoff, log: DeliveryLog) -> Self {
+        let (sender, receiver) = mpsc::channel::<Event>();
+        let failed_log_writes = Arc::new(AtomicU64::new(0));
+        let mut delivery = Delivery {
+            webhooks,
+            backoff,
+            sequence: log.last_delivery,
+            log,
+            agent: AgentBuilder::new().timeout(TIMEOUT).build(),
+            failed_log_writes: failed_log_writes.clone(),
+        };
+        let worker = thread::spawn(move || {
+            for event in receiver {
+                delivery.dispatch(&event);
+            }
+        });
+        Self {
+            sender: Mutex::new(Some(sender)),
+            worker: Some(worker),
+            failed_log_writes,
+        }
+    }
+
+    // Lines of the delivery log that failed to be written so far, the deliveries went on.
+    pub fn failed_log_writes(&self) -> u64 {
+        self.failed_log_writes.load(Ordering::Relaxed)
+    }
+}
+
+impl Observer for WebhookDispatcher {
+    fn notify(&self, event: &Event) {
+        if !is_account_event(event) {
+            return;
+        }
+        if let Some(sender) = self
+            .sender
+            .lock()
+            .expect("Webhook sender poisoned")
+            .as_ref()
+        {
+            // A worker gone has nowhere to deliver anyway
+            let _ = sender.send(event.clone());
+        }
+    }
+}
+
+impl Drop for WebhookDispatcher {
+    fn drop(&mut self) {
+        self.sender.lock().expect("Webhook sender poisoned").take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+// Appends a line per delivery attempt: timestamp, delivery, event, url, attempt, outcome.
+// The outcome is the HTTP status of the response, or `unreachable` when there was none.
+#[derive(Debug)]
+pub struct DeliveryLog {
+    writer: BufWriter<File>,
+    // The delivery of the last line already in the file, 0 for a new one.
+    last_delivery: u64,
+}
+
+impl DeliveryLog {
+    pub fn open(filename: &str) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(filename)?;
+        let mut last_delivery = 0;
+        for line in BufReader::new(&file).lines() {
+            let line = line?;
+            if let Some(delivery) = line.split(',').nth(1) {
+                last_delivery = delivery.parse().map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Unreadable delivery log line: {}", line),
+                    )
+                })?;
+            }
+        }
+        Ok(Self {
+            writer: BufWriter::new(file),
+            last_delivery,
+        })
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.writer, "{}", line)?;
+        self.writer.flush()
+    }
+}
+
+// What the worker thread of the dispatcher owns.
+#[derive(Debug)]
+struct Delivery {
+    webhooks: Vec<Webhook>,
+    backoff: Backoff,
+    log: DeliveryLog,
+    agent: Agent,
+    // Numbers the events, for receivers to drop repeated deliveries.
+    sequence: u64,
+    failed_log_writes: Arc<AtomicU64>,
+}
+
+impl Delivery {
+    fn dispatch(&mut self, event: &Event) {
+        self.sequence += 1;
+        let body = event.to_json().to_string();
+        for webhook in self.webhooks.iter() {
+            let signature = format!("sha256={}", sign(&webhook.secret, &body));
+            self.backoff.retry(|attempt| {
+                let outcome = match self
+                    .agent
+                    .post(&webhook.url)
+                    .set("Content-Type", "application/json")
+                    .set("X-Event", event.name())
+                    .set("X-Delivery", &self.sequence.to_string())
+                    .set("X-Signature", &signature)
+                    .send_string(&body)
+                {
+                    Ok(response) => Some(response.status()),
+                    Err(ureq::Error::Status(status, _)) => Some(status),
+                    Err(ureq::Error::Transport(_)) => None,
+                };
+                let logged = self.log.write(&format!(
+                    "{},{},{},{},{},{}",
+                    SystemClock.now(),
+                    self.sequence,
+                    event.name(),
+                    webhook.url,
+                    attempt + 1,
+                    outcome.map_or("unreachable".to_string(), |status| status.to_string())
+                ));
+                // A log that can't be written isn't a reason to stop delivering
+                if let Err(error) = logged {
+                    self.failed_log_writes.fetch_add(1, Ordering::Relaxed);
+                    eprintln!("Couldn't write the webhook delivery log: {}", error);
+                }
+                matches!(outcome, Some(status) if status < 300)
+            });
+        }
+    }
+}